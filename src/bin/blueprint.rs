@@ -0,0 +1,79 @@
+use std::fs::File;
+use std::io::Write;
+
+use bevy::prelude::*;
+
+use SpaceSandbox::ship::prelude::*;
+
+fn usage() {
+    println!("Convert ships between saved scenes and text blueprints");
+    println!("  blueprint export <ship.scn.ron> <ship{}>", BLUEPRINT_EXT);
+    println!("  blueprint import <ship{}> <ship.scn.ron>", BLUEPRINT_EXT);
+}
+
+fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.len() != 4 {
+        usage();
+        std::process::exit(1);
+    }
+
+    let type_registry = AppTypeRegistry::default();
+    register_save_types(&type_registry);
+
+    let res = match args[1].as_str() {
+        "export" => export(&args[2], &args[3], &type_registry),
+        "import" => import(&args[2], &args[3], &type_registry),
+        _ => {
+            usage();
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(err) = res {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    }
+}
+
+fn export(src : &str, dst : &str, type_registry : &AppTypeRegistry) -> Result<(), String> {
+    let mut sub_world = read_ship_scene(src, type_registry)?;
+    let data = sub_world.query::<&DiskShipBase64>().iter(&sub_world).next()
+        .ok_or(format!("{} has no ship data", src))?;
+    let disk_ship = data.disk_ship()?;
+
+    let blueprint = Blueprint::from_disk_ship(&disk_ship, &sub_world, &type_registry.read())
+        .map_err(|err| err.to_string())?;
+    let text = blueprint.to_json().map_err(|err| err.to_string())?;
+
+    File::create(dst)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|err| format!("Failed to write {}: {}", dst, err))?;
+
+    println!("Exported {} instances and {} voxels to {}", blueprint.instances.len(), blueprint.voxels.len(), dst);
+    Ok(())
+}
+
+fn import(src : &str, dst : &str, type_registry : &AppTypeRegistry) -> Result<(), String> {
+    let text = std::fs::read_to_string(src)
+        .map_err(|err| format!("Failed to read {}: {}", src, err))?;
+    let blueprint = Blueprint::from_json(&text).map_err(|err| err.to_string())?;
+
+    let mut sub_world = World::default();
+    sub_world.insert_resource(type_registry.clone());
+
+    let disk_ship = blueprint.to_disk_ship(&mut sub_world, &type_registry.read())
+        .map_err(|err| err.to_string())?;
+    sub_world.spawn(DiskShipBase64::new(&disk_ship));
+
+    let dynamic_scene = DynamicScene::from_world(&sub_world);
+    let ron_scene = dynamic_scene.serialize_ron(type_registry)
+        .map_err(|err| format!("Failed to serialize scene: {}", err))?;
+
+    File::create(dst)
+        .and_then(|mut file| file.write_all(ron_scene.as_bytes()))
+        .map_err(|err| format!("Failed to write {}: {}", dst, err))?;
+
+    println!("Imported {} instances and {} voxels to {}", blueprint.instances.len(), blueprint.voxels.len(), dst);
+    Ok(())
+}
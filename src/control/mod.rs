@@ -98,6 +98,13 @@ pub enum BuildAction {
     LevelDown,
     RotateClockwise,
     RotateCounterClockwise,
+    RotateX,
+    RotateZ,
+    Undo,
+    Redo,
+    Copy,
+    Cut,
+    Paste,
 }
 
 
@@ -113,6 +120,13 @@ impl BuildAction {
             BuildAction::LevelDown,
             BuildAction::RotateClockwise,
             BuildAction::RotateCounterClockwise,
+            BuildAction::RotateX,
+            BuildAction::RotateZ,
+            BuildAction::Undo,
+            BuildAction::Redo,
+            BuildAction::Copy,
+            BuildAction::Cut,
+            BuildAction::Paste,
         ]
     }
 }
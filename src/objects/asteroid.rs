@@ -0,0 +1,388 @@
+use bevy::prelude::*;
+use bevy::math::DVec3;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::{HashMap, HashSet};
+use bevy_transform64::prelude::DTransform;
+use serde::{Serialize, Deserialize};
+
+use crate::DSpatialBundle;
+use crate::ship::hull_collider::{greedy_boxes, boxes_collider};
+use crate::space_voxel::VoxelMap;
+use crate::space_voxel::chunked_voxel_map::ChunkedVoxelMap;
+use crate::space_voxel::objected_voxel_map::VoxelVal;
+use crate::space_voxel::objected_mesh_generate::{generate_mesh_padded, group_quads, quads_mesh};
+use crate::space_voxel::lod::{VoxelLod, LOD_REMESH_PER_FRAME, downsample_chunk, chunks_to_remesh, coarse_cell};
+
+pub const ASTEROID_VOXEL_SIZE : f64 = 2.0;
+pub const ASTEROID_CHUNK_SIZE : IVec3 = IVec3::new(16, 16, 16);
+/// Generated asteroids taken from their tasks per frame, so the first meshes of a big field are spread over frames
+const ASTEROIDS_PER_FRAME : usize = 4;
+
+#[derive(Clone, Copy, Reflect, Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Default)]
+pub enum AsteroidBlock {
+    #[default]
+    Rock,
+    IronOre,
+    GoldOre
+}
+
+impl AsteroidBlock {
+    pub const ALL : [AsteroidBlock; 3] = [AsteroidBlock::Rock, AsteroidBlock::IronOre, AsteroidBlock::GoldOre];
+
+    pub fn color(&self) -> Color {
+        match self {
+            AsteroidBlock::Rock => Color::rgb(0.36, 0.33, 0.3),
+            AsteroidBlock::IronOre => Color::rgb(0.55, 0.3, 0.2),
+            AsteroidBlock::GoldOre => Color::rgb(0.85, 0.7, 0.25),
+        }
+    }
+}
+
+pub type AsteroidMap = ChunkedVoxelMap<VoxelVal<AsteroidBlock>>;
+
+/// Everything needed to generate the asteroid again, the cells follow from it
+#[derive(Component, Clone, Copy, Reflect, Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+#[reflect(Component)]
+pub struct AsteroidSeed {
+    pub seed : u64,
+    /// Mean radius in cells
+    pub radius : i32
+}
+
+/// Cells of a generated asteroid. Cell zero has its corner at the asteroid center
+#[derive(Component, Clone)]
+pub struct Asteroid {
+    pub seed : AsteroidSeed,
+    pub map : AsteroidMap,
+    /// Cells changed after generation. The seed and these edits are enough to restore the asteroid
+    pub edits : HashMap<IVec3, VoxelVal<AsteroidBlock>>
+}
+
+impl Asteroid {
+    pub fn generate(seed : AsteroidSeed) -> Asteroid {
+        Asteroid {
+            seed,
+            map : generate_asteroid_map(&seed),
+            edits : HashMap::new()
+        }
+    }
+
+    /// Generates the asteroid and applies replicated edits on top
+    pub fn with_edits(seed : AsteroidSeed, edits : HashMap<IVec3, VoxelVal<AsteroidBlock>>) -> Asteroid {
+        let mut asteroid = Asteroid::generate(seed);
+        for (idx, val) in edits {
+            asteroid.set_cell(&idx, val);
+        }
+        asteroid
+    }
+
+    pub fn set_cell(&mut self, idx : &IVec3, val : VoxelVal<AsteroidBlock>) {
+        self.map.set_voxel_by_idx(idx, val.clone());
+        self.edits.insert(*idx, val);
+    }
+}
+
+fn mix(mut x : u64) -> u64 {
+    // splitmix64 finalizer
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+/// Stable hash of a seed and a lattice point, it does not depend on the platform or crate versions
+pub fn hash_cell(seed : u64, p : IVec3) -> u64 {
+    let mut h = mix(seed ^ 0x9e3779b97f4a7c15);
+    for v in [p.x, p.y, p.z] {
+        h = mix(h ^ (v as u32 as u64));
+    }
+    h
+}
+
+fn lattice_value(seed : u64, p : IVec3) -> f64 {
+    (hash_cell(seed, p) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Smooth value noise in 0..1 with one lattice point per unit
+pub fn value_noise(seed : u64, p : DVec3) -> f64 {
+    let cell = p.floor();
+    let base = cell.as_ivec3();
+    let t = p - cell;
+    let t = t * t * (DVec3::splat(3.0) - 2.0 * t);
+
+    let mut res = 0.0;
+    for corner in 0..8 {
+        let offset = IVec3::new(corner & 1, (corner >> 1) & 1, (corner >> 2) & 1);
+        let w = offset.as_dvec3();
+        let weight = (w * t + (DVec3::ONE - w) * (DVec3::ONE - t)).to_array().iter().product::<f64>();
+        res += weight * lattice_value(seed, base + offset);
+    }
+    res
+}
+
+/// Octaves of [`value_noise`], each one twice finer and half as strong. Stays in 0..1
+pub fn fractal_noise(seed : u64, p : DVec3, octaves : u32) -> f64 {
+    let mut res = 0.0;
+    let mut amplitude = 0.5;
+    let mut total = 0.0;
+    let mut p = p;
+    for octave in 0..octaves {
+        res += amplitude * value_noise(seed.wrapping_add(octave as u64), p);
+        total += amplitude;
+        amplitude *= 0.5;
+        p *= 2.0;
+    }
+    res / total
+}
+
+const ORE_SALT : u64 = 0x6f7265;
+
+/// Block of the cell with center `pos` in cells from the asteroid center, `None` is empty space
+pub fn asteroid_cell(seed : &AsteroidSeed, pos : DVec3) -> Option<AsteroidBlock> {
+    let radius = seed.radius as f64;
+    let dist = pos.length();
+    // the surface radius wobbles with the direction, sampled on a sphere of radius 2
+    let dir = pos.try_normalize().unwrap_or(DVec3::Y);
+    let surface = radius * (0.6 + 0.6 * fractal_noise(seed.seed, dir * 2.0, 3));
+    // small craters and bumps
+    let surface = surface + 2.0 * (fractal_noise(seed.seed ^ 1, pos / 4.0, 2) - 0.5);
+    if dist >= surface {
+        return None;
+    }
+
+    let ore = fractal_noise(seed.seed ^ ORE_SALT, pos / 5.0, 2);
+    if ore > 0.68 && dist < radius * 0.5 {
+        Some(AsteroidBlock::GoldOre)
+    } else if ore > 0.62 {
+        Some(AsteroidBlock::IronOre)
+    } else {
+        Some(AsteroidBlock::Rock)
+    }
+}
+
+pub fn generate_asteroid_map(seed : &AsteroidSeed) -> AsteroidMap {
+    let mut map = AsteroidMap::new(ASTEROID_VOXEL_SIZE, ASTEROID_CHUNK_SIZE);
+    map.first_voxel_pos = DVec3::ZERO;
+    // the surface is at most 1.2 radius plus the bumps
+    let extent = (seed.radius as f64 * 1.2).ceil() as i32 + 2;
+    for z in -extent..extent {
+        for y in -extent..extent {
+            for x in -extent..extent {
+                let idx = IVec3::new(x, y, z);
+                if let Some(block) = asteroid_cell(seed, idx.as_dvec3() + 0.5) {
+                    map.set_voxel_by_idx(&idx, VoxelVal::Voxel(block));
+                }
+            }
+        }
+    }
+    map
+}
+
+#[derive(Resource, Default)]
+pub struct AsteroidMaterials {
+    pub materials : HashMap<AsteroidBlock, Handle<StandardMaterial>>
+}
+
+/// Mesh and collider entities of the asteroid chunks, by chunk origin
+#[derive(Component, Default)]
+pub struct AsteroidChunks {
+    pub meshes : HashMap<IVec3, Vec<Entity>>,
+    pub colliders : HashMap<IVec3, Entity>,
+    /// Chunks still meshed at the previous [`VoxelLod`] level
+    pub pending : HashSet<IVec3>
+}
+
+pub fn setup_asteroid_materials(
+    mut asteroid_materials : ResMut<AsteroidMaterials>,
+    mut materials : ResMut<Assets<StandardMaterial>>
+) {
+    for block in AsteroidBlock::ALL {
+        let material = materials.add(StandardMaterial {
+            base_color : block.color(),
+            perceptual_roughness : 0.9,
+            metallic : if block == AsteroidBlock::Rock { 0.0 } else { 0.6 },
+            ..default()
+        });
+        asteroid_materials.materials.insert(block, material);
+    }
+}
+
+/// Asteroid cells being generated on the [`AsyncComputeTaskPool`]
+#[derive(Component)]
+pub struct AsteroidTask(Task<Asteroid>);
+
+/// Starts generating the cells of seeded asteroids off the main thread
+pub fn generate_asteroids(
+    mut cmds : Commands,
+    seeds : Query<(Entity, &AsteroidSeed), (Without<Asteroid>, Without<AsteroidTask>)>
+) {
+    let pool = AsyncComputeTaskPool::get();
+    for (e, seed) in seeds.iter() {
+        let seed = *seed;
+        cmds.entity(e).insert(AsteroidTask(pool.spawn(async move { Asteroid::generate(seed) })));
+    }
+}
+
+/// Inserts the asteroids whose generation is done, a few per frame
+pub fn poll_asteroid_tasks(
+    mut cmds : Commands,
+    mut tasks : Query<(Entity, &mut AsteroidTask)>
+) {
+    let mut done = 0;
+    for (e, mut task) in tasks.iter_mut() {
+        if done == ASTEROIDS_PER_FRAME {
+            break;
+        }
+        if task.0.is_finished() {
+            // the task is done, so this does not wait
+            let asteroid = pollster::block_on(&mut task.0);
+            cmds.entity(e).remove::<AsteroidTask>().insert(asteroid);
+            done += 1;
+        }
+    }
+}
+
+/// Rebuilds meshes and colliders of the dirty asteroid chunks.
+/// A [`VoxelLod`] level change rebuilds all meshes, [`LOD_REMESH_PER_FRAME`] at a time. Colliders keep the full detail
+pub fn update_asteroid_chunks(
+    mut cmds : Commands,
+    mut meshes : ResMut<Assets<Mesh>>,
+    asteroid_materials : Res<AsteroidMaterials>,
+    mut asteroids : Query<(Entity, &mut Asteroid, Option<&mut AsteroidChunks>, Option<Ref<VoxelLod>>)>
+) {
+    let mut budget = LOD_REMESH_PER_FRAME;
+    for (asteroid_e, mut asteroid, chunks, lod) in asteroids.iter_mut() {
+        let lod_changed = lod.as_ref().is_some_and(|lod| lod.is_changed());
+        let pending = chunks.as_ref().is_some_and(|chunks| !chunks.pending.is_empty());
+        if asteroid.map.dirty_set.is_empty() && !lod_changed && !pending {
+            continue;
+        }
+        let factor = lod.map_or(1, |lod| lod.factor_for(asteroid.map.chunk_size));
+        let first_build = chunks.is_none();
+        let mut new_chunks = AsteroidChunks::default();
+        let chunks = match chunks {
+            Some(chunks) => chunks.into_inner(),
+            None => &mut new_chunks
+        };
+
+        let map = &asteroid.map;
+        let known = map.map.keys().chain(chunks.meshes.keys()).copied().collect::<Vec<_>>();
+        let to_remesh = chunks_to_remesh(&map.dirty_set, &mut chunks.pending, known.into_iter(), lod_changed, &mut budget);
+        for origin in to_remesh {
+            for e in chunks.meshes.remove(&origin).unwrap_or_default() {
+                cmds.entity(e).despawn_recursive();
+            }
+            let Some(chunk) = map.map.get(&origin) else {
+                continue;
+            };
+            let coarse;
+            let mesh_chunk = if factor > 1 {
+                coarse = downsample_chunk(chunk, factor);
+                &coarse
+            } else {
+                chunk
+            };
+
+            // the padding comes from the neighbour chunks, so faces between chunks are culled
+            let coarse_origin = origin / factor;
+            let buffer = generate_mesh_padded(mesh_chunk, |lp| coarse_cell(map, coarse_origin + lp, factor));
+            // mesh vertices start at the padding cell before the chunk origin
+            let mesh_pos = map.get_idx_pos(&(origin - IVec3::splat(factor)));
+            let voxel_size = map.voxel_size * factor as f64;
+            let mut spawned = vec![];
+            for (block, faces) in group_quads(mesh_chunk, &buffer) {
+                let Some(material) = asteroid_materials.materials.get(&block) else {
+                    continue;
+                };
+                let e = cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(mesh_pos.x, mesh_pos.y, mesh_pos.z)))
+                    .insert(meshes.add(quads_mesh(&faces, voxel_size as f32)))
+                    .insert(material.clone())
+                    .insert(Name::new("Asteroid chunk"))
+                    .id();
+                cmds.entity(asteroid_e).add_child(e);
+                spawned.push(e);
+            }
+            chunks.meshes.insert(origin, spawned);
+        }
+
+        for origin in map.dirty_set.iter().copied() {
+            if let Some(e) = chunks.colliders.remove(&origin) {
+                cmds.entity(e).despawn_recursive();
+            }
+            let Some(chunk) = map.map.get(&origin) else {
+                continue;
+            };
+            let boxes = greedy_boxes(chunk, |val| matches!(val, VoxelVal::Voxel(_)));
+            if let Some(collider) = boxes_collider(&boxes, map.voxel_size) {
+                let pos = map.get_idx_pos(&origin);
+                let e = cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(pos.x, pos.y, pos.z)))
+                    .insert(collider)
+                    .insert(Name::new("Asteroid collider"))
+                    .id();
+                cmds.entity(asteroid_e).add_child(e);
+                chunks.colliders.insert(origin, e);
+            }
+        }
+        asteroid.bypass_change_detection().map.dirty_set.clear();
+
+        // colliders are tracked here too, even if no chunk got a mesh
+        if first_build {
+            cmds.entity(asteroid_e).insert(new_chunks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cells(asteroid : &Asteroid) -> Vec<(IVec3, VoxelVal<AsteroidBlock>)> {
+        let mut cells : Vec<(IVec3, VoxelVal<AsteroidBlock>)> = asteroid.map.iter()
+            .filter(|(_, val)| !matches!(val, VoxelVal::None))
+            .map(|(idx, val)| (idx, val.clone()))
+            .collect();
+        cells.sort_by_key(|(idx, _)| (idx.z, idx.y, idx.x));
+        cells
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let seed = AsteroidSeed { seed : 42, radius : 10 };
+        let a = Asteroid::generate(seed);
+        let b = Asteroid::generate(seed);
+        assert_eq!(cells(&a), cells(&b));
+        assert!(!cells(&a).is_empty());
+        assert!(cells(&a).iter().any(|(_, val)| *val != VoxelVal::Voxel(AsteroidBlock::Rock)));
+        assert!(matches!(a.map.get_by_idx(&IVec3::ZERO), VoxelVal::Voxel(_)));
+
+        let other = Asteroid::generate(AsteroidSeed { seed : 43, radius : 10 });
+        assert_ne!(cells(&a), cells(&other));
+
+        let mut mined = a.clone();
+        mined.set_cell(&IVec3::ZERO, VoxelVal::None);
+        let restored = Asteroid::with_edits(seed, mined.edits.clone());
+        assert_eq!(cells(&restored), cells(&mined));
+    }
+
+    #[test]
+    fn edits_survive_regenerate() {
+        let seed = AsteroidSeed { seed : 7, radius : 10 };
+        let mut mined = Asteroid::generate(seed);
+        // cells of negative chunks and of a chunk the generation never allocates
+        let edits = [
+            (IVec3::new(-3, -2, -4), VoxelVal::None),
+            (IVec3::new(-5, 1, -1), VoxelVal::Voxel(AsteroidBlock::GoldOre)),
+            (IVec3::new(40, -20, 33), VoxelVal::Voxel(AsteroidBlock::IronOre))
+        ];
+        assert_ne!(mined.map.get_cloned_by_idx(&edits[0].0), VoxelVal::None);
+        for (idx, val) in edits.iter() {
+            mined.set_cell(idx, val.clone());
+        }
+
+        let restored = Asteroid::with_edits(seed, mined.edits.clone());
+        for (idx, val) in edits {
+            assert_eq!(restored.map.get_cloned_by_idx(&idx), val);
+        }
+        assert_eq!(restored.map.chunk_bounds(), mined.map.chunk_bounds());
+        assert_eq!(cells(&restored), cells(&mined));
+    }
+}
@@ -1,12 +1,103 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, math::DVec3};
 use bevy_proto::prelude::{Schematic, ReflectSchematic};
+use bevy_transform64::prelude::{DGlobalTransform, DTransform};
+use bevy_xpbd_3d::prelude::Collider;
 
-#[derive(Component, Reflect, Default, Schematic)]
-#[reflect(Schematic)]
+use crate::control::{Action, FPSAction};
+use crate::pawn_system::{Pawn, CurrentPawnMarker};
+use crate::scenes::fps_mode::IsFPSMode;
+
+/// Pawns closer than this can open a door with the interact key, m
+const INTERACT_DISTANCE : f64 = 2.0;
+/// Automatic doors open for pawns closer than this, m
+const AUTO_OPEN_DISTANCE : f64 = 1.5;
+/// Seconds to fully open or close
+const DOOR_MOVE_TIME : f32 = 0.6;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DoorState {
+    #[default]
+    Closed,
+    Opening,
+    Open,
+    Closing
+}
+
+/// Sliding door. The [`DoorLeaf`] children move between `closed_pos` and `opened_pos`.
+/// `is_open` is the requested state, gas passes and the collider is off while it is set.
+/// A locked door refuses to open but can still be closed, a denied request changes nothing
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
 pub struct Door {
     pub is_open : bool,
     pub opened_pos : Vec3,
     pub closed_pos : Vec3,
+    /// Locked doors refuse to open, closing is allowed
+    #[reflect(default)]
+    pub locked : bool,
+    /// Opens by itself while a pawn is near
+    #[reflect(default)]
+    pub auto_open : bool,
+    #[reflect(ignore)]
+    pub state : DoorState,
+    /// 0 is closed, 1 is open
+    #[reflect(ignore)]
+    pub progress : f32,
+    /// Collider taken off the open door
+    #[reflect(ignore)]
+    collider : Option<Collider>
+}
+
+impl Door {
+    fn target(&self) -> f32 {
+        if self.is_open { 1.0 } else { 0.0 }
+    }
+}
+
+/// Moving part of a [`Door`], a direct child of it
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct DoorLeaf;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DoorAction {
+    Open,
+    Close,
+    Toggle,
+    Lock,
+    Unlock
+}
+
+/// Asks a door to change. `by` is the pawn or other entity which asked, if any
+#[derive(Event, Clone, Copy, Debug)]
+pub struct CmdDoor {
+    pub door : Entity,
+    pub action : DoorAction,
+    pub by : Option<Entity>
+}
+
+/// Sent when a request was refused because the door is locked or access was denied
+#[derive(Event, Clone, Copy, Debug)]
+pub struct DoorDenied {
+    pub door : Entity,
+    pub by : Option<Entity>
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DoorRequest {
+    pub cmd : CmdDoor,
+    pub denied : bool
+}
+
+/// Requests of the current frame. Access control systems run in [`DoorSet::Access`] and set `denied`
+#[derive(Resource, Default)]
+pub struct DoorRequests(pub Vec<DoorRequest>);
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
+pub enum DoorSet {
+    Request,
+    Access,
+    Apply
 }
 
 pub struct DoorPlugin;
@@ -14,17 +105,248 @@ pub struct DoorPlugin;
 impl Plugin for DoorPlugin {
     fn build(&self, app: &mut App) {
         app.register_type::<Door>();
+        app.register_type::<DoorLeaf>();
+        app.add_event::<CmdDoor>();
+        app.add_event::<DoorDenied>();
+        app.insert_resource(DoorRequests::default());
+
+        app.configure_sets(Update, (DoorSet::Request, DoorSet::Access, DoorSet::Apply).chain());
+        app.add_systems(Update, (
+            init_door.before(DoorSet::Request),
+            (interact_door.run_if(in_state(IsFPSMode::Yes)), auto_open_door, collect_door_cmds.after(interact_door).after(auto_open_door))
+                .in_set(DoorSet::Request),
+            (apply_door_requests, animate_door.after(apply_door_requests))
+                .in_set(DoorSet::Apply)
+        ));
     }
 }
 
+/// Puts a new or loaded door right into its requested state
 fn init_door(
-    _doors : Query<&mut Door, Added<Door>>
+    mut cmds : Commands,
+    mut doors : Query<(Entity, &mut Door, Option<&Collider>), Added<Door>>
 ) {
-    
+    for (door_e, mut door, collider) in doors.iter_mut() {
+        door.progress = door.target();
+        if door.is_open {
+            door.state = DoorState::Open;
+            door.collider = collider.cloned();
+            cmds.entity(door_e).remove::<Collider>();
+        } else {
+            door.state = DoorState::Closed;
+        }
+    }
+}
+
+fn nearest_door<'a>(
+    pos : DVec3,
+    max_distance : f64,
+    doors : impl Iterator<Item = (Entity, &'a DGlobalTransform)>
+) -> Option<Entity> {
+    doors
+        .map(|(e, transform)| (e, transform.translation().distance(pos)))
+        .filter(|(_, distance)| *distance < max_distance)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(e, _)| e)
 }
 
-fn open_door__system(
-    _doors : Query<&mut Door>,
+fn interact_door(
+    input : Res<Input<Action>>,
+    pawn : Query<(Entity, &DGlobalTransform), With<CurrentPawnMarker>>,
+    doors : Query<(Entity, &DGlobalTransform), With<Door>>,
+    mut cmd_door : EventWriter<CmdDoor>
 ) {
+    if !input.just_pressed(Action::FPS(FPSAction::Interact)) {
+        return;
+    }
+    let Ok((pawn_e, pawn_transform)) = pawn.get_single() else {
+        return;
+    };
+    if let Some(door) = nearest_door(pawn_transform.translation(), INTERACT_DISTANCE, doors.iter()) {
+        cmd_door.send(CmdDoor {
+            door,
+            action : DoorAction::Toggle,
+            by : Some(pawn_e)
+        });
+    }
+}
 
-}
\ No newline at end of file
+fn auto_open_door(
+    pawns : Query<(Entity, &DGlobalTransform), With<Pawn>>,
+    doors : Query<(Entity, &Door, &DGlobalTransform)>,
+    mut cmd_door : EventWriter<CmdDoor>
+) {
+    for (door_e, door, door_transform) in doors.iter().filter(|(_, door, _)| door.auto_open) {
+        let near = pawns.iter()
+            .find(|(_, transform)| transform.translation().distance(door_transform.translation()) < AUTO_OPEN_DISTANCE)
+            .map(|(e, _)| e);
+        match (near, door.is_open) {
+            (Some(pawn), false) if !door.locked => cmd_door.send(CmdDoor {
+                door : door_e,
+                action : DoorAction::Open,
+                by : Some(pawn)
+            }),
+            (None, true) => cmd_door.send(CmdDoor {
+                door : door_e,
+                action : DoorAction::Close,
+                by : None
+            }),
+            _ => {}
+        }
+    }
+}
+
+fn collect_door_cmds(
+    mut cmd_door : EventReader<CmdDoor>,
+    mut requests : ResMut<DoorRequests>
+) {
+    requests.0.extend(cmd_door.iter().map(|cmd| DoorRequest {
+        cmd : *cmd,
+        denied : false
+    }));
+}
+
+fn apply_door_requests(
+    mut requests : ResMut<DoorRequests>,
+    mut doors : Query<&mut Door>,
+    mut denied : EventWriter<DoorDenied>
+) {
+    for request in requests.0.drain(..) {
+        let cmd = request.cmd;
+        let Ok(mut door) = doors.get_mut(cmd.door) else {
+            continue;
+        };
+        let open = match cmd.action {
+            DoorAction::Open => true,
+            DoorAction::Close => false,
+            DoorAction::Toggle => !door.is_open,
+            DoorAction::Lock | DoorAction::Unlock => {
+                if request.denied {
+                    denied.send(DoorDenied { door : cmd.door, by : cmd.by });
+                } else {
+                    door.locked = cmd.action == DoorAction::Lock;
+                }
+                continue;
+            }
+        };
+        if open == door.is_open {
+            continue;
+        }
+        if request.denied || (open && door.locked) {
+            denied.send(DoorDenied { door : cmd.door, by : cmd.by });
+            continue;
+        }
+        door.is_open = open;
+    }
+}
+
+/// Moves the door leaves and switches the collider off once the door is fully open
+fn animate_door(
+    mut cmds : Commands,
+    time : Res<Time>,
+    mut doors : Query<(Entity, &mut Door, Option<&Collider>, Option<Ref<Children>>)>,
+    mut leaves : Query<&mut DTransform, With<DoorLeaf>>
+) {
+    let step = time.delta_seconds() / DOOR_MOVE_TIME;
+    for (door_e, mut door, collider, children) in doors.iter_mut() {
+        let target = door.target();
+        // the leaves are spawned after the door, so they are put in place when they appear
+        let new_model = children.as_ref().is_some_and(|children| children.is_changed());
+        if door.progress == target && matches!(door.state, DoorState::Open | DoorState::Closed) && !new_model {
+            continue;
+        }
+
+        if target > door.progress {
+            door.state = DoorState::Opening;
+            door.progress = (door.progress + step).min(target);
+        } else if target < door.progress {
+            door.state = DoorState::Closing;
+            // a closing door blocks the way at once
+            if let Some(collider) = door.collider.take() {
+                cmds.entity(door_e).insert(collider);
+            }
+            door.progress = (door.progress - step).max(target);
+        }
+
+        if door.progress >= 1.0 {
+            door.state = DoorState::Open;
+            if door.collider.is_none() {
+                door.collider = collider.cloned();
+                cmds.entity(door_e).remove::<Collider>();
+            }
+        } else if door.progress <= 0.0 {
+            door.state = DoorState::Closed;
+        }
+
+        let pos = door.closed_pos.lerp(door.opened_pos, door.progress).as_dvec3();
+        for child in children.iter().flat_map(|children| children.iter()) {
+            if let Ok(mut transform) = leaves.get_mut(*child) {
+                transform.translation = pos;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn send(world : &mut World, door : Entity, action : DoorAction, denied : bool) -> bool {
+        world.resource_mut::<DoorRequests>().0.push(DoorRequest {
+            cmd : CmdDoor { door, action, by : None },
+            denied
+        });
+        let mut schedule = Schedule::default();
+        schedule.add_systems(apply_door_requests);
+        schedule.run(world);
+        world.get::<Door>(door).unwrap().is_open
+    }
+
+    #[test]
+    fn locked_door_closes_but_does_not_open() {
+        let mut world = World::new();
+        world.insert_resource(DoorRequests::default());
+        world.insert_resource(Events::<DoorDenied>::default());
+        let door = world.spawn(Door { is_open : true, ..default() }).id();
+
+        assert!(send(&mut world, door, DoorAction::Lock, false));
+        assert!(!send(&mut world, door, DoorAction::Close, false));
+        assert!(!send(&mut world, door, DoorAction::Toggle, false));
+        assert_eq!(world.resource::<Events<DoorDenied>>().len(), 1);
+
+        // a denied request changes nothing in both directions
+        assert!(!send(&mut world, door, DoorAction::Unlock, true));
+        assert!(world.get::<Door>(door).unwrap().locked);
+        assert!(!send(&mut world, door, DoorAction::Unlock, false));
+        assert!(send(&mut world, door, DoorAction::Open, false));
+        assert!(send(&mut world, door, DoorAction::Close, true));
+        assert_eq!(world.resource::<Events<DoorDenied>>().len(), 3);
+    }
+
+    #[test]
+    fn only_leaves_slide() {
+        let mut world = World::new();
+        let start = Instant::now();
+        let mut time = Time::new(start);
+        time.update_with_instant(start + Duration::from_secs(1));
+        world.insert_resource(time);
+
+        let leaf = world.spawn((DoorLeaf, DTransform::default())).id();
+        let other = world.spawn(DTransform::from_xyz(0.0, 0.0, 1.0)).id();
+        let door = world.spawn(Door {
+            is_open : true,
+            opened_pos : Vec3::new(1.9, 0.0, 0.0),
+            ..default()
+        }).push_children(&[leaf, other]).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(animate_door);
+        schedule.run(&mut world);
+
+        assert_eq!(world.get::<Door>(door).unwrap().state, DoorState::Open);
+        assert_eq!(world.get::<DTransform>(leaf).unwrap().translation, DVec3::new(1.9f32 as f64, 0.0, 0.0));
+        assert_eq!(world.get::<DTransform>(other).unwrap().translation, DVec3::new(0.0, 0.0, 1.0));
+    }
+}
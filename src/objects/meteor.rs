@@ -1,6 +1,13 @@
-use bevy::prelude::*;
-use bevy_proto::prelude::{PrototypesMut, ProtoCommands, Schematic, ReflectSchematic};
-use rand::Rng;
+use bevy::{prelude::*, math::DVec3};
+use bevy_proto::prelude::{Schematic, ReflectSchematic};
+use bevy_transform64::prelude::DTransform;
+use bevy_xpbd_3d::prelude::RigidBody;
+
+use crate::DSpatialBundle;
+use crate::space_voxel::lod::{VoxelLod, select_voxel_lod};
+use super::asteroid::*;
+use super::radar::RadarDetected;
+
 #[derive(Component, Reflect, Default, Schematic)]
 #[reflect(Schematic)]
 pub struct Meteor {
@@ -9,72 +16,72 @@ pub struct Meteor {
 
 #[derive(Hash, PartialEq, Eq, Clone, Debug, Event)]
 pub enum MeteorFieldCommand {
-    Spawn,
+    /// Field of procedural asteroids, the same seed gives the same field
+    Spawn { seed : u64 },
     Despawn,
 }
 
 pub struct MetorFieldPlugin;
 
-#[derive(Resource, Default)]
-struct MeteorFieldState {
-    pub protos : Vec<HandleUntyped>
-}
-
 impl Plugin for MetorFieldPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<MeteorFieldCommand>();
 
-        app.insert_resource(MeteorFieldState::default());
+        app.init_resource::<AsteroidMaterials>();
+        app.add_systems(Startup, setup_asteroid_materials);
         app.add_systems(Update, meteor_field_spawn);
-        app.add_systems(Update, proto_loading);
+        app.add_systems(Update, (generate_asteroids, poll_asteroid_tasks, update_asteroid_chunks).chain()
+            .after(meteor_field_spawn)
+            .after(select_voxel_lod));
         app.register_type::<Meteor>();
+        app.register_type::<AsteroidSeed>();
     }
 }
 
-fn proto_loading(mut prototypes : PrototypesMut, _field : ResMut<MeteorFieldState>) {
-    prototypes.load("space_objects/asteroid_1.prototype.ron");
+/// Seed of the asteroid `i` of the field and its position
+fn field_asteroid(field_seed : u64, i : u64, radius : f64) -> (AsteroidSeed, DVec3) {
+    let h = hash_cell(field_seed, IVec3::new(i as i32, 0, 0));
+    let unit = |salt : i32| (hash_cell(h, IVec3::new(salt, 0, 0)) >> 11) as f64 / (1u64 << 53) as f64;
+    let pos = DVec3::new(unit(0), unit(1), unit(2)) * 2.0 - DVec3::ONE;
+    let seed = AsteroidSeed {
+        seed : h,
+        radius : 8 + (unit(3) * 16.0) as i32
+    };
+    (seed, pos * radius)
 }
 
 fn meteor_field_spawn(
     mut commands: Commands,
-    mut proto_commands : ProtoCommands,
-    _meshes: ResMut<Assets<Mesh>>,
-    _materials: ResMut<Assets<StandardMaterial>>,
     mut events : EventReader<MeteorFieldCommand>,
-    query : Query<Entity, With<Meteor>>,
-    _asset_server : Res<AssetServer>
+    query : Query<Entity, With<Meteor>>
 ) {
     for event in events.iter() {
         match event {
-            MeteorFieldCommand::Spawn => {
+            MeteorFieldCommand::Spawn { seed } => {
                 for entity in query.iter() {
                     commands.entity(entity).despawn_recursive();
                 }
 
-                //spawn new meteors in 1km radius
-                let mut rng = rand::thread_rng();
+                //spawn new meteors in 10km radius
                 let count = 100;
                 let radius = 10000.0;
                 let min_dist = 100.0;
-                for _ in 0..count {
-                    let spawn_pos = Vec3::new(
-                        rng.gen_range(-1.0..1.0) * radius,
-                        rng.gen_range(-1.0..1.0) * radius,
-                        rng.gen_range(-1.0..1.0) * radius,
-                    );
+                for i in 0..count {
+                    let (asteroid_seed, spawn_pos) = field_asteroid(*seed, i, radius);
                     if spawn_pos.length() < min_dist {
                         continue;
                     }
-                    let _entity = proto_commands.spawn("Asteroid 1");
-                    // entity.entity_commands().insert(
-                    //     DSpatialBundle::from_transform(DTransform::from_xyz(
-                    //         spawn_pos.x as f64,
-                    //         spawn_pos.y as f64,
-                    //         spawn_pos.z as f64,
-                    //     ).with_scale(DVec3::new(200.0, 200.0,200.0))),
-                    // ).insert(Meteor{})
-                    // .insert(RadarDetected{ color : Color::YELLOW})
-                    // .insert(SpaceCollider(ColliderBuilder::ball(0.5).build()));
+                    commands.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(
+                            spawn_pos.x,
+                            spawn_pos.y,
+                            spawn_pos.z,
+                        )))
+                        .insert(asteroid_seed)
+                        .insert(Meteor {})
+                        .insert(RigidBody::Static)
+                        .insert(VoxelLod::default())
+                        .insert(RadarDetected { color : Color::YELLOW })
+                        .insert(Name::new("Asteroid"));
                 }
             }
             MeteorFieldCommand::Despawn => {
@@ -84,4 +91,4 @@ fn meteor_field_spawn(
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -1,18 +1,22 @@
 pub mod pilot_seat;
 pub mod meteor;
+pub mod asteroid;
 pub mod radar;
 pub mod door;
 pub mod ship_camera;
 pub mod gravity_generator;
 pub mod guns;
+pub mod ship_systems;
 
 pub mod prelude {
     pub use super::pilot_seat::*;
     pub use super::meteor::*;
+    pub use super::asteroid::*;
     pub use super::*;
     pub use radar::*;
     pub use door::*;
     pub use gravity_generator::*;
+    pub use ship_systems::*;
 }
 
 use bevy::prelude::*;
@@ -26,6 +30,7 @@ impl Plugin for SpaceObjectsPlugin {
         app.add_plugin(radar::RadarPlugin);
         app.add_plugin(door::DoorPlugin);
         app.add_plugin(gravity_generator::GravityGeneratorPlugin);
+        app.add_plugin(ship_systems::ShipSystemsPlugin);
         app.register_type::<ship_camera::ShipCamera>();
     }
 }
\ No newline at end of file
@@ -1,7 +1,7 @@
 
 
 
-use bevy::{prelude::*, math::DVec3};
+use bevy::{prelude::*, math::{DVec3, DMat3}};
 use bevy_egui::*;
 use bevy_proto::prelude::{Schematic, ReflectSchematic};
 use bevy_transform64::prelude::DTransform;
@@ -10,7 +10,9 @@ use bevy_xpbd_3d::prelude::*;
 
 use crate::{pawn_system::{ChangePawn, Pawn, CurrentPawn}, control::{Action, PilotingAction}, ship::Ship, scenes::{settings::settings_system, fps_mode::IsFPSMode}};
 
-use super::ship_camera::ShipCamera;
+use crate::DSpatialBundle;
+
+use super::{ship_camera::ShipCamera, radar::Radar, ship_systems::ShipSystems};
 
 struct PawnCache {
     pawn : Entity,
@@ -31,6 +33,10 @@ pub struct PilotSeat {
 pub struct PilotSeatPlugin;
 
 const PILOT_POSITION : DVec3 = DVec3::new(0.0, 0.5, 0.0);
+/// Turning torque in N·m, shared by the pilot input and the stabilizers
+const PILOT_TORQUE : f64 = 400_000.0;
+/// How fast the stabilizers try to stop the spin, 1/s
+const STABILIZER_RATE : f64 = 5.0;
 
 impl Plugin for PilotSeatPlugin {
     fn build(&self, app: &mut App) {
@@ -47,11 +53,24 @@ impl Plugin for PilotSeatPlugin {
             Update,
             piloting.run_if(in_state(IsFPSMode::Yes))
         );
+        app.add_systems(Update, attach_radar);
 
         app.register_type::<PilotSeat>();
     }
 }
 
+/// Every pilot seat carries a radar screen above the console
+fn attach_radar(
+    mut cmds : Commands,
+    seats : Query<Entity, Added<PilotSeat>>
+) {
+    for seat in seats.iter() {
+        let radar = cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(0.0, 1.0, -0.75)))
+            .insert(Radar::default()).id();
+        cmds.entity(seat).add_child(radar);
+    }
+}
+
 fn camera_selection(
     _cameras : Query<&mut Transform, With<ShipCamera>>,
 ) {
@@ -59,48 +78,57 @@ fn camera_selection(
 }
 
 fn piloting(
-    mut pilot_seats : Query<(&DTransform, &mut PilotSeat), Without<Pawn>>,
-    mut ships : Query<(&DTransform, &mut LinearVelocity, &mut AngularVelocity, &mut ExternalForce, &mut ExternalTorque), With<Ship>>,
+    mut pilot_seats : Query<(&DTransform, &Parent, &mut PilotSeat), Without<Pawn>>,
+    mut ships : Query<(&DTransform, &mut AngularVelocity, &mut ShipSystems, &Inertia, &InverseInertia), With<Ship>>,
     input : Res<Input<Action>>,
+    time : Res<Time>,
     mut pawns : Query<(&mut DTransform, &Pawn), (Without<Ship>, Without<ShipCamera>)>,
     cameras : Query<&DTransform, (Without<Ship>, With<ShipCamera>)>
 ) {
-    for (pilot_seat_transform, mut pilot_seat) in pilot_seats.iter_mut() {
+    for (pilot_seat_transform, seat_parent, mut pilot_seat) in pilot_seats.iter_mut() {
         if pilot_seat.pawn.is_some() {
-            let (ship_transform, _ship_velocity, mut ship_angular, mut ship_impulse, _ship_torgue) = ships.iter_mut().next().unwrap();
+            let Ok((ship_transform, mut ship_angular, mut ship_systems, inertia, inv_inertia)) = ships.get_mut(seat_parent.get()) else {
+                continue;
+            };
             let forward = ship_transform.forward();
             let right = ship_transform.right();
             let up = ship_transform.up();
-            let mut target_linvel = DVec3::ZERO;
-            let speed = 100.0;
+            // engines facing the requested direction are throttled up by the ship systems
+            let mut thrust = DVec3::ZERO;
             if input.pressed(Action::Piloting(PilotingAction::MoveForward)) {
-                target_linvel += forward * speed;
+                thrust += DVec3::NEG_Z;
             }
             if input.pressed(Action::Piloting(PilotingAction::MoveBackward)) {
-                target_linvel -= forward * speed;
+                thrust += DVec3::Z;
             }
-            ship_impulse.apply_force(target_linvel);
+            ship_systems.thrust_command = thrust;
 
-            let mut angvel = -ship_angular.0 * 0.9;
+            let mut turn = DVec3::ZERO;
             if input.pressed(Action::Piloting(PilotingAction::TurnUp)) {
-                angvel += right;
+                turn += right;
             }
             if input.pressed(Action::Piloting(PilotingAction::TurnDown)) {
-                angvel -= right;
+                turn -= right;
             }
             if input.pressed(Action::Piloting(PilotingAction::TurnLeft)) {
-                angvel += up;
+                turn += up;
             }
             if input.pressed(Action::Piloting(PilotingAction::TurnRight)) {
-                angvel -= up;
+                turn -= up;
             }
             if input.pressed(Action::Piloting(PilotingAction::RollLeft)) {
-                angvel += forward;
+                turn += forward;
             }
             if input.pressed(Action::Piloting(PilotingAction::RollRight)) {
-                angvel -= forward;
+                turn -= forward;
             }
-            ship_angular.0 += angvel;
+            // inertia is stored in the ship frame
+            let rot = DMat3::from_quat(ship_transform.rotation);
+            let inertia = rot * inertia.0 * rot.transpose();
+            let inv_inertia = rot * inv_inertia.0 * rot.transpose();
+            let stabilize = (inertia * -ship_angular.0 * STABILIZER_RATE).clamp_length_max(PILOT_TORQUE);
+            let torque = turn * PILOT_TORQUE + stabilize;
+            ship_angular.0 += inv_inertia * torque * time.delta_seconds_f64();
 
             if let Ok((mut pawn_tranform, _pawn)) = pawns.get_mut(pilot_seat.pawn.as_ref().unwrap().pawn) {
                 if pilot_seat.current_camera.is_none() {
@@ -127,19 +155,26 @@ fn piloting(
 }
 
 fn pilot_debug_ui(
-   mut pilot_seats : Query<&mut PilotSeat, Without<Pawn>>,
+   mut pilot_seats : Query<(&mut PilotSeat, Option<&Parent>), Without<Pawn>>,
    mut egui_ctxs : Query<&mut EguiContext>,
-   ships : Query<(&DTransform, &LinearVelocity), With<Ship>>,
+   ships : Query<(&DTransform, &LinearVelocity, Option<&ShipSystems>), With<Ship>>,
    _pawns : Query<(&DTransform, &Pawn)>,
 ) {
 
     let mut ctx = egui_ctxs.single_mut();
     egui::SidePanel::left("pilot_debug_ui").show(ctx.get_mut(), |ui| {
-        for mut pilot_seat in pilot_seats.iter_mut() {
+        for (mut pilot_seat, parent) in pilot_seats.iter_mut() {
             if let Some(_pawn) = &mut pilot_seat.pawn {
-                let (ship_transform, ship_vel) = ships.iter().next().unwrap();
+                let Some((ship_transform, ship_vel, systems)) = parent.and_then(|parent| ships.get(parent.get()).ok()) else {
+                    continue;
+                };
                 ui.label(format!("Distance from world origin: {:.0}", ship_transform.translation.distance(DVec3::ZERO)));
                 ui.label(format!("Ship velocity {:.2}", ship_vel.length()));
+                if let Some(systems) = systems {
+                    ui.label(format!("Engines: {}, thrust {:.0} N", systems.engine_count, systems.thrust));
+                    ui.label(format!("Fuel: {:.1} / {:.1} kg", systems.fuel, systems.fuel_capacity));
+                    ui.label(format!("Power: {:.0} / {:.0} W", systems.power_supply, systems.power_demand));
+                }
                 // ui.label(format!("Ship rotation velocity {:.2}", ship_vel.angvel.length()));
 
                 ui.label(format!("Camera count: {}", pilot_seat.cameras.len()));
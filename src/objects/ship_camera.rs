@@ -3,6 +3,6 @@ use bevy::prelude::*;
 use bevy_proto::prelude::{Schematic, ReflectSchematic};
 
 #[derive(Component, Reflect, Default, Schematic)]
-#[reflect(Schematic)]
+#[reflect(Component, Schematic)]
 pub struct ShipCamera;
 
@@ -0,0 +1,214 @@
+use bevy::{prelude::*, math::DVec3};
+use bevy_proto::prelude::{Schematic, ReflectSchematic};
+use bevy_transform64::prelude::DGlobalTransform;
+use bevy_xpbd_3d::prelude::*;
+
+use crate::ship::Ship;
+
+/// Pushes the ship along the engine forward (-Z) while it has fuel and power
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct Engine {
+    /// Thrust at full throttle, N
+    pub max_thrust : f64,
+    /// Fuel burned at full throttle, kg/s
+    pub fuel_use : f64,
+    #[reflect(ignore)]
+    pub throttle : f64
+}
+
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct FuelTank {
+    /// kg
+    pub capacity : f64,
+    /// kg. Set by the prototype for new tanks, saves keep what is left
+    #[reflect(default)]
+    pub fuel : f64
+}
+
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct PowerGenerator {
+    /// W
+    pub output : f64
+}
+
+/// Anything which needs power to work, engines included
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct PowerConsumer {
+    /// W
+    pub demand : f64
+}
+
+/// State of the ship systems, summed over the blocks of the ship
+#[derive(Component, Default, Clone)]
+pub struct ShipSystems {
+    /// Requested thrust direction in the ship frame, length up to 1. Reset after every step
+    pub thrust_command : DVec3,
+    pub power_supply : f64,
+    pub power_demand : f64,
+    pub fuel : f64,
+    pub fuel_capacity : f64,
+    /// Thrust produced during the last step, N
+    pub thrust : f64,
+    pub engine_count : usize
+}
+
+impl ShipSystems {
+    /// Share of the demand covered by generators
+    pub fn power_ratio(&self) -> f64 {
+        if self.power_demand <= 0.0 {
+            1.0
+        } else {
+            (self.power_supply / self.power_demand).min(1.0)
+        }
+    }
+}
+
+pub struct ShipSystemsPlugin;
+
+impl Plugin for ShipSystemsPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_type::<Engine>();
+        app.register_type::<FuelTank>();
+        app.register_type::<PowerGenerator>();
+        app.register_type::<PowerConsumer>();
+
+        app.add_systems(Update, init_ship_systems);
+        app.add_systems(PostUpdate, simulate_ship_systems);
+    }
+}
+
+fn init_ship_systems(
+    mut cmds : Commands,
+    ships : Query<Entity, Added<Ship>>
+) {
+    for ship in ships.iter() {
+        cmds.entity(ship).insert(ShipSystems::default());
+    }
+}
+
+/// Sets engine throttles from the thrust command, burns fuel, shares power and applies the thrust
+fn simulate_ship_systems(
+    time : Res<Time>,
+    mut ships : Query<(&mut ShipSystems, &DGlobalTransform, &Children, Option<&CenterOfMass>, &mut ExternalForce, &mut ExternalTorque)>,
+    mut engines : Query<(&mut Engine, &DGlobalTransform)>,
+    mut tanks : Query<&mut FuelTank>,
+    generators : Query<&PowerGenerator>,
+    consumers : Query<&PowerConsumer>
+) {
+    let dt = time.delta_seconds_f64();
+    for (mut systems, ship_transform, children, com, mut force, mut torque) in ships.iter_mut() {
+        let command = ship_transform.transform_point(systems.thrust_command) - ship_transform.translation();
+        systems.thrust_command = DVec3::ZERO;
+
+        systems.power_supply = children.iter().filter_map(|e| generators.get(*e).ok()).map(|g| g.output).sum();
+        systems.power_demand = children.iter().filter_map(|e| consumers.get(*e).ok()).map(|c| c.demand).sum();
+        let power_ratio = systems.power_ratio();
+
+        systems.engine_count = 0;
+        let mut fuel_needed = 0.0;
+        for child in children.iter() {
+            if let Ok((mut engine, engine_transform)) = engines.get_mut(*child) {
+                engine.throttle = engine_transform.forward().dot(command).clamp(0.0, 1.0);
+                fuel_needed += engine.throttle * engine.fuel_use * dt;
+                systems.engine_count += 1;
+            }
+        }
+
+        systems.fuel = 0.0;
+        systems.fuel_capacity = 0.0;
+        for child in children.iter() {
+            if let Ok(tank) = tanks.get(*child) {
+                systems.fuel += tank.fuel;
+                systems.fuel_capacity += tank.capacity;
+            }
+        }
+        let fuel_ratio = if fuel_needed > 0.0 {
+            (systems.fuel / fuel_needed).min(1.0)
+        } else {
+            1.0
+        };
+        // every tank gives the same share of its content
+        if systems.fuel > 0.0 {
+            let burned = fuel_needed * fuel_ratio;
+            for child in children.iter() {
+                if let Ok(mut tank) = tanks.get_mut(*child) {
+                    let share = tank.fuel / systems.fuel;
+                    tank.fuel = (tank.fuel - burned * share).max(0.0);
+                }
+            }
+            systems.fuel = (systems.fuel - burned).max(0.0);
+        }
+
+        let com = ship_transform.transform_point(com.map(|com| com.0).unwrap_or(DVec3::ZERO));
+        let mut total_force = DVec3::ZERO;
+        let mut total_torque = DVec3::ZERO;
+        for child in children.iter() {
+            if let Ok((engine, engine_transform)) = engines.get(*child) {
+                let thrust = engine_transform.forward() * engine.max_thrust * engine.throttle * fuel_ratio * power_ratio;
+                total_force += thrust;
+                total_torque += (engine_transform.translation() - com).cross(thrust);
+            }
+        }
+        systems.thrust = total_force.length();
+        force.set_force(total_force);
+        torque.set_torque(total_torque);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bevy_transform64::prelude::DTransform;
+
+    use super::*;
+
+    #[test]
+    fn engine_burns_fuel_and_pushes() {
+        let mut world = World::new();
+        let start = Instant::now();
+        let mut time = Time::new(start);
+        time.update_with_instant(start + Duration::from_secs(1));
+        world.insert_resource(time);
+
+        let at_origin = || DGlobalTransform::from(DTransform::default());
+        let engine = world.spawn((
+            Engine { max_thrust : 1000.0, fuel_use : 2.0, throttle : 0.0 },
+            PowerConsumer { demand : 100.0 },
+            at_origin()
+        )).id();
+        let tank = world.spawn(FuelTank { capacity : 10.0, fuel : 3.0 }).id();
+        let generator = world.spawn(PowerGenerator { output : 50.0 }).id();
+        let ship = world.spawn((
+            ShipSystems::default(),
+            at_origin(),
+            ExternalForce::default(),
+            ExternalTorque::default()
+        )).push_children(&[engine, tank, generator]).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(simulate_ship_systems);
+        let mut step = |world : &mut World| {
+            world.get_mut::<ShipSystems>(ship).unwrap().thrust_command = DVec3::NEG_Z;
+            schedule.run(world);
+            (world.get::<ShipSystems>(ship).unwrap().thrust, world.get::<FuelTank>(tank).unwrap().fuel)
+        };
+
+        // full throttle for a second, but only half of the power demand is covered
+        let (thrust, fuel) = step(&mut world);
+        assert!((thrust - 500.0).abs() < 1e-9);
+        assert!((fuel - 1.0).abs() < 1e-9);
+
+        // the tank runs dry half way through the step
+        let (thrust, fuel) = step(&mut world);
+        assert!((thrust - 250.0).abs() < 1e-9);
+        assert_eq!(fuel, 0.0);
+
+        let (thrust, _) = step(&mut world);
+        assert_eq!(thrust, 0.0);
+    }
+}
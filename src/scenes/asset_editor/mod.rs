@@ -7,7 +7,7 @@ use bevy_prototype_debug_lines::DebugLines;
 use bevy_transform64::{DTransformBundle, prelude::{DTransform, DGlobalTransform}, SimpleWorldOrigin};
 use serde::{Serialize, Deserialize};
 use bevy_xpbd_3d::prelude::*;
-use crate::{SceneType, pawn_system::Pawn, ship::{instance_rotate::InstanceRotate, prelude::VoxelInstance, VOXEL_SIZE}};
+use crate::{SceneType, pawn_system::Pawn, ship::{instance_rotate::InstanceRotate, prelude::{VoxelInstance, BlockMaterial}, VOXEL_SIZE}};
 use bevy_common_assets::ron::RonAssetPlugin;
 
 use self::ron_collider::{RonColliderCompound, RonCollider, RonBoxCollider, RonSphereCollider};
@@ -411,7 +411,8 @@ fn setup_block(mut cmds : Commands, query : Query<(Entity, &BlockConfig, Option<
         let instance = VoxelInstance {
             bbox : config.bbox,
             common_id : 0,
-            origin : config.origin
+            origin : config.origin,
+            mass : BlockMaterial::default().block_mass(config.bbox, VOXEL_SIZE)
         };
         let _bbox = instance.bbox;
 
@@ -0,0 +1,349 @@
+use bevy::prelude::*;
+
+use super::*;
+
+const HISTORY_LIMIT : usize = 256;
+
+/// Everything needed to put a placed instance back into the ship
+#[derive(Clone)]
+pub struct PlacedBlock {
+    pub name : String,
+    pub idx : IVec3,
+    pub bbox : IVec3,
+    pub transform : DTransform,
+    pub rotate : InstanceRotate,
+    /// Entity in [`InstanceStates`] which holds the saved components of the erased instance
+    pub state : Option<Entity>
+}
+
+/// Raw voxel cell change, `None` is an empty cell
+#[derive(Clone, Copy)]
+pub struct VoxelEdit {
+    pub idx : IVec3,
+    pub from : Option<ShipBlock>,
+    pub to : Option<ShipBlock>
+}
+
+#[derive(Clone)]
+pub enum BuildCommand {
+    Place(Vec<PlacedBlock>),
+    Erase(Vec<PlacedBlock>),
+    Sculpt(Vec<VoxelEdit>),
+    /// Placed instances with the lowest cells `blocks` shifted by `offset`, the entities keep living
+    Move {
+        blocks : Vec<IVec3>,
+        offset : IVec3
+    },
+    /// Several commands which are undone and redone as one step
+    Batch(Vec<BuildCommand>)
+}
+
+impl BuildCommand {
+    fn is_empty(&self) -> bool {
+        match self {
+            BuildCommand::Place(blocks) | BuildCommand::Erase(blocks) => blocks.is_empty(),
+            BuildCommand::Sculpt(edits) => edits.is_empty(),
+            BuildCommand::Move { blocks, offset } => blocks.is_empty() || *offset == IVec3::ZERO,
+            BuildCommand::Batch(cmds) => cmds.iter().all(|cmd| cmd.is_empty()),
+        }
+    }
+
+    fn inverse(&self) -> BuildCommand {
+        match self {
+            BuildCommand::Place(blocks) => BuildCommand::Erase(blocks.clone()),
+            BuildCommand::Erase(blocks) => BuildCommand::Place(blocks.clone()),
+            BuildCommand::Sculpt(edits) => BuildCommand::Sculpt(
+                edits.iter().rev().map(|edit| VoxelEdit {
+                    idx : edit.idx,
+                    from : edit.to,
+                    to : edit.from
+                }).collect()
+            ),
+            BuildCommand::Move { blocks, offset } => BuildCommand::Move {
+                blocks : blocks.iter().map(|idx| *idx + *offset).collect(),
+                offset : -*offset
+            },
+            BuildCommand::Batch(cmds) => BuildCommand::Batch(
+                cmds.iter().rev().map(|cmd| cmd.inverse()).collect()
+            ),
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct BuildHistory {
+    pub undo : Vec<BuildCommand>,
+    pub redo : Vec<BuildCommand>
+}
+
+impl BuildHistory {
+    pub fn push(&mut self, cmd : BuildCommand) {
+        if cmd.is_empty() {
+            return;
+        }
+        self.undo.push(cmd);
+        self.redo.clear();
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
+
+/// Components of erased instances which are kept by [`SaveLoadCfg`] (door lock, fuel...),
+/// so undo brings the instance back as it was and not as a fresh prototype
+#[derive(Resource, Default)]
+pub struct InstanceStates {
+    pub world : World
+}
+
+impl InstanceStates {
+    pub fn clear(&mut self) {
+        self.world.clear_entities();
+    }
+}
+
+/// Copies the saved components of `e` into `state`, the command has to run before `e` is despawned
+fn store_state(cmds : &mut Commands, e : Entity, state : Entity) {
+    cmds.add(move |world : &mut World| {
+        world.resource_scope(|world, cfg : Mut<SaveLoadCfg>| {
+            world.resource_scope(|world, mut states : Mut<InstanceStates>| {
+                if let (Some(mut src), Some(mut dst)) = (world.get_entity(e), states.world.get_entity_mut(state)) {
+                    cfg.save.copy(&mut dst, &mut src);
+                }
+            });
+        });
+    });
+}
+
+fn restore_state(cmds : &mut Commands, e : Entity, state : Entity) {
+    cmds.add(move |world : &mut World| {
+        world.resource_scope(|world, cfg : Mut<SaveLoadCfg>| {
+            world.resource_scope(|world, states : Mut<InstanceStates>| {
+                if let (Some(mut src), Some(mut dst)) = (states.world.get_entity(state), world.get_entity_mut(e)) {
+                    cfg.save.copy(&mut dst, &mut src);
+                }
+            });
+        });
+    });
+}
+
+pub type PlacedInstanceQuery<'w, 's> = Query<'w, 's, (&'static VoxelInstance, &'static DTransform, &'static InstanceRotate, &'static InstanceGridPos), Without<ActiveBlock>>;
+
+pub fn instance_name(all_instances : &AllVoxelInstances, common_id : u32) -> Option<String> {
+    all_instances.configs.iter()
+        .find(|cfg| cfg.instance.common_id == common_id)
+        .map(|cfg| cfg.name.clone())
+}
+
+pub fn place_block(
+    cmds : &mut Commands,
+    asset_server : &AssetServer,
+    all_instances : &AllVoxelInstances,
+    ship_e : Entity,
+    ship : &mut Ship,
+    block : &PlacedBlock
+) -> Option<Entity> {
+    if !ship.map.can_place_object(&block.idx, &block.bbox) {
+        return None;
+    }
+    let inst_cfg = all_instances.configs.iter().find(|cfg| cfg.name == block.name)?;
+
+    let e = inst_cfg.create.build(cmds, asset_server);
+    if let Some(state) = block.state {
+        restore_state(cmds, e, state);
+    }
+    ship.map.place_object(e, Footprint {
+        idx : block.idx,
+        bbox : block.bbox,
+        rot_steps : block.rotate.rot_steps
+    });
+    cmds.entity(e)
+        .insert(block.transform)
+        .insert(block.rotate.clone())
+        .insert(InstanceGridPos {
+            idx : block.idx,
+            bbox : block.bbox
+        });
+    cmds.entity(ship_e).add_child(e);
+    Some(e)
+}
+
+/// Removes the instance which occupies `idx` and returns what is needed to restore it
+pub fn erase_block(
+    cmds : &mut Commands,
+    all_instances : &AllVoxelInstances,
+    states : &mut InstanceStates,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery,
+    idx : &IVec3
+) -> Option<PlacedBlock> {
+    erase_block_into(cmds, all_instances, states, ship, instances, idx, None)
+}
+
+/// Same as [`erase_block`], but the state goes into `state` when the command already has one
+fn erase_block_into(
+    cmds : &mut Commands,
+    all_instances : &AllVoxelInstances,
+    states : &mut InstanceStates,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery,
+    idx : &IVec3,
+    state : Option<Entity>
+) -> Option<PlacedBlock> {
+    let VoxelVal::Object(e) = ship.map.get_by_idx(idx).clone() else {
+        return None;
+    };
+
+    ship.map.erase_object(e);
+    let placed = if let Ok((inst, tr, rot, grid_pos)) = instances.get(e) {
+        instance_name(all_instances, inst.common_id).map(|name| {
+            let state = state.unwrap_or_else(|| states.world.spawn_empty().id());
+            store_state(cmds, e, state);
+            PlacedBlock {
+                name,
+                idx : grid_pos.idx,
+                bbox : grid_pos.bbox,
+                transform : *tr,
+                rotate : rot.clone(),
+                state : Some(state)
+            }
+        })
+    } else {
+        None
+    };
+
+    cmds.entity(e).despawn_recursive();
+    placed
+}
+
+/// Shifts the instances which occupy `blocks` by `offset` cells. Nothing is changed and `false` is returned
+/// if any of them does not fit at the new place
+pub fn move_objects(
+    cmds : &mut Commands,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery,
+    blocks : &[IVec3],
+    offset : IVec3
+) -> bool {
+    // free the moved cells, so blocks can overlap their own previous place
+    let freed : Vec<_> = blocks.iter()
+        .filter_map(|idx| ship.map.object_at(idx))
+        .filter_map(|e| ship.map.erase_object(e).map(|footprint| (e, footprint)))
+        .collect();
+    let fits = freed.iter().all(|(_, footprint)| ship.map.can_place_object(&(footprint.idx + offset), &footprint.bbox));
+    let offset = if fits { offset } else { IVec3::ZERO };
+
+    let shift = offset.as_dvec3() * ship.map.voxel_size;
+    for (e, mut footprint) in freed {
+        footprint.idx += offset;
+        ship.map.place_object(e, footprint);
+        if !fits {
+            continue;
+        }
+        if let Ok((_, tr, _, _)) = instances.get(e) {
+            let mut tr = *tr;
+            tr.translation += shift;
+            cmds.entity(e)
+                .insert(tr)
+                .insert(InstanceGridPos {
+                    idx : footprint.idx,
+                    bbox : footprint.bbox
+                });
+        }
+    }
+    fits
+}
+
+/// Sets a raw voxel cell. Cells of instances are left alone, `None` is returned when nothing changed
+pub fn set_cell(ship : &mut Ship, idx : &IVec3, to : Option<ShipBlock>) -> Option<VoxelEdit> {
+    let from = match ship.map.get_by_idx(idx) {
+        VoxelVal::None => None,
+        VoxelVal::Voxel(block) => Some(*block),
+        VoxelVal::Object(_) => return None
+    };
+    if from == to {
+        return None;
+    }
+    ship.map.set_voxel_by_idx(idx, to.map_or(VoxelVal::None, VoxelVal::Voxel));
+    Some(VoxelEdit {
+        idx : *idx,
+        from,
+        to
+    })
+}
+
+fn apply_command(
+    cmd : &BuildCommand,
+    cmds : &mut Commands,
+    asset_server : &AssetServer,
+    all_instances : &AllVoxelInstances,
+    states : &mut InstanceStates,
+    ship_e : Entity,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery
+) {
+    match cmd {
+        BuildCommand::Place(blocks) => {
+            for block in blocks {
+                place_block(cmds, asset_server, all_instances, ship_e, ship, block);
+            }
+        },
+        BuildCommand::Erase(blocks) => {
+            for block in blocks {
+                erase_block_into(cmds, all_instances, states, ship, instances, &block.idx, block.state);
+            }
+        },
+        BuildCommand::Sculpt(edits) => {
+            for edit in edits {
+                set_cell(ship, &edit.idx, edit.to);
+            }
+        },
+        BuildCommand::Move { blocks, offset } => {
+            move_objects(cmds, ship, instances, blocks, *offset);
+        },
+        BuildCommand::Batch(batch) => {
+            for cmd in batch {
+                apply_command(cmd, cmds, asset_server, all_instances, states, ship_e, ship, instances);
+            }
+        },
+    }
+}
+
+pub fn undo_redo_system(
+    mut cmds : Commands,
+    asset_server : Res<AssetServer>,
+    all_instances : Res<AllVoxelInstances>,
+    input : Res<Input<Action>>,
+    mut block : ResMut<StationBuildBlock>,
+    mut history : ResMut<BuildHistory>,
+    mut states : ResMut<InstanceStates>,
+    mut ships : Query<&mut Ship>,
+    instances : PlacedInstanceQuery
+) {
+    let undo = input.just_pressed(Action::Build(control::BuildAction::Undo)) || block.cmd == StationBuildCmds::Undo;
+    let redo = input.just_pressed(Action::Build(control::BuildAction::Redo)) || block.cmd == StationBuildCmds::Redo;
+    if block.cmd == StationBuildCmds::Undo || block.cmd == StationBuildCmds::Redo {
+        block.cmd = StationBuildCmds::None;
+    }
+
+    let Ok(mut ship) = ships.get_mut(block.ship) else {
+        return;
+    };
+
+    if undo {
+        if let Some(cmd) = history.undo.pop() {
+            apply_command(&cmd.inverse(), &mut cmds, &asset_server, &all_instances, &mut states, block.ship, &mut ship, &instances);
+            history.redo.push(cmd);
+        }
+    } else if redo {
+        if let Some(cmd) = history.redo.pop() {
+            apply_command(&cmd, &mut cmds, &asset_server, &all_instances, &mut states, block.ship, &mut ship, &instances);
+            history.undo.push(cmd);
+        }
+    }
+}
@@ -1,4 +1,10 @@
 mod ui;
+mod history;
+mod selection;
+mod symmetry;
+mod tools;
+mod raycast;
+mod rooms;
 
 use std::f32::consts::PI;
 
@@ -13,6 +19,12 @@ use bevy_transform64::prelude::DTransform;
 use instance_rotate::InstanceRotate;
 use bevy_xpbd_3d::prelude::*;
 use ui::*;
+use history::*;
+use selection::*;
+use symmetry::*;
+use tools::*;
+use raycast::*;
+use rooms::*;
 
 use bevy::prelude::*;
 
@@ -22,6 +34,7 @@ use crate::ship::*;
 use crate::ship::common::{AllVoxelInstances, VoxelInstance, TELEPORN_NAME};
 use crate::*;
 use crate::ship::save_load::*;
+use crate::ship::save_slots::*;
 use crate::space_voxel::VoxelMap;
 use crate::space_voxel::objected_voxel_map::*;
 
@@ -29,7 +42,8 @@ use super::fps_mode::IsFPSMode;
 
 #[derive(Resource, Default)]
 pub struct ActiveWindows {
-    pub load_ship : bool
+    pub load_ship : bool,
+    pub confirm_overwrite : Option<String>
 }
 
 #[derive(Default, Clone, Hash, PartialEq, Eq, Debug, States)]
@@ -66,19 +80,55 @@ impl Plugin for StationBuilderPlugin {
                     clear_all_system,
                     quick_save,
                     quick_load,
+                    autosave_system,
                     capture_loaded_ship,
                     go_to_fps,
                     move_camera_build,
                     z_slicing,
-                    load_ship_ui.run_if(|windows : Res<ActiveWindows>| windows.load_ship)
+                    load_ship_ui.run_if(|windows : Res<ActiveWindows>| windows.load_ship),
+                    confirm_overwrite_ui.run_if(|windows : Res<ActiveWindows>| windows.confirm_overwrite.is_some()),
+                    undo_redo_system.after(ship_build_menu)
                 ).in_set(ShipBuildSet::Base));
 
+        app.add_systems(Update,
+                (
+                    selection_ui,
+                    leave_selection_on_pick.after(ship_build_menu),
+                    selection_system.after(selection_ui).after(leave_selection_on_pick),
+                    draw_selection.after(selection_system),
+                    draw_symmetry,
+                    build_tool_system.after(ship_build_menu).after(selection_ui),
+                    draw_tool_preview.after(build_tool_system),
+                    sculpt_system.after(ship_build_menu).after(selection_ui)
+                ).in_set(ShipBuildSet::Base));
+
+        app.add_systems(Update, draw_rooms.in_set(ShipBuildSet::Base));
+
+        app.insert_resource(BuildHistory::default());
+        app.insert_resource(InstanceStates::default());
+        app.insert_resource(BuildSelection::default());
+        app.insert_resource(BuildSymmetry::default());
+        app.insert_resource(BuildTools::default());
+        app.insert_resource(BuildRooms::default());
+
+        app.insert_resource(AutosaveState::default());
+
         app.add_plugins(StationBuilderUI);
     }
 }
 
 pub enum BuildMode {
-    SingleOnY(f64)
+    SingleOnY(f64),
+    /// Snaps to the faces of placed blocks, the level is used where the cursor hits nothing
+    Raycast(f64)
+}
+
+impl BuildMode {
+    pub fn level(&self) -> f64 {
+        match self {
+            BuildMode::SingleOnY(lvl) | BuildMode::Raycast(lvl) => *lvl
+        }
+    }
 }
 
 #[derive(Resource)]
@@ -98,7 +148,9 @@ pub enum StationBuildCmds {
     ClearAll,
     QuickSave,
     QuickLoad,
-    GoToFPS
+    GoToFPS,
+    Undo,
+    Redo
 }
 
 #[derive(Component)]
@@ -153,29 +205,32 @@ fn move_camera_build(
     }
 }
 
+/// The current ship is replaced only once the new one is loaded, a broken save keeps it in place
 fn capture_loaded_ship(
+    mut cmds : Commands,
     mut block : ResMut<StationBuildBlock>,
-    mut cmd_load : EventReader<ShipLoaded>
+    mut history : ResMut<BuildHistory>,
+    mut states : ResMut<InstanceStates>,
+    mut cmd_load : EventReader<ShipLoaded>,
+    ship_entity : Query<Entity, With<Ship>>,
 ) {
     for ship in cmd_load.iter() {
+        for e in ship_entity.iter().filter(|e| *e != ship.0) {
+            cmds.entity(e).despawn_recursive();
+        }
         block.ship = ship.0;
+        history.clear();
+        states.clear();
     }
 }
 
 fn quick_load(
-    mut cmds : Commands,
     mut block : ResMut<StationBuildBlock>,
     mut cmd_load : EventWriter<CmdShipLoad>,
-    ship_entity : Query<Entity, With<Ship>>,
 ) {
     if block.cmd == StationBuildCmds::QuickLoad {
         block.cmd = StationBuildCmds::None;
-
-        for e in &ship_entity {
-            cmds.entity(e).despawn_recursive();
-        }
-
-        cmd_load.send(CmdShipLoad("quick.scn.ron".to_string()));
+        cmd_load.send(CmdShipLoad(QUICK_SAVE_PATH.to_string()));
     }
 }
 
@@ -186,19 +241,77 @@ fn quick_save(
 ) {
     if block.cmd == StationBuildCmds::QuickSave {
         block.cmd = StationBuildCmds::None;
-        cmd_save.send(CmdShipSave(block.ship, "quick.scn.ron".to_string()));
+        cmd_save.send(CmdShipSave(block.ship, QUICK_SAVE_PATH.to_string()));
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct AutosaveState {
+    pub timer : Timer
+}
+
+/// Autosave ticks only while the builder is active, so play time in FPS mode is not counted
+fn autosave_system(
+    mut state : ResMut<AutosaveState>,
+    cfg : Res<SaveSlotsCfg>,
+    time : Res<Time>,
+    block : Res<StationBuildBlock>,
+    ships : Query<&Ship>,
+    mut cmd_save : EventWriter<CmdShipSave>
+) {
+    if !cfg.autosave_enabled {
+        return;
+    }
+    let duration = std::time::Duration::from_secs_f32(cfg.autosave_minutes.max(0.1) * 60.0);
+    if state.timer.duration() != duration {
+        state.timer = Timer::new(duration, TimerMode::Repeating);
+    }
+
+    if state.timer.tick(time.delta()).just_finished() && ships.contains(block.ship) {
+        cmd_save.send(CmdShipSave(block.ship, AUTOSAVE_PATH.to_string()));
     }
 }
 
 fn clear_all_system(
     mut cmds : Commands,
-    ship_entity : Query<Entity, With<Ship>>,
+    mut ships : Query<(Entity, &mut Ship)>,
     mut block : ResMut<StationBuildBlock>,
-
+    all_instances : Res<AllVoxelInstances>,
+    instances : PlacedInstanceQuery,
+    mut history : ResMut<BuildHistory>,
+    mut states : ResMut<InstanceStates>
 ) {
     if block.cmd == StationBuildCmds::ClearAll {
         block.cmd = StationBuildCmds::None;
-        for e in &ship_entity {
+
+        if let Ok((_, mut ship)) = ships.get_mut(block.ship) {
+            let mut objects = vec![];
+            let mut edits = vec![];
+            for (idx, val) in ship.map.iter() {
+                match val {
+                    VoxelVal::Object(e) => objects.push((*e, idx)),
+                    VoxelVal::Voxel(cell) => edits.push(VoxelEdit {
+                        idx,
+                        from : Some(*cell),
+                        to : None
+                    }),
+                    VoxelVal::None => {}
+                }
+            }
+            objects.sort_by_key(|(e, _)| *e);
+            objects.dedup_by_key(|(e, _)| *e);
+
+            let erased = objects.iter()
+                .filter_map(|(_, idx)| erase_block(&mut cmds, &all_instances, &mut states, &mut ship, &instances, idx))
+                .collect();
+            // the new ship is empty, so undo puts the voxels back into it
+            history.push(BuildCommand::Batch(vec![
+                BuildCommand::Erase(erased),
+                BuildCommand::Sculpt(edits)
+            ]));
+        }
+
+        for (e, _) in &ships {
             cmds.entity(e).despawn_recursive();
         }
 
@@ -207,35 +320,64 @@ fn clear_all_system(
     }
 }
 
+/// Turns the block in hand. It is not in the map yet, so there is nothing for the history,
+/// the rotation is stored by the `Place` command
 fn rotate_block(
     block : ResMut<StationBuildBlock>,
     mut query : Query<(&mut DTransform, &mut InstanceRotate), With<ActiveBlock>>,
-    input : Res<Input<Action>>,
+    input : Res<Input<Action>>
 ) {
-    if let Some(e) = block.e {
-        if input.just_pressed(Action::Build(control::BuildAction::RotateCounterClockwise)) {
-            if let Ok((mut transform, mut rotate)) = query.get_mut(e) {
-                transform.rotate(DQuat::from_rotation_y(PI as f64 / 2.0));
-                rotate.rot_steps.x += 1;
-                
-            }
+    let Some(e) = block.e else {
+        return;
+    };
+    let turns = [
+        (control::BuildAction::RotateCounterClockwise, DVec3::Y),
+        (control::BuildAction::RotateClockwise, DVec3::NEG_Y),
+        (control::BuildAction::RotateX, DVec3::X),
+        (control::BuildAction::RotateZ, DVec3::Z),
+    ];
+    for (action, axis) in turns {
+        if !input.just_pressed(Action::Build(action)) {
+            continue;
+        }
+        if let Ok((mut transform, mut rotate)) = query.get_mut(e) {
+            *rotate = rotate.turned(axis);
+            transform.rotation = rotate.quat();
         }
     }
-   
+}
+
+/// Where the block in hand would be placed
+pub fn active_placement(block : &StationBuildBlock, tr : &DTransform, rot : &InstanceRotate, ship : &Ship) -> PlacedBlock {
+    let inst = block.instance.as_ref().unwrap();
+    let bbox = rot.rotate_bbox(inst.bbox);
+    PlacedBlock {
+        name : block.cur_name.clone(),
+        idx : ship.get_grid_idx_by_center(&(tr.translation - inst.origin_offset(rot)), &bbox),
+        bbox,
+        transform : *tr,
+        rotate : rot.clone(),
+        state : None
+    }
 }
 
 fn spawn_block(
     mut cmds : Commands,
     asset_server : Res<AssetServer>,
     buttons : Res<Input<MouseButton>>,
-    active_blocks : Query<(&mut DTransform, &InstanceRotate), With<ActiveBlock>>,
+    active_blocks : Query<(&DTransform, &InstanceRotate), With<ActiveBlock>>,
     block : ResMut<StationBuildBlock>,
     mut ships : Query<&mut Ship>,
     all_instances : Res<AllVoxelInstances>,
+    instances : PlacedInstanceQuery,
+    mut history : ResMut<BuildHistory>,
+    mut states : ResMut<InstanceStates>,
+    symmetry : Res<BuildSymmetry>,
+    tools : Res<BuildTools>,
     mut ctx : Query<&mut EguiContext>
 ) {
     let mut ctx = ctx.single_mut();
-    if block.e.is_none() {
+    if block.e.is_none() || tools.tool != BuildTool::Single {
         return;
     }
 
@@ -262,39 +404,21 @@ fn spawn_block(
         return;
     }
 
-    let inst = block.instance.as_ref().unwrap();
-    let mut bbox = inst.bbox;
-    if rot.rot_steps.x % 2 == 1 {
-        std::mem::swap(&mut bbox.x, &mut bbox.z);
-    }
-    let hs = bbox.as_dvec3() / 2.0 * VOXEL_SIZE;
-    let grid_idx = ship.get_grid_idx_by_center(&(tr.translation - hs * inst.origin), &bbox);
-    let id = ship.map.get_by_idx(&grid_idx).clone();
+    let placed = active_placement(&block, &tr, &rot, &ship);
+    let grid_idx = placed.idx;
 
     if buttons.pressed(MouseButton::Left) {
-        if ship.map.can_place_object(&grid_idx, &bbox) {
-            // ship.map.set_object_by_idx(e, pos, bbox)
-            for inst_cfg in &all_instances.configs {
-                if inst_cfg.name == block.cur_name {
-                    let e = inst_cfg.create.build(&mut cmds, &asset_server);
-                    ship.map.set_object_by_idx(e, &grid_idx, &bbox);
-                    println!("{:#?}", &tr);
-                    cmds.entity(e)
-                        .insert(tr);
-                    cmds.entity(block.ship).add_child(e);
-                }
-            }
-        }
+        let mut blocks = vec![placed.clone()];
+        blocks.extend(symmetry.mirror_block(&placed, &ship.map));
+        let placed = blocks.into_iter()
+            .filter(|placed| place_block(&mut cmds, &asset_server, &all_instances, block.ship, &mut ship, placed).is_some())
+            .collect();
+        history.push(BuildCommand::Place(placed));
     } else if buttons.pressed(MouseButton::Right) {
-        
-        ship.map.erase_object(&grid_idx, &IVec3::new(50,50,50));
-        match id {
-            VoxelVal::None => {},
-            VoxelVal::Voxel(_) => todo!(),
-            VoxelVal::Object(e) => {
-                cmds.entity(e).despawn_recursive();
-            },
-        }
+        let erased = symmetry.with_mirrored_cells(grid_idx).iter()
+            .filter_map(|idx| erase_block(&mut cmds, &all_instances, &mut states, &mut ship, &instances, idx))
+            .collect();
+        history.push(BuildCommand::Erase(erased));
     }
 
 }
@@ -327,54 +451,71 @@ pub fn viewport_to_world(
     })
 }
 
+pub fn cursor_ray(
+    cameras : &Query<(&Camera, &DGlobalTransform)>,
+    windows : &Query<&Window, With<PrimaryWindow>>
+) -> Option<DRay> {
+    let cursor_pos = windows.get_single().ok()?.cursor_position()?;
+    let (cam, tr) = cameras.iter().next()?;
+
+    viewport_to_world(
+        cam.projection_matrix().as_dmat4(),
+        cam.logical_viewport_size()?.as_dvec2(),
+        tr,
+        cursor_pos.as_dvec2())
+}
+
 fn pos_block(
     cameras : Query<(&Camera, &DGlobalTransform)>,
-    mut active_blocks : Query<&mut DTransform, With<ActiveBlock>>,
+    mut active_blocks : Query<(&mut DTransform, &InstanceRotate), With<ActiveBlock>>,
     block : ResMut<StationBuildBlock>,
     windows : Query<&Window, With<PrimaryWindow>>,
     mut ships : Query<&mut Ship>,
+    ship_transforms : Query<&DGlobalTransform, With<Ship>>
 ) {
     if block.e.is_none() {
         return;
     }
-    let cursot_pos_option = windows.single().cursor_position();
-    if cursot_pos_option.is_none() {
-        return;
-    }
     if !ships.contains(block.ship) {
         return;
     }
-
-    let (cam, tr) = cameras.iter().next().unwrap();
-    let cursor_pos = cursot_pos_option.unwrap();
-
-    let mouse_ray = viewport_to_world(
-        cam.projection_matrix().as_dmat4(),
-        cam.logical_viewport_size().unwrap().as_dvec2(),
-        tr,
-        cursor_pos.as_dvec2()).unwrap();
+    let Some(mouse_ray) = cursor_ray(&cameras, &windows) else {
+        return;
+    };
 
     let e = block.e.unwrap();
     let mut active_tr;
-    if let Ok(tr) = active_blocks.get_mut(e) {
-        (active_tr) = tr;
+    let rot;
+    if let Ok((tr, active_rot)) = active_blocks.get_mut(e) {
+        (active_tr, rot) = (tr, active_rot);
     } else {
         return;
     }
 
-    match block.mode {
-        BuildMode::SingleOnY(lvl) => {
-            let ship = ships.get_mut(block.ship).unwrap();
-
-            let t = (lvl - mouse_ray.origin.y) / mouse_ray.direction.y;
-            let pos = mouse_ray.origin + t * mouse_ray.direction;
-            let bbox = block.instance.as_ref().unwrap().bbox;
-            let hs = bbox.as_dvec3() / 2.0 * ship.map.voxel_size;
-            let corner_pos = pos - hs - hs * block.instance.as_ref().unwrap().origin;
-            let grid_pos = ship.map.get_grid_pos(&corner_pos);
-            active_tr.translation = grid_pos + hs + hs * block.instance.as_ref().unwrap().origin;
-        },
+    let ship = ships.get_mut(block.ship).unwrap();
+    let Ok(ship_tr) = ship_transforms.get(block.ship) else {
+        return;
+    };
+    let inst = block.instance.as_ref().unwrap();
+    let bbox = rot.rotate_bbox(inst.bbox);
+    let hs = bbox.as_dvec3() / 2.0 * ship.map.voxel_size;
+    let offset = inst.origin_offset(rot);
+
+    if let BuildMode::Raycast(_) = block.mode {
+        if let Some(hit) = raycast_map(&ship.map, ship_tr, &mouse_ray, 1000.0) {
+            let corner_pos = ship.map.get_idx_pos(&snap_to_face(&hit, bbox));
+            active_tr.translation = corner_pos + hs + offset;
+            return;
+        }
     }
+
+    // the build level is in the ship frame too
+    let Some(pos) = level_point(&ray_to_local(ship_tr, &mouse_ray), block.mode.level()) else {
+        return;
+    };
+    let corner_pos = pos - hs - offset;
+    let grid_pos = ship.map.get_grid_pos(&corner_pos);
+    active_tr.translation = grid_pos + hs + offset;
 }
 
 
@@ -0,0 +1,40 @@
+use bevy::prelude::*;
+
+use crate::space_voxel::query::VoxelHit;
+
+use super::*;
+
+/// World ray in the frame of the ship with the global transform `ship_tr`
+pub fn ray_to_local(ship_tr : &DGlobalTransform, ray : &DRay) -> DRay {
+    let to_local = ship_tr.compute_matrix().inverse();
+    DRay {
+        origin : to_local.transform_point3(ray.origin),
+        direction : to_local.transform_vector3(ray.direction)
+    }
+}
+
+/// First occupied cell of the ship map along the world ray, the hit is in the ship frame
+pub fn raycast_map(map : &ShipMap, ship_tr : &DGlobalTransform, ray : &DRay, max_dist : f64) -> Option<VoxelHit<VoxelVal<ShipBlock>>> {
+    let ray = ray_to_local(ship_tr, ray);
+    map.raycast(&ray.origin, &ray.direction, max_dist)
+}
+
+/// Point where the ray crosses the horizontal build level
+pub fn level_point(ray : &DRay, level : f64) -> Option<DVec3> {
+    let t = (level - ray.origin.y) / ray.direction.y;
+    (t.is_finite() && t > 0.0).then(|| ray.origin + t * ray.direction)
+}
+
+/// Lowest cell of a `bbox` sized block resting on the hit face, centered on the hit cell
+pub fn snap_to_face<T>(hit : &VoxelHit<T>, bbox : IVec3) -> IVec3 {
+    let target = hit.idx + hit.normal;
+    let mut min = target - bbox / 2;
+    for axis in 0..3 {
+        if hit.normal[axis] > 0 {
+            min[axis] = target[axis];
+        } else if hit.normal[axis] < 0 {
+            min[axis] = target[axis] - bbox[axis] + 1;
+        }
+    }
+    min
+}
@@ -0,0 +1,87 @@
+use bevy::prelude::*;
+use bevy_egui::*;
+use bevy_transform64::SimpleWorldOrigin;
+
+use crate::ship::atmos::ShipAtmos;
+use crate::ship::rooms::ShipRooms;
+
+use super::*;
+
+#[derive(Resource, Default)]
+pub struct BuildRooms {
+    pub overlay : bool,
+    /// Room id
+    pub selected : Option<u32>
+}
+
+pub fn rooms_ui(
+    ui : &mut egui::Ui,
+    cfg : &mut BuildRooms,
+    rooms : Option<Mut<ShipRooms>>,
+    atmos : Option<&ShipAtmos>
+) {
+    ui.checkbox(&mut cfg.overlay, "Show rooms");
+    if !cfg.overlay {
+        return;
+    }
+    let Some(mut rooms) = rooms else {
+        return;
+    };
+
+    let voxel_size = rooms.graph.voxel_size;
+    let inner : Vec<usize> = (0..rooms.graph.rooms.len())
+        .filter(|room| !rooms.graph.rooms[*room].exterior)
+        .collect();
+    ui.label(format!("Rooms: {}", inner.len()));
+    for room in inner {
+        let id = rooms.graph.rooms[room].id;
+        let mut name = rooms.name(room);
+        ui.horizontal(|ui| {
+            if ui.selectable_label(cfg.selected == Some(id), format!("#{}", id)).clicked() {
+                cfg.selected = if cfg.selected == Some(id) { None } else { Some(id) };
+            }
+            if ui.text_edit_singleline(&mut name).changed() {
+                rooms.names.insert(id, name.clone());
+            }
+        });
+        if cfg.selected == Some(id) {
+            let graph = &rooms.graph;
+            ui.label(format!("Volume: {:.1} m³", graph.rooms[room].volume(voxel_size)));
+            ui.label(format!("Doors: {}", graph.rooms[room].doors.len()));
+            let neighbours : Vec<String> = graph.neighbours(room)
+                .map(|(_, other)| rooms.name(other))
+                .collect();
+            if !neighbours.is_empty() {
+                ui.label(format!("Leads to: {}", neighbours.join(", ")));
+            }
+            if let Some(atmos_room) = atmos.and_then(|atmos| atmos.rooms.get(room)) {
+                ui.label(format!("Pressure: {:.1} kPa", atmos_room.pressure()));
+            }
+        }
+    }
+}
+
+/// Bounds of the closed rooms, the selected one is highlighted
+pub fn draw_rooms(
+    mut gizmos : Gizmos,
+    origin : Res<SimpleWorldOrigin>,
+    block : Res<StationBuildBlock>,
+    cfg : Res<BuildRooms>,
+    ships : Query<(&Ship, &ShipRooms)>
+) {
+    if !cfg.overlay {
+        return;
+    }
+    let Ok((ship, rooms)) = ships.get(block.ship) else {
+        return;
+    };
+    for room in rooms.graph.rooms.iter().filter(|room| !room.exterior) {
+        let (min, max) = room.bounds();
+        let color = if cfg.selected == Some(room.id) {
+            Color::ORANGE
+        } else {
+            Color::TEAL
+        };
+        draw_cells(&mut gizmos, &origin, &ship.map, min, max - min + IVec3::ONE, color);
+    }
+}
@@ -0,0 +1,487 @@
+use bevy::prelude::*;
+use bevy_egui::*;
+use bevy_transform64::SimpleWorldOrigin;
+
+use crate::ship::atmos::ShipAtmos;
+use crate::ship::rooms::ShipRooms;
+use crate::space_voxel::region::RegionVoxelMap;
+
+use super::*;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SelectionMode {
+    #[default]
+    Off,
+    Select,
+    Paste,
+    Move
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionCmd {
+    #[default]
+    None,
+    Copy,
+    Cut,
+    Delete
+}
+
+/// Copied blocks and hull voxels. Indices and translations are relative to the lowest cell of the copied area
+#[derive(Default, Clone)]
+pub struct Prefab {
+    pub blocks : Vec<PlacedBlock>,
+    pub voxels : Vec<(IVec3, ShipBlock)>,
+    pub size : IVec3
+}
+
+impl Prefab {
+    /// Blocks and the voxel cells of the area from `min` to `max`, `max` included
+    pub fn from_area(blocks : &[PlacedBlock], map : &ShipMap, (min, max) : (IVec3, IVec3)) -> Prefab {
+        let size = max - min + IVec3::ONE;
+        let corner = map.get_idx_pos(&min);
+
+        let blocks = blocks.iter().map(|block| {
+            let mut block = block.clone();
+            block.idx -= min;
+            block.transform.translation -= corner;
+            block
+        }).collect();
+        let voxels = map.copy_region(&min, &size).iter()
+            .filter_map(|(idx, val)| match val {
+                VoxelVal::Voxel(block) => Some((idx, *block)),
+                _ => None
+            })
+            .collect();
+
+        Prefab {
+            blocks,
+            voxels,
+            size
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.voxels.is_empty()
+    }
+
+    /// Quarter turn around Y in the same direction as the single block rotation
+    pub fn rotated(&self, voxel_size : f64) -> Prefab {
+        let rot = DQuat::from_rotation_y(PI as f64 / 2.0);
+        let blocks = self.blocks.iter().map(|block| {
+            let mut block = block.clone();
+            block.idx = IVec3::new(block.idx.z, block.idx.y, self.size.x - block.idx.x - block.bbox.x);
+            block.bbox = IVec3::new(block.bbox.z, block.bbox.y, block.bbox.x);
+            block.transform.translation = rot * block.transform.translation
+                + DVec3::new(0.0, 0.0, self.size.x as f64 * voxel_size);
+            block.transform.rotation = rot * block.transform.rotation;
+            block.rotate = block.rotate.turned(DVec3::Y);
+            block
+        }).collect();
+        let voxels = self.voxels.iter()
+            .map(|(idx, block)| (IVec3::new(idx.z, idx.y, self.size.x - idx.x - 1), *block))
+            .collect();
+
+        Prefab {
+            blocks,
+            voxels,
+            size : IVec3::new(self.size.z, self.size.y, self.size.x)
+        }
+    }
+
+    /// The prefab moved with its lowest cell to `idx` after `rot_steps` quarter turns
+    pub fn placed_at(&self, map : &ShipMap, idx : IVec3, rot_steps : i32) -> Prefab {
+        let mut prefab = self.clone();
+        for _ in 0..rot_steps.rem_euclid(4) {
+            prefab = prefab.rotated(map.voxel_size);
+        }
+        let corner = map.get_idx_pos(&idx);
+
+        for block in prefab.blocks.iter_mut() {
+            block.idx += idx;
+            block.transform.translation += corner;
+        }
+        for (cell, _) in prefab.voxels.iter_mut() {
+            *cell += idx;
+        }
+        prefab
+    }
+}
+
+#[derive(Resource, Default)]
+pub struct BuildSelection {
+    pub mode : SelectionMode,
+    pub cmd : SelectionCmd,
+    /// Cell under the cursor on the current build level
+    pub hover : Option<IVec3>,
+    pub drag_start : Option<IVec3>,
+    /// Inclusive min and max selected cells
+    pub area : Option<(IVec3, IVec3)>,
+    pub clipboard : Prefab,
+    pub paste_rot : i32
+}
+
+pub type SelectableQuery<'w, 's> = Query<'w, 's, (Entity, &'static Parent, &'static VoxelInstance, &'static DTransform, &'static InstanceRotate, &'static InstanceGridPos), Without<ActiveBlock>>;
+
+fn intersects(idx : IVec3, bbox : IVec3, min : IVec3, max : IVec3) -> bool {
+    let to = idx + bbox - IVec3::ONE;
+    idx.cmple(max).all() && to.cmpge(min).all()
+}
+
+pub fn selected_blocks(
+    selectable : &SelectableQuery,
+    all_instances : &AllVoxelInstances,
+    ship_e : Entity,
+    (min, max) : (IVec3, IVec3)
+) -> Vec<PlacedBlock> {
+    let mut res = vec![];
+    for (_, parent, inst, tr, rot, grid_pos) in selectable.iter() {
+        if parent.get() != ship_e || !intersects(grid_pos.idx, grid_pos.bbox, min, max) {
+            continue;
+        }
+        if let Some(name) = instance_name(all_instances, inst.common_id) {
+            res.push(PlacedBlock {
+                name,
+                idx : grid_pos.idx,
+                bbox : grid_pos.bbox,
+                transform : *tr,
+                rotate : rot.clone(),
+                state : None
+            });
+        }
+    }
+    res
+}
+
+fn erase_blocks(
+    cmds : &mut Commands,
+    all_instances : &AllVoxelInstances,
+    states : &mut InstanceStates,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery,
+    blocks : &[PlacedBlock]
+) -> Vec<PlacedBlock> {
+    blocks.iter()
+        .filter_map(|block| erase_block(cmds, all_instances, states, ship, instances, &block.idx))
+        .collect()
+}
+
+/// Writes the voxel cells, cells of instances are kept
+fn write_voxels(ship : &mut Ship, voxels : &[(IVec3, ShipBlock)]) -> Vec<VoxelEdit> {
+    voxels.iter()
+        .filter_map(|(idx, block)| set_cell(ship, idx, Some(*block)))
+        .collect()
+}
+
+/// Empties the voxel cells
+fn clear_voxels(ship : &mut Ship, voxels : &[(IVec3, ShipBlock)]) -> Vec<VoxelEdit> {
+    voxels.iter()
+        .filter_map(|(idx, _)| set_cell(ship, idx, None))
+        .collect()
+}
+
+/// Shifts the instances and voxels of the area by `offset` cells as one history step.
+/// Nothing is changed if an instance does not fit at the new place
+fn move_area(
+    cmds : &mut Commands,
+    ship : &mut Ship,
+    instances : &PlacedInstanceQuery,
+    blocks : Vec<IVec3>,
+    area : (IVec3, IVec3),
+    offset : IVec3
+) -> Option<BuildCommand> {
+    let voxels = Prefab::from_area(&[], &ship.map, area).placed_at(&ship.map, area.0, 0).voxels;
+    // the voxels leave first, so instances can move into their cells
+    let cleared = clear_voxels(ship, &voxels);
+    if !move_objects(cmds, ship, instances, &blocks, offset) {
+        for edit in cleared.iter().rev() {
+            set_cell(ship, &edit.idx, edit.from);
+        }
+        return None;
+    }
+    let moved : Vec<(IVec3, ShipBlock)> = voxels.iter().map(|(idx, block)| (*idx + offset, *block)).collect();
+    let written = write_voxels(ship, &moved);
+
+    Some(BuildCommand::Batch(vec![
+        BuildCommand::Sculpt(cleared),
+        BuildCommand::Move { blocks, offset },
+        BuildCommand::Sculpt(written)
+    ]))
+}
+
+pub fn selection_system(
+    mut cmds : Commands,
+    asset_server : Res<AssetServer>,
+    all_instances : Res<AllVoxelInstances>,
+    input : Res<Input<Action>>,
+    buttons : Res<Input<MouseButton>>,
+    block : Res<StationBuildBlock>,
+    mut selection : ResMut<BuildSelection>,
+    mut history : ResMut<BuildHistory>,
+    mut states : ResMut<InstanceStates>,
+    mut ships : Query<&mut Ship>,
+    selectable : SelectableQuery,
+    instances : PlacedInstanceQuery,
+    cameras : Query<(&Camera, &DGlobalTransform)>,
+    windows : Query<&Window, With<PrimaryWindow>>,
+    mut ctx : Query<&mut EguiContext>
+) {
+    let Ok(mut ship) = ships.get_mut(block.ship) else {
+        return;
+    };
+
+    selection.hover = cursor_ray(&cameras, &windows)
+        .and_then(|ray| level_point(&ray, block.mode.level()))
+        .map(|pos| ship.map.get_grid_idx(&pos));
+
+    if input.just_pressed(Action::Build(control::BuildAction::Copy)) {
+        selection.cmd = SelectionCmd::Copy;
+    }
+    if input.just_pressed(Action::Build(control::BuildAction::Cut)) {
+        selection.cmd = SelectionCmd::Cut;
+    }
+    if input.just_pressed(Action::Build(control::BuildAction::Paste)) && !selection.clipboard.is_empty() {
+        selection.mode = SelectionMode::Paste;
+    }
+
+    let cmd = selection.cmd;
+    selection.cmd = SelectionCmd::None;
+    if let Some(area) = selection.area.filter(|_| cmd != SelectionCmd::None) {
+        let blocks = selected_blocks(&selectable, &all_instances, block.ship, area);
+        let prefab = Prefab::from_area(&blocks, &ship.map, area);
+        if cmd == SelectionCmd::Cut || cmd == SelectionCmd::Delete {
+            let erased = erase_blocks(&mut cmds, &all_instances, &mut states, &mut ship, &instances, &blocks);
+            let cleared = clear_voxels(&mut ship, &prefab.placed_at(&ship.map, area.0, 0).voxels);
+            history.push(BuildCommand::Batch(vec![
+                BuildCommand::Erase(erased),
+                BuildCommand::Sculpt(cleared)
+            ]));
+        }
+        if cmd == SelectionCmd::Copy || cmd == SelectionCmd::Cut {
+            selection.clipboard = prefab;
+            selection.paste_rot = 0;
+        }
+    }
+
+    if selection.mode == SelectionMode::Paste
+            && input.just_pressed(Action::Build(control::BuildAction::RotateCounterClockwise)) {
+        selection.paste_rot += 1;
+    }
+
+    if ctx.single_mut().get_mut().is_pointer_over_area() {
+        return;
+    }
+    let Some(hover) = selection.hover else {
+        return;
+    };
+
+    match selection.mode {
+        SelectionMode::Off => {},
+        SelectionMode::Select => {
+            if buttons.just_pressed(MouseButton::Left) {
+                selection.drag_start = Some(hover);
+            }
+            if let Some(start) = selection.drag_start {
+                if buttons.pressed(MouseButton::Left) {
+                    selection.area = Some((start.min(hover), start.max(hover)));
+                } else {
+                    selection.drag_start = None;
+                }
+            }
+        },
+        SelectionMode::Paste => {
+            if buttons.just_pressed(MouseButton::Left) {
+                let prefab = selection.clipboard.placed_at(&ship.map, hover, selection.paste_rot);
+                let placed = prefab.blocks.into_iter()
+                    .filter(|placed| place_block(&mut cmds, &asset_server, &all_instances, block.ship, &mut ship, placed).is_some())
+                    .collect();
+                let written = write_voxels(&mut ship, &prefab.voxels);
+                history.push(BuildCommand::Batch(vec![
+                    BuildCommand::Place(placed),
+                    BuildCommand::Sculpt(written)
+                ]));
+            }
+        },
+        SelectionMode::Move => {
+            let Some((min, max)) = selection.area else {
+                return;
+            };
+            if buttons.just_pressed(MouseButton::Left) {
+                let blocks : Vec<IVec3> = selected_blocks(&selectable, &all_instances, block.ship, (min, max))
+                    .iter()
+                    .map(|block| block.idx)
+                    .collect();
+                let offset = hover - min;
+                if let Some(moved) = move_area(&mut cmds, &mut ship, &instances, blocks, (min, max), offset) {
+                    history.push(moved);
+                    selection.area = Some((min + offset, max + offset));
+                    selection.mode = SelectionMode::Select;
+                }
+            }
+        },
+    }
+}
+
+pub fn draw_cells(
+    gizmos : &mut Gizmos,
+    origin : &SimpleWorldOrigin,
+    map : &ShipMap,
+    idx : IVec3,
+    size : IVec3,
+    color : Color
+) {
+    let from = map.get_idx_pos(&idx);
+    let to = map.get_idx_pos(&(idx + size));
+    let center = (from + to) / 2.0 - origin.origin;
+    let scale = to - from;
+    gizmos.cuboid(
+        Transform::from_translation(center.as_vec3()).with_scale(scale.as_vec3()),
+        color);
+}
+
+pub fn draw_selection(
+    mut gizmos : Gizmos,
+    origin : Res<SimpleWorldOrigin>,
+    block : Res<StationBuildBlock>,
+    selection : Res<BuildSelection>,
+    ships : Query<&Ship>
+) {
+    let Ok(ship) = ships.get(block.ship) else {
+        return;
+    };
+
+    if let Some((min, max)) = selection.area {
+        draw_cells(&mut gizmos, &origin, &ship.map, min, max - min + IVec3::ONE, Color::YELLOW);
+    }
+
+    let Some(hover) = selection.hover else {
+        return;
+    };
+    match selection.mode {
+        SelectionMode::Off => {},
+        SelectionMode::Select => {
+            draw_cells(&mut gizmos, &origin, &ship.map, hover, IVec3::ONE, Color::YELLOW);
+        },
+        SelectionMode::Paste => {
+            let prefab = selection.clipboard.placed_at(&ship.map, hover, selection.paste_rot);
+            draw_cells(&mut gizmos, &origin, &ship.map, hover, prefab.size, Color::YELLOW);
+            for placed in prefab.blocks {
+                let color = if ship.map.can_place_object(&placed.idx, &placed.bbox) {
+                    Color::GREEN
+                } else {
+                    Color::RED
+                };
+                draw_cells(&mut gizmos, &origin, &ship.map, placed.idx, placed.bbox, color);
+            }
+        },
+        SelectionMode::Move => {
+            if let Some((min, max)) = selection.area {
+                draw_cells(&mut gizmos, &origin, &ship.map, hover, max - min + IVec3::ONE, Color::CYAN);
+            }
+        },
+    }
+}
+
+pub fn selection_ui(
+    mut cmds : Commands,
+    mut ctx : Query<&mut EguiContext>,
+    mut block : ResMut<StationBuildBlock>,
+    mut selection : ResMut<BuildSelection>,
+    mut symmetry : ResMut<BuildSymmetry>,
+    mut tools : ResMut<BuildTools>,
+    mut rooms_cfg : ResMut<BuildRooms>,
+    mut ship_rooms : Query<(&mut ShipRooms, Option<&ShipAtmos>)>
+) {
+    let mut ctx = ctx.single_mut();
+    egui::Window::new("Build tools").show(ctx.get_mut(), |ui| {
+        symmetry_ui(ui, &mut symmetry);
+        tools_ui(ui, &mut tools);
+        ui.separator();
+        let (rooms, atmos) = ship_rooms.get_mut(block.ship).ok().unzip();
+        rooms_ui(ui, &mut rooms_cfg, rooms, atmos.flatten());
+        ui.separator();
+
+        let prev_mode = selection.mode;
+        ui.horizontal(|ui| {
+            ui.selectable_value(&mut selection.mode, SelectionMode::Off, "Build");
+            ui.selectable_value(&mut selection.mode, SelectionMode::Select, "Select");
+            if !selection.clipboard.is_empty() {
+                ui.selectable_value(&mut selection.mode, SelectionMode::Paste, "Paste");
+            }
+            if selection.area.is_some() {
+                ui.selectable_value(&mut selection.mode, SelectionMode::Move, "Move");
+            }
+        });
+
+        // selection modes use the mouse, so the block in hand is dropped
+        if selection.mode != prev_mode && selection.mode != SelectionMode::Off {
+            if let Some(e) = block.e.take() {
+                cmds.entity(e).despawn_recursive();
+            }
+        }
+
+        if let Some((min, max)) = selection.area {
+            let size = max - min + IVec3::ONE;
+            ui.label(format!("Selected {}x{}x{} cells", size.x, size.y, size.z));
+            ui.horizontal(|ui| {
+                if ui.button("Copy").clicked() {
+                    selection.cmd = SelectionCmd::Copy;
+                }
+                if ui.button("Cut").clicked() {
+                    selection.cmd = SelectionCmd::Cut;
+                }
+                if ui.button("Delete").clicked() {
+                    selection.cmd = SelectionCmd::Delete;
+                }
+                if ui.button("Deselect").clicked() {
+                    selection.area = None;
+                    if selection.mode == SelectionMode::Move {
+                        selection.mode = SelectionMode::Select;
+                    }
+                }
+            });
+        }
+
+        if !selection.clipboard.is_empty() {
+            ui.label(format!("Clipboard: {} blocks, {} voxels", selection.clipboard.blocks.len(), selection.clipboard.voxels.len()));
+            if selection.mode == SelectionMode::Paste {
+                ui.label(format!("Paste rotation: {} deg", selection.paste_rot.rem_euclid(4) * 90));
+                if ui.button("Rotate").clicked() {
+                    selection.paste_rot += 1;
+                }
+            }
+        }
+    });
+}
+
+/// Picking a block from the build menu returns to the single block mode
+pub fn leave_selection_on_pick(
+    block : Res<StationBuildBlock>,
+    mut selection : ResMut<BuildSelection>
+) {
+    if block.e.is_some() && selection.mode != SelectionMode::Off {
+        selection.mode = SelectionMode::Off;
+        selection.drag_start = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefab_carries_hull_voxels() {
+        let mut ship = Ship::new();
+        ship.map.set_voxel_by_idx(&IVec3::new(-3, 0, 2), VoxelVal::Voxel(ShipBlock::Armor));
+        ship.map.set_voxel_by_idx(&IVec3::new(-2, 0, 2), VoxelVal::Voxel(ShipBlock::Glass));
+        ship.map.set_voxel_by_idx(&IVec3::new(5, 0, 5), VoxelVal::Voxel(ShipBlock::Glass));
+
+        let prefab = Prefab::from_area(&[], &ship.map, (IVec3::new(-3, 0, 1), IVec3::new(-1, 0, 2)));
+        assert_eq!(prefab.size, IVec3::new(3, 1, 2));
+        assert_eq!(prefab.voxels, vec![(IVec3::new(0, 0, 1), ShipBlock::Armor), (IVec3::new(1, 0, 1), ShipBlock::Glass)]);
+
+        // a quarter turn sends +x to -z, the box keeps its lowest cell
+        let placed = prefab.placed_at(&ship.map, IVec3::new(10, 0, 10), 1);
+        assert_eq!(placed.size, IVec3::new(2, 1, 3));
+        assert_eq!(placed.voxels, vec![(IVec3::new(11, 0, 12), ShipBlock::Armor), (IVec3::new(11, 0, 11), ShipBlock::Glass)]);
+    }
+}
@@ -0,0 +1,149 @@
+use bevy::prelude::*;
+use bevy_egui::*;
+use bevy_transform64::SimpleWorldOrigin;
+
+
+use super::*;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymmetryMode {
+    #[default]
+    None,
+    X,
+    Z,
+    XZ
+}
+
+#[derive(Resource)]
+pub struct BuildSymmetry {
+    pub mode : SymmetryMode,
+    /// Grid lines the mirror planes go through. Cell `i` is mirrored to `2 * plane - 1 - i`
+    pub plane : IVec3
+}
+
+impl Default for BuildSymmetry {
+    fn default() -> Self {
+        Self {
+            mode : SymmetryMode::None,
+            // center of the default 100^3 ship
+            plane : IVec3::new(50, 50, 50)
+        }
+    }
+}
+
+impl BuildSymmetry {
+    fn mirror_x(&self) -> bool {
+        self.mode == SymmetryMode::X || self.mode == SymmetryMode::XZ
+    }
+
+    fn mirror_z(&self) -> bool {
+        self.mode == SymmetryMode::Z || self.mode == SymmetryMode::XZ
+    }
+
+    fn block_across_x(&self, block : &PlacedBlock, map : &ShipMap) -> PlacedBlock {
+        let plane_x = map.get_idx_pos(&self.plane).x;
+        let q = block.transform.rotation;
+        let mut res = block.clone();
+        res.idx.x = 2 * self.plane.x - block.idx.x - block.bbox.x;
+        res.transform.translation.x = 2.0 * plane_x - block.transform.translation.x;
+        res.transform.rotation = DQuat::from_xyzw(q.x, -q.y, -q.z, q.w);
+        res.rotate = block.rotate.mirrored_x();
+        res
+    }
+
+    fn block_across_z(&self, block : &PlacedBlock, map : &ShipMap) -> PlacedBlock {
+        let plane_z = map.get_idx_pos(&self.plane).z;
+        let q = block.transform.rotation;
+        let mut res = block.clone();
+        res.idx.z = 2 * self.plane.z - block.idx.z - block.bbox.z;
+        res.transform.translation.z = 2.0 * plane_z - block.transform.translation.z;
+        res.transform.rotation = DQuat::from_xyzw(-q.x, -q.y, q.z, q.w);
+        res.rotate = block.rotate.mirrored_z();
+        res
+    }
+
+    /// Mirror images of a block, the block itself is not included
+    pub fn mirror_block(&self, block : &PlacedBlock, map : &ShipMap) -> Vec<PlacedBlock> {
+        let mut res = vec![];
+        if self.mirror_x() {
+            res.push(self.block_across_x(block, map));
+        }
+        if self.mirror_z() {
+            res.push(self.block_across_z(block, map));
+        }
+        if self.mirror_x() && self.mirror_z() {
+            let across_x = self.block_across_x(block, map);
+            res.push(self.block_across_z(&across_x, map));
+        }
+        // a block lying on the plane is its own image
+        let mut unique : Vec<PlacedBlock> = vec![];
+        for image in res {
+            if image.idx != block.idx && unique.iter().all(|other| other.idx != image.idx) {
+                unique.push(image);
+            }
+        }
+        unique
+    }
+
+    /// `idx` together with its mirror images
+    pub fn with_mirrored_cells(&self, idx : IVec3) -> Vec<IVec3> {
+        let across_x = IVec3::new(2 * self.plane.x - 1 - idx.x, idx.y, idx.z);
+        let across_z = IVec3::new(idx.x, idx.y, 2 * self.plane.z - 1 - idx.z);
+        let mut res = vec![idx];
+        if self.mirror_x() {
+            res.push(across_x);
+        }
+        if self.mirror_z() {
+            res.push(across_z);
+        }
+        if self.mirror_x() && self.mirror_z() {
+            res.push(IVec3::new(across_x.x, idx.y, across_z.z));
+        }
+        res
+    }
+}
+
+pub fn symmetry_ui(ui : &mut egui::Ui, symmetry : &mut BuildSymmetry) {
+    ui.horizontal(|ui| {
+        ui.label("Symmetry:");
+        ui.selectable_value(&mut symmetry.mode, SymmetryMode::None, "None");
+        ui.selectable_value(&mut symmetry.mode, SymmetryMode::X, "X");
+        ui.selectable_value(&mut symmetry.mode, SymmetryMode::Z, "Z");
+        ui.selectable_value(&mut symmetry.mode, SymmetryMode::XZ, "XZ");
+    });
+    if symmetry.mirror_x() {
+        ui.add(egui::DragValue::new(&mut symmetry.plane.x).prefix("Mirror plane x:"));
+    }
+    if symmetry.mirror_z() {
+        ui.add(egui::DragValue::new(&mut symmetry.plane.z).prefix("Mirror plane z:"));
+    }
+}
+
+pub fn draw_symmetry(
+    mut gizmos : Gizmos,
+    origin : Res<SimpleWorldOrigin>,
+    block : Res<StationBuildBlock>,
+    symmetry : Res<BuildSymmetry>,
+    ships : Query<&Ship>
+) {
+    let Ok(ship) = ships.get(block.ship) else {
+        return;
+    };
+    let lvl = block.mode.level();
+
+    // the lines cover the built part of the ship and at least the old 100³ build area
+    let (min, max) = ship.map.chunk_bounds()
+        .map(|(min, max)| (min.min(IVec3::ZERO), max.max(IVec3::splat(100))))
+        .unwrap_or((IVec3::ZERO, IVec3::splat(100)));
+    let from = ship.map.get_idx_pos(&min);
+    let to = ship.map.get_idx_pos(&max);
+    let plane = ship.map.get_idx_pos(&symmetry.plane);
+    let point = |x : f64, z : f64| (DVec3::new(x, lvl, z) - origin.origin).as_vec3();
+
+    if symmetry.mirror_x() {
+        gizmos.line(point(plane.x, from.z), point(plane.x, to.z), Color::ORANGE);
+    }
+    if symmetry.mirror_z() {
+        gizmos.line(point(from.x, plane.z), point(to.x, plane.z), Color::ORANGE);
+    }
+}
@@ -0,0 +1,289 @@
+use bevy::prelude::*;
+use bevy_egui::*;
+use bevy_transform64::SimpleWorldOrigin;
+
+use super::*;
+
+/// Blocks along one axis of a drag, longer drags are cut so the preview stays small
+pub const MAX_TOOL_SPAN : i32 = 128;
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BuildTool {
+    #[default]
+    Single,
+    Line,
+    Rect,
+    HollowBox,
+    /// Adds and removes raw voxel cells
+    Sculpt
+}
+
+impl BuildTool {
+    /// Lowest cells of the blocks tiled from `from` to `to` with `step` sized blocks.
+    /// At most [`MAX_TOOL_SPAN`] blocks are laid along every axis
+    pub fn footprints(&self, from : IVec3, to : IVec3, step : IVec3, height : i32) -> Vec<IVec3> {
+        let step = step.max(IVec3::ONE);
+        let height = height.clamp(1, MAX_TOOL_SPAN);
+        let dir = (to - from).signum();
+        let nx = ((to.x - from.x).abs() / step.x + 1).min(MAX_TOOL_SPAN);
+        let nz = ((to.z - from.z).abs() / step.z + 1).min(MAX_TOOL_SPAN);
+        let cell = |i : i32, y : i32, j : i32| from + IVec3::new(dir.x * i * step.x, y * step.y, dir.z * j * step.z);
+
+        let mut res = vec![];
+        match self {
+            BuildTool::Single | BuildTool::Sculpt => {
+                res.push(from);
+            },
+            BuildTool::Line => {
+                if nx >= nz {
+                    res.extend((0..nx).map(|i| cell(i, 0, 0)));
+                } else {
+                    res.extend((0..nz).map(|j| cell(0, 0, j)));
+                }
+            },
+            BuildTool::Rect => {
+                for j in 0..nz {
+                    for i in 0..nx {
+                        res.push(cell(i, 0, j));
+                    }
+                }
+            },
+            BuildTool::HollowBox => {
+                for y in 0..height {
+                    for j in 0..nz {
+                        for i in 0..nx {
+                            if i == 0 || j == 0 || i == nx - 1 || j == nz - 1 {
+                                res.push(cell(i, y, j));
+                            }
+                        }
+                    }
+                }
+            },
+        }
+        res
+    }
+}
+
+#[derive(Resource)]
+pub struct BuildTools {
+    pub tool : BuildTool,
+    /// Layers of blocks in the hollow box walls
+    pub wall_height : i32,
+    pub drag_start : Option<IVec3>,
+    pub preview : Vec<PlacedBlock>,
+    /// Preview placements rejected by `can_place_object`
+    pub blocked : usize,
+    /// Material of the sculpted cells
+    pub sculpt_block : ShipBlock
+}
+
+impl Default for BuildTools {
+    fn default() -> Self {
+        Self {
+            tool : BuildTool::Single,
+            wall_height : 1,
+            drag_start : None,
+            preview : vec![],
+            blocked : 0,
+            sculpt_block : ShipBlock::HullSteel
+        }
+    }
+}
+
+pub fn tools_ui(ui : &mut egui::Ui, tools : &mut BuildTools) {
+    ui.horizontal(|ui| {
+        ui.label("Tool:");
+        ui.selectable_value(&mut tools.tool, BuildTool::Single, "Single");
+        ui.selectable_value(&mut tools.tool, BuildTool::Line, "Line");
+        ui.selectable_value(&mut tools.tool, BuildTool::Rect, "Rect");
+        ui.selectable_value(&mut tools.tool, BuildTool::HollowBox, "Hollow box");
+        ui.selectable_value(&mut tools.tool, BuildTool::Sculpt, "Sculpt");
+    });
+    if tools.tool == BuildTool::HollowBox {
+        ui.add(egui::DragValue::new(&mut tools.wall_height)
+            .prefix("Wall height:")
+            .clamp_range(1..=64));
+    }
+    if tools.tool == BuildTool::Sculpt {
+        ui.horizontal(|ui| {
+            ui.label("Material:");
+            for block in ShipBlock::ALL {
+                ui.selectable_value(&mut tools.sculpt_block, block, block.name());
+            }
+        });
+    }
+    if !tools.preview.is_empty() {
+        ui.label(format!("{} blocks, {} blocked", tools.preview.len(), tools.blocked));
+    }
+}
+
+pub fn build_tool_system(
+    mut cmds : Commands,
+    asset_server : Res<AssetServer>,
+    all_instances : Res<AllVoxelInstances>,
+    buttons : Res<Input<MouseButton>>,
+    block : Res<StationBuildBlock>,
+    active_blocks : Query<(&DTransform, &InstanceRotate), With<ActiveBlock>>,
+    mut ships : Query<&mut Ship>,
+    mut tools : ResMut<BuildTools>,
+    symmetry : Res<BuildSymmetry>,
+    mut history : ResMut<BuildHistory>,
+    mut ctx : Query<&mut EguiContext>
+) {
+    let active = block.e.and_then(|e| active_blocks.get(e).ok());
+    let Some((tr, rot)) = active.filter(|_| !matches!(tools.tool, BuildTool::Single | BuildTool::Sculpt)) else {
+        tools.drag_start = None;
+        tools.preview.clear();
+        return;
+    };
+    let Ok(mut ship) = ships.get_mut(block.ship) else {
+        return;
+    };
+
+    let cur = active_placement(&block, tr, rot, &ship);
+    if buttons.just_pressed(MouseButton::Left) && !ctx.single_mut().get_mut().is_pointer_over_area() {
+        tools.drag_start = Some(cur.idx);
+    }
+    let Some(start) = tools.drag_start else {
+        tools.preview.clear();
+        return;
+    };
+
+    let mut preview : Vec<PlacedBlock> = vec![];
+    for idx in tools.tool.footprints(start, cur.idx, cur.bbox, tools.wall_height) {
+        let mut placed = cur.clone();
+        placed.idx = idx;
+        placed.transform.translation += (idx - cur.idx).as_dvec3() * ship.map.voxel_size;
+
+        let mirrored = symmetry.mirror_block(&placed, &ship.map);
+        for placed in std::iter::once(placed).chain(mirrored) {
+            if preview.iter().all(|other| other.idx != placed.idx) {
+                preview.push(placed);
+            }
+        }
+    }
+    tools.blocked = preview.iter()
+        .filter(|placed| !ship.map.can_place_object(&placed.idx, &placed.bbox))
+        .count();
+
+    if buttons.pressed(MouseButton::Left) {
+        tools.preview = preview;
+    } else {
+        let placed = preview.into_iter()
+            .filter(|placed| place_block(&mut cmds, &asset_server, &all_instances, block.ship, &mut ship, placed).is_some())
+            .collect();
+        history.push(BuildCommand::Place(placed));
+        tools.drag_start = None;
+        tools.preview.clear();
+    }
+}
+
+/// Left click adds a cell on the hit face or on the build level, right click removes the hit cell
+pub fn sculpt_system(
+    cameras : Query<(&Camera, &DGlobalTransform)>,
+    windows : Query<&Window, With<PrimaryWindow>>,
+    buttons : Res<Input<MouseButton>>,
+    block : Res<StationBuildBlock>,
+    mut ships : Query<&mut Ship>,
+    ship_transforms : Query<&DGlobalTransform, With<Ship>>,
+    tools : Res<BuildTools>,
+    symmetry : Res<BuildSymmetry>,
+    mut history : ResMut<BuildHistory>,
+    mut ctx : Query<&mut EguiContext>
+) {
+    if tools.tool != BuildTool::Sculpt {
+        return;
+    }
+    let add = buttons.just_pressed(MouseButton::Left);
+    let remove = buttons.just_pressed(MouseButton::Right);
+    if !(add || remove) || ctx.single_mut().get_mut().is_pointer_over_area() {
+        return;
+    }
+    let (Ok(mut ship), Ok(ship_tr)) = (ships.get_mut(block.ship), ship_transforms.get(block.ship)) else {
+        return;
+    };
+    let Some(ray) = cursor_ray(&cameras, &windows) else {
+        return;
+    };
+
+    let hit = raycast_map(&ship.map, ship_tr, &ray, 1000.0);
+    let target = if add {
+        match hit {
+            Some(hit) if hit.normal != IVec3::ZERO => hit.idx + hit.normal,
+            Some(_) => return,
+            None => {
+                let Some(pos) = level_point(&ray_to_local(ship_tr, &ray), block.mode.level()) else {
+                    return;
+                };
+                ship.map.get_grid_idx(&pos)
+            }
+        }
+    } else {
+        let Some(hit) = hit else {
+            return;
+        };
+        hit.idx
+    };
+
+    let to = if add { Some(tools.sculpt_block) } else { None };
+    let edits : Vec<VoxelEdit> = symmetry.with_mirrored_cells(target).iter()
+        .filter_map(|idx| set_cell(&mut ship, idx, to))
+        .collect();
+    history.push(BuildCommand::Sculpt(edits));
+}
+
+pub fn draw_tool_preview(
+    mut gizmos : Gizmos,
+    origin : Res<SimpleWorldOrigin>,
+    block : Res<StationBuildBlock>,
+    tools : Res<BuildTools>,
+    ships : Query<&Ship>
+) {
+    let Ok(ship) = ships.get(block.ship) else {
+        return;
+    };
+    for placed in &tools.preview {
+        let color = if ship.map.can_place_object(&placed.idx, &placed.bbox) {
+            Color::GREEN
+        } else {
+            Color::RED
+        };
+        draw_cells(&mut gizmos, &origin, &ship.map, placed.idx, placed.bbox, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_follows_the_longer_axis() {
+        let cells = BuildTool::Line.footprints(IVec3::ZERO, IVec3::new(-4, 0, 1), IVec3::new(2, 1, 1), 1);
+        assert_eq!(cells, vec![IVec3::ZERO, IVec3::new(-2, 0, 0), IVec3::new(-4, 0, 0)]);
+    }
+
+    #[test]
+    fn rect_and_hollow_box() {
+        let rect = BuildTool::Rect.footprints(IVec3::ZERO, IVec3::new(2, 0, 2), IVec3::ONE, 1);
+        assert_eq!(rect.len(), 9);
+        assert!(rect.contains(&IVec3::new(1, 0, 1)));
+
+        let hollow = BuildTool::HollowBox.footprints(IVec3::ZERO, IVec3::new(2, 0, 2), IVec3::ONE, 2);
+        assert_eq!(hollow.len(), 16);
+        assert!(!hollow.contains(&IVec3::new(1, 0, 1)));
+        assert!(!hollow.contains(&IVec3::new(1, 1, 1)));
+        assert!(hollow.contains(&IVec3::new(2, 1, 0)));
+    }
+
+    #[test]
+    fn zero_step_and_long_drags_are_bounded() {
+        let cells = BuildTool::Rect.footprints(IVec3::ZERO, IVec3::new(3, 0, 0), IVec3::ZERO, 1);
+        assert_eq!(cells.len(), 4);
+
+        let far = IVec3::new(100_000, 0, 100_000);
+        assert_eq!(BuildTool::Line.footprints(IVec3::ZERO, far, IVec3::ONE, 1).len(), MAX_TOOL_SPAN as usize);
+        assert_eq!(BuildTool::Rect.footprints(IVec3::ZERO, far, IVec3::ONE, 1).len(), (MAX_TOOL_SPAN * MAX_TOOL_SPAN) as usize);
+        let hollow = BuildTool::HollowBox.footprints(IVec3::ZERO, far, IVec3::ONE, i32::MAX);
+        assert_eq!(hollow.len(), (4 * (MAX_TOOL_SPAN - 1) * MAX_TOOL_SPAN) as usize);
+    }
+}
@@ -10,7 +10,7 @@ pub struct StationBuilderUI;
 
 #[derive(Resource, Default)]
 pub struct CachedSavedShips {
-    pub paths : Vec<String>
+    pub slots : Vec<SaveSlot>
 }
 
 impl Plugin for StationBuilderUI {
@@ -39,6 +39,8 @@ fn setup_chat(
 #[derive(Resource, Default)]
 pub struct BuildMenuState {
     pub save_name : String,
+    /// Why the typed save name was refused
+    pub save_error : Option<String>,
     pub connect_ip : String,
     pub chat : String,
     pub chat_msg : String
@@ -58,7 +60,9 @@ pub fn ship_build_menu(
     client_op : Option<ResMut<NetworkClient>>,
     network_cmds : EventWriter<ServerNetworkCmd>,
     chat_channel : ResMut<NetworkChat>,
-    input : ResMut<Input<Action>>
+    input : ResMut<Input<Action>>,
+    mut slots_cfg : ResMut<SaveSlotsCfg>,
+    history : Res<BuildHistory>
 ) {
     let mut ctx = ctx.single_mut();
     egui::SidePanel::left("Build panel").show(ctx.get_mut(), |ui| {
@@ -71,6 +75,14 @@ pub fn ship_build_menu(
         if ui.button("Clear level").clicked() {
             block.cmd = StationBuildCmds::ClearAll;
         }
+        ui.horizontal(|ui| {
+            if ui.add_enabled(!history.undo.is_empty(), egui::Button::new("Undo")).clicked() {
+                block.cmd = StationBuildCmds::Undo;
+            }
+            if ui.add_enabled(!history.redo.is_empty(), egui::Button::new("Redo")).clicked() {
+                block.cmd = StationBuildCmds::Redo;
+            }
+        });
         ui.separator();
         if ui.button("Quick load").clicked() {
             block.cmd = StationBuildCmds::QuickLoad;
@@ -82,31 +94,56 @@ pub fn ship_build_menu(
 
         if ui.button("Load from file").clicked() {
             active_windows.load_ship = !active_windows.load_ship;
+            cahed_saved_paths.slots = list_slots();
+        }
 
-            let mut paths = vec![];
-            for entry in std::fs::read_dir("saves").unwrap() {
-                if let Ok(file) = entry {
-                    if let Ok(file_tp) = file.file_type() {
-                        if file_tp.is_file() && file.path().to_str().unwrap().contains("scn.ron") {
-                            paths.push(file.path().to_str().unwrap().to_string());
-                        }
+        ui.add(egui::TextEdit::singleline(&mut state.save_name));
+        if ui.button("Save by name").clicked() {
+            match named_save_path(&state.save_name) {
+                Ok(path) => {
+                    state.save_error = None;
+                    if std::path::Path::new(&path).exists() {
+                        active_windows.confirm_overwrite = Some(path);
+                    } else {
+                        cmd_save.send(CmdShipSave(block.ship, path));
                     }
-                }
+                },
+                Err(err) => state.save_error = Some(err)
             }
-
-            cahed_saved_paths.paths = paths;
+        }
+        if let Some(err) = &state.save_error {
+            ui.colored_label(egui::Color32::RED, err);
         }
 
-        ui.add(egui::TextEdit::singleline(&mut state.save_name));
-        if ui.button("Save by name").clicked() {
-            cmd_save.send(CmdShipSave(block.ship, format!("saves/{}.scn.ron", state.save_name)));
+        ui.checkbox(&mut slots_cfg.autosave_enabled, "Autosave");
+        if slots_cfg.autosave_enabled {
+            ui.add(egui::DragValue::new(&mut slots_cfg.autosave_minutes)
+                .prefix("Autosave every (min):")
+                .speed(0.1)
+                .clamp_range(0.5..=120.0)
+                .fixed_decimals(1));
+            ui.add(egui::DragValue::new(&mut slots_cfg.autosave_keep)
+                .prefix("Keep autosaves:")
+                .clamp_range(1..=50));
         }
+        ui.add(egui::DragValue::new(&mut slots_cfg.backups)
+            .prefix("Keep backups:")
+            .clamp_range(0..=50));
 
         ui.separator();
 
         let step = 0.25;
+        let mut snap = matches!(block.mode, BuildMode::Raycast(_));
+        if ui.checkbox(&mut snap, "Snap to surfaces").changed() {
+            let lvl = block.mode.level();
+            block.mode = if snap {
+                BuildMode::Raycast(lvl)
+            } else {
+                BuildMode::SingleOnY(lvl)
+            };
+        }
         match &mut block.mode {
-            BuildMode::SingleOnY(lvl) => {
+            BuildMode::SingleOnY(lvl) | BuildMode::Raycast(lvl) => {
                 if input.just_pressed(Action::Build(control::BuildAction::LevelDown)) {
                     *lvl -= step;
                 }
@@ -216,12 +253,32 @@ pub fn load_ship_ui(
             .show(ctx.get_mut(), |ui| {
 
             ui.label("Ships:");
-            for path in &saved_ships.paths {
-                if ui.button(path).clicked() {
-                    load_ship_cmd.send(CmdShipLoad(path.clone()));
-                    active_windows.load_ship = false;
-                }
-            }
+            egui::ScrollArea::vertical().max_height(400.0).show(ui, |ui| {
+                egui::Grid::new("saved ships grid").striped(true).show(ui, |ui| {
+                    for slot in &saved_ships.slots {
+                        if ui.button(slot_stem(&slot.path)).clicked() {
+                            load_ship_cmd.send(CmdShipLoad(slot.path.clone()));
+                            active_windows.load_ship = false;
+                        }
+                        if let Some(meta) = &slot.meta {
+                            ui.label(format!("{:?}", meta.kind));
+                            ui.label(&meta.ship_name);
+                            ui.label(format!("{} blocks", meta.block_count));
+                            ui.label(format_age(meta.created));
+                        } else {
+                            ui.label(format!("{:?}", slot_kind(&slot.path)));
+                            ui.label("-");
+                            ui.label("-");
+                            ui.label("-");
+                        }
+                        ui.label(&slot.path).on_hover_text(
+                            slot.meta.as_ref()
+                                .and_then(|m| m.thumbnail.clone())
+                                .unwrap_or_default());
+                        ui.end_row();
+                    }
+                });
+            });
 
             ui.separator();
 
@@ -229,4 +286,29 @@ pub fn load_ship_ui(
                 active_windows.load_ship = false;
             }
         });
-}
\ No newline at end of file
+}
+
+pub fn confirm_overwrite_ui(
+    mut ctx : Query<&mut EguiContext>,
+    mut active_windows : ResMut<ActiveWindows>,
+    block : Res<StationBuildBlock>,
+    mut cmd_save : EventWriter<CmdShipSave>) {
+        let Some(path) = active_windows.confirm_overwrite.clone() else {
+            return;
+        };
+        let mut ctx = ctx.single_mut();
+        egui::Window::new("Overwrite save?")
+            .collapsible(false)
+            .show(ctx.get_mut(), |ui| {
+            ui.label(format!("{} already exists. The previous version will be moved to {}", &path, BACKUP_DIR));
+            ui.horizontal(|ui| {
+                if ui.button("Overwrite").clicked() {
+                    cmd_save.send(CmdShipSave(block.ship, path.clone()));
+                    active_windows.confirm_overwrite = None;
+                }
+                if ui.button("Cancel").clicked() {
+                    active_windows.confirm_overwrite = None;
+                }
+            });
+        });
+}
@@ -0,0 +1,336 @@
+use bevy::prelude::*;
+use bevy_proto::prelude::{Schematic, ReflectSchematic};
+
+use super::InstanceGridPos;
+use crate::space_voxel::objected_voxel_map::Footprint;
+use super::rooms::{RoomGraph, ShipRooms};
+
+const GAS_CONSTANT : f64 = 8.314;
+const STANDARD_TEMPERATURE : f64 = 293.15;
+const STANDARD_PRESSURE : f64 = 101.325;
+/// mol/s moved through one m² of opening per kPa of pressure difference
+const CONDUCTANCE : f64 = 50.0;
+/// Share of the gas lost to space each second by volumes open to the outside
+const SPACE_LEAK_RATE : f64 = 0.5;
+/// J/(mol K), air is close to an ideal diatomic gas
+const MOLAR_HEAT_CAPACITY : f64 = 2.5 * GAS_CONSTANT;
+/// W lost through the hull per K of difference with space and per m³ of room
+const HULL_HEAT_LOSS : f64 = 0.05;
+const SPACE_TEMPERATURE : f64 = 3.0;
+
+/// Marks a voxel instance template which gas can't pass, like plates, walls and windows
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct Airtight;
+
+/// Puts heat into the room the instance stands in, negative `power` cools it
+#[derive(Component, Reflect, Default, Schematic, Clone)]
+#[reflect(Component, Schematic)]
+pub struct Heater {
+    /// W
+    pub power : f64
+}
+
+/// Amount of every gas in mol
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct GasMix {
+    pub oxygen : f64,
+    pub nitrogen : f64,
+    pub carbon_dioxide : f64
+}
+
+impl GasMix {
+    pub fn air(volume : f64, temperature : f64) -> GasMix {
+        let total = STANDARD_PRESSURE * 1000.0 * volume / (GAS_CONSTANT * temperature);
+        GasMix {
+            oxygen : total * 0.21,
+            nitrogen : total * 0.79,
+            carbon_dioxide : 0.0
+        }
+    }
+
+    pub fn total(&self) -> f64 {
+        self.oxygen + self.nitrogen + self.carbon_dioxide
+    }
+
+    pub fn scaled(&self, k : f64) -> GasMix {
+        GasMix {
+            oxygen : self.oxygen * k,
+            nitrogen : self.nitrogen * k,
+            carbon_dioxide : self.carbon_dioxide * k
+        }
+    }
+
+    pub fn add(&mut self, other : &GasMix) {
+        self.oxygen += other.oxygen;
+        self.nitrogen += other.nitrogen;
+        self.carbon_dioxide += other.carbon_dioxide;
+    }
+}
+
+/// Gas of a room of the [`RoomGraph`] with the same index
+#[derive(Clone, Debug)]
+pub struct AtmosRoom {
+    /// m³
+    pub volume : f64,
+    pub exterior : bool,
+    pub gas : GasMix,
+    /// K
+    pub temperature : f64
+}
+
+impl AtmosRoom {
+    /// kPa
+    pub fn pressure(&self) -> f64 {
+        if self.volume <= 0.0 {
+            0.0
+        } else {
+            self.gas.total() * GAS_CONSTANT * self.temperature / self.volume / 1000.0
+        }
+    }
+
+    /// Moves `amount` mol of this room mix into `other`, mixing the temperatures
+    fn transfer(&mut self, other : &mut AtmosRoom, amount : f64) {
+        let total = self.gas.total();
+        if amount <= 0.0 || total <= 0.0 {
+            return;
+        }
+        let moved = self.gas.scaled((amount / total).min(1.0));
+        self.gas = self.gas.scaled(1.0 - (amount / total).min(1.0));
+
+        let other_total = other.gas.total();
+        other.temperature = (other.temperature * other_total + self.temperature * moved.total())
+            / (other_total + moved.total());
+        other.gas.add(&moved);
+    }
+}
+
+/// Door between two rooms
+#[derive(Clone, Debug)]
+pub struct AtmosOpening {
+    pub door : Entity,
+    pub rooms : (usize, usize),
+    pub open : bool,
+    /// m²
+    pub area : f64
+}
+
+#[derive(Component, Clone, Default)]
+pub struct ShipAtmos {
+    pub rooms : Vec<AtmosRoom>,
+    pub openings : Vec<AtmosOpening>,
+    /// W put into every room by heaters
+    pub heat_input : Vec<f64>,
+    /// Room graph generation the rooms are aligned with
+    generation : Option<u32>
+}
+
+impl ShipAtmos {
+    /// Follows a rebuilt room graph. The gas of the old rooms is shared by the cells they left,
+    /// newly opened cells are empty. The first build fills closed rooms with air
+    pub fn rebuild(&mut self, graph : &RoomGraph) {
+        let first_build = self.generation.is_none();
+        let old = std::mem::take(&mut self.rooms);
+        self.generation = Some(graph.generation);
+        self.rooms = graph.rooms.iter()
+            .map(|room| AtmosRoom {
+                volume : room.volume(graph.voxel_size),
+                exterior : room.exterior,
+                gas : GasMix::default(),
+                temperature : STANDARD_TEMPERATURE
+            })
+            .collect();
+        self.heat_input = vec![0.0; self.rooms.len()];
+
+        let mut heat = vec![0.0; self.rooms.len()];
+        for (old_idx, new_idx, share) in &graph.remap {
+            let Some(old_room) = old.get(*old_idx) else {
+                continue;
+            };
+            let gas = old_room.gas.scaled(*share);
+            self.rooms[*new_idx].gas.add(&gas);
+            heat[*new_idx] += gas.total() * old_room.temperature;
+        }
+        for (room, heat) in self.rooms.iter_mut().zip(heat) {
+            if room.gas.total() > 0.0 {
+                room.temperature = heat / room.gas.total();
+            }
+            if first_build && !room.exterior {
+                room.gas = GasMix::air(room.volume, STANDARD_TEMPERATURE);
+            }
+        }
+
+        self.openings.clear();
+        for door in &graph.doors {
+            // one face of a door slab passes the gas
+            let area = door.cells.len() as f64 * graph.voxel_size * graph.voxel_size;
+            for (i, a) in door.rooms.iter().enumerate() {
+                for b in door.rooms.iter().skip(i + 1) {
+                    self.openings.push(AtmosOpening {
+                        door : door.door,
+                        rooms : (*a, *b),
+                        open : door.open,
+                        area
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn set_door_open(&mut self, door : Entity, open : bool) {
+        for opening in self.openings.iter_mut().filter(|opening| opening.door == door) {
+            opening.open = open;
+        }
+    }
+
+    /// Gas flow through open doors and out of the exterior volumes, heaters and the hull losses
+    pub fn step(&mut self, dt : f64) {
+        for opening in self.openings.iter().filter(|opening| opening.open) {
+            let (a, b) = opening.rooms;
+            let pa = self.rooms[a].pressure();
+            let pb = self.rooms[b].pressure();
+            let (from, to) = if pa >= pb { (a, b) } else { (b, a) };
+
+            // never push more than is needed to level the pressures
+            let (vf, vt) = (self.rooms[from].volume, self.rooms[to].volume);
+            let (nf, nt) = (self.rooms[from].gas.total(), self.rooms[to].gas.total());
+            let level = ((nf * vt - nt * vf) / (vf + vt)).max(0.0);
+            let flow = (CONDUCTANCE * opening.area * (pa - pb).abs() * dt).min(level);
+
+            let (first, second) = self.rooms.split_at_mut(from.max(to));
+            let (src, dst) = if from < to {
+                (&mut first[from], &mut second[0])
+            } else {
+                (&mut second[0], &mut first[to])
+            };
+            src.transfer(dst, flow);
+        }
+
+        let keep = (-SPACE_LEAK_RATE * dt).exp();
+        for room in self.rooms.iter_mut().filter(|room| room.exterior) {
+            room.gas = room.gas.scaled(keep);
+        }
+
+        for (room, input) in self.rooms.iter_mut().zip(&self.heat_input) {
+            let capacity = room.gas.total() * MOLAR_HEAT_CAPACITY;
+            if room.exterior || capacity <= 0.0 {
+                continue;
+            }
+            let loss = HULL_HEAT_LOSS * room.volume * (room.temperature - SPACE_TEMPERATURE);
+            room.temperature = (room.temperature + (input - loss) * dt / capacity).max(SPACE_TEMPERATURE);
+        }
+    }
+}
+
+/// Follows the room graph of the ships and steps the gas flow
+pub fn update_ship_atmos(
+    mut cmds : Commands,
+    time : Res<Time>,
+    mut ships : Query<(Entity, Ref<ShipRooms>, Option<&mut ShipAtmos>, Option<&Children>)>,
+    heaters : Query<(&Heater, &InstanceGridPos)>
+) {
+    for (ship_e, rooms, atmos, children) in ships.iter_mut() {
+        let Some(mut atmos) = atmos else {
+            let mut atmos = ShipAtmos::default();
+            atmos.rebuild(&rooms.graph);
+            cmds.entity(ship_e).insert(atmos);
+            continue;
+        };
+        if atmos.generation != Some(rooms.graph.generation) {
+            atmos.rebuild(&rooms.graph);
+        } else if rooms.is_changed() {
+            for door in &rooms.graph.doors {
+                atmos.set_door_open(door.door, door.open);
+            }
+        }
+
+        atmos.heat_input.iter_mut().for_each(|input| *input = 0.0);
+        for (heater, grid) in children.iter().flat_map(|children| children.iter()).filter_map(|e| heaters.get(*e).ok()) {
+            if let Some(room) = rooms.graph.room_touching(Footprint::new(grid.idx, grid.bbox).cells()) {
+                atmos.heat_input[room] += heater.power;
+            }
+        }
+        atmos.step(time.delta_seconds_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::IVec3;
+
+    use crate::space_voxel::{VoxelMap, objected_voxel_map::VoxelVal};
+    use crate::ship::rooms::tests::{two_rooms, kind};
+
+    use super::*;
+
+    #[test]
+    fn rooms_and_pressure() {
+        let map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+        let mut atmos = ShipAtmos::default();
+        atmos.rebuild(&graph);
+
+        assert_eq!(atmos.rooms.len(), 2);
+        assert_eq!(atmos.openings.len(), 1);
+        for room in &atmos.rooms {
+            assert!((room.pressure() - STANDARD_PRESSURE).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn open_door_levels_pressure() {
+        let map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+        let mut atmos = ShipAtmos::default();
+        atmos.rebuild(&graph);
+        atmos.rooms[1].gas = GasMix::default();
+
+        atmos.step(1.0);
+        assert_eq!(atmos.rooms[1].gas.total(), 0.0);
+
+        atmos.set_door_open(Entity::from_raw(7), true);
+        for _ in 0..100 {
+            atmos.step(0.1);
+        }
+        let (pa, pb) = (atmos.rooms[0].pressure(), atmos.rooms[1].pressure());
+        assert!((pa - pb).abs() < 0.1);
+        assert!((pa - STANDARD_PRESSURE / 2.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn breach_vents_room() {
+        let mut map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+        let mut atmos = ShipAtmos::default();
+        atmos.rebuild(&graph);
+        let before = atmos.rooms[0].gas.total();
+
+        map.set_voxel_by_idx(&IVec3::new(0, 2, 2), VoxelVal::None);
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), Some(&graph));
+        atmos.rebuild(&graph);
+        let breached = atmos.rooms.iter().position(|room| room.exterior).unwrap();
+        assert!((atmos.rooms[breached].gas.total() - before).abs() < 1e-6);
+
+        for _ in 0..100 {
+            atmos.step(0.1);
+        }
+        assert!(atmos.rooms[breached].pressure() < 1.0);
+    }
+
+    #[test]
+    fn heater_warms_and_hull_cools() {
+        let map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+        let mut atmos = ShipAtmos::default();
+        atmos.rebuild(&graph);
+        atmos.heat_input[0] = 2000.0;
+
+        for _ in 0..600 {
+            atmos.step(1.0);
+        }
+        assert!(atmos.rooms[0].temperature > STANDARD_TEMPERATURE + 10.0);
+        assert!(atmos.rooms[1].temperature < STANDARD_TEMPERATURE - 1.0);
+        // closed rooms keep their gas, so the pressure follows the temperature
+        assert!(atmos.rooms[0].pressure() > atmos.rooms[1].pressure());
+    }
+}
@@ -0,0 +1,300 @@
+use bevy::ecs::reflect::ReflectComponent;
+use bevy::reflect::TypeRegistryInternal;
+use bevy::reflect::serde::{TypedReflectDeserializer, TypedReflectSerializer};
+use bevy::{prelude::*, math::DVec3, utils::HashMap};
+use serde::de::DeserializeSeed;
+use serde::{Deserialize, Serialize};
+
+use super::prelude::*;
+
+pub const BLUEPRINT_EXT : &str = ".blueprint.json";
+const BLUEPRINT_VERSION : u32 = 3;
+
+/// Human readable form of a saved ship.
+/// Unlike [`DiskShipBase64`] it lists placed instances one by one, so it can be diffed and edited by hand.
+/// Indices are cells of the ship map, its cell zero has the corner at `first_voxel_pos`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Blueprint {
+    pub version : u32,
+    pub voxel_size : f64,
+    /// Missing before version 3, such files use the default ship layout
+    #[serde(default = "default_first_voxel_pos")]
+    pub first_voxel_pos : DVec3,
+    pub instances : Vec<BlueprintInstance>,
+    pub voxels : Vec<BlueprintVoxel>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlueprintInstance {
+    pub template : String,
+    /// Lowest grid index of the occupied cells
+    pub idx : IVec3,
+    /// Occupied cells, already rotated
+    pub bbox : IVec3,
+    pub rot_steps : IVec3,
+    /// Saved components of the instance keyed by type name
+    #[serde(default)]
+    pub components : serde_json::Map<String, serde_json::Value>
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BlueprintVoxel {
+    pub idx : IVec3,
+    pub block : ShipBlock
+}
+
+#[derive(Debug)]
+pub enum BlueprintError {
+    UnknownTemplate(u32),
+    UnknownState(u32),
+    UnregisteredComponent(String),
+    Component(String, String),
+    /// Made by a newer game
+    Version(u32),
+    Json(serde_json::Error)
+}
+
+fn default_first_voxel_pos() -> DVec3 {
+    SHIP_FIRST_VOXEL_POS
+}
+
+impl std::fmt::Display for BlueprintError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlueprintError::UnknownTemplate(id) => write!(f, "unknown template id {}", id),
+            BlueprintError::UnknownState(id) => write!(f, "unknown state id {}", id),
+            BlueprintError::UnregisteredComponent(name) => write!(f, "component {} is not registered", name),
+            BlueprintError::Component(name, err) => write!(f, "failed to read component {}: {}", name, err),
+            BlueprintError::Version(version) => write!(f, "blueprint version {} is newer than the supported {}", version, BLUEPRINT_VERSION),
+            BlueprintError::Json(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<serde_json::Error> for BlueprintError {
+    fn from(err: serde_json::Error) -> Self {
+        BlueprintError::Json(err)
+    }
+}
+
+impl Blueprint {
+    /// `states` is the world the saved scene was loaded into, it holds the per instance components
+    pub fn from_disk_ship(disk_ship : &DiskShip, states : &World, registry : &TypeRegistryInternal) -> Result<Blueprint, BlueprintError> {
+        let mut instances = vec![];
+        let mut voxels = vec![];
+
+        for (state_id, (template_id, from, to)) in disk_ship.footprints() {
+            let template = disk_ship.template_names.get(&template_id)
+                .ok_or(BlueprintError::UnknownTemplate(template_id))?
+                .clone();
+            let state_e = disk_ship.states.get(&state_id)
+                .ok_or(BlueprintError::UnknownState(state_id))?;
+            let state_e = Entity::from_raw(state_e.index());
+
+            let mut components = serde_json::Map::new();
+            let mut rot_steps = IVec3::ZERO;
+            if let Some(entity) = states.get_entity(state_e) {
+                if let Some(rot) = entity.get::<InstanceRotate>() {
+                    rot_steps = rot.rot_steps;
+                }
+                for component_id in entity.archetype().components() {
+                    let Some(type_id) = states.components().get_info(component_id).and_then(|info| info.type_id()) else {
+                        continue;
+                    };
+                    let Some(registration) = registry.get(type_id) else {
+                        continue;
+                    };
+                    let Some(reflect_component) = registration.data::<ReflectComponent>() else {
+                        continue;
+                    };
+                    let Some(value) = reflect_component.reflect(entity) else {
+                        continue;
+                    };
+                    let value = serde_json::to_value(TypedReflectSerializer::new(value, registry))?;
+                    components.insert(registration.type_name().to_string(), value);
+                }
+            }
+
+            instances.push(BlueprintInstance {
+                template,
+                idx : from,
+                bbox : to - from + IVec3::ONE,
+                rot_steps,
+                components
+            });
+        }
+
+        for (idx, v) in disk_ship.map.iter() {
+            if let DiskShipVoxel::Voxel(block) = v {
+                voxels.push(BlueprintVoxel {
+                    idx,
+                    block : *block
+                });
+            }
+        }
+        voxels.sort_by_key(|voxel| (voxel.idx.z, voxel.idx.y, voxel.idx.x));
+
+        Ok(Blueprint {
+            version : BLUEPRINT_VERSION,
+            voxel_size : disk_ship.map.voxel_size,
+            first_voxel_pos : disk_ship.map.first_voxel_pos,
+            instances,
+            voxels
+        })
+    }
+
+    /// Builds the disk ship back. Per instance components are spawned into `states`
+    pub fn to_disk_ship(&self, states : &mut World, registry : &TypeRegistryInternal) -> Result<DiskShip, BlueprintError> {
+        let mut map = Ship::empty_map::<DiskShipVoxel>();
+        map.voxel_size = self.voxel_size;
+        map.first_voxel_pos = self.first_voxel_pos;
+        let mut template_ids : HashMap<String, u32> = HashMap::new();
+        let mut template_names : HashMap<u32, String> = HashMap::new();
+        let mut state_entities : HashMap<u32, Entity> = HashMap::new();
+
+        for (state_id, inst) in self.instances.iter().enumerate() {
+            let state_id = state_id as u32;
+            let next_id = template_ids.len() as u32;
+            let template_id = *template_ids.entry(inst.template.clone()).or_insert(next_id);
+            template_names.insert(template_id, inst.template.clone());
+
+            let mut entity = states.spawn_empty();
+            for (type_name, value) in &inst.components {
+                let registration = registry.get_with_name(type_name)
+                    .ok_or_else(|| BlueprintError::UnregisteredComponent(type_name.clone()))?;
+                let reflect_component = registration.data::<ReflectComponent>()
+                    .ok_or_else(|| BlueprintError::UnregisteredComponent(type_name.clone()))?;
+                let component = TypedReflectDeserializer::new(registration, registry)
+                    .deserialize(value.clone())
+                    .map_err(|err| BlueprintError::Component(type_name.clone(), err.to_string()))?;
+                reflect_component.insert(&mut entity, &*component);
+            }
+            state_entities.insert(state_id, entity.id());
+
+            for z in 0..inst.bbox.z {
+                for y in 0..inst.bbox.y {
+                    for x in 0..inst.bbox.x {
+                        map.set_voxel_by_idx(
+                            &(inst.idx + IVec3::new(x, y, z)),
+                            DiskShipVoxel::Instance(InstanceId { template_id, state_id }));
+                    }
+                }
+            }
+        }
+
+        for voxel in &self.voxels {
+            map.set_voxel_by_idx(&voxel.idx, DiskShipVoxel::Voxel(voxel.block));
+        }
+
+        Ok(DiskShip {
+            map,
+            template_names,
+            states : state_entities
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String, BlueprintError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(text : &str) -> Result<Blueprint, BlueprintError> {
+        let blueprint : Blueprint = serde_json::from_str(text)?;
+        if blueprint.version > BLUEPRINT_VERSION {
+            return Err(BlueprintError::Version(blueprint.version));
+        }
+        Ok(blueprint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::world::EntityRef;
+    use crate::objects::door::Door;
+    use crate::objects::ship_systems::FuelTank;
+
+    /// Cells with the template name or block of every occupied cell, so differently numbered ids compare equal
+    fn named_cells(disk_ship : &DiskShip) -> Vec<(IVec3, String)> {
+        let mut cells : Vec<(IVec3, String)> = disk_ship.map.iter()
+            .filter_map(|(idx, v)| match v {
+                DiskShipVoxel::None => None,
+                DiskShipVoxel::Voxel(block) => Some((idx, block.name().to_string())),
+                DiskShipVoxel::Instance(id) => Some((idx, disk_ship.template_names[&id.template_id].clone()))
+            })
+            .collect();
+        cells.sort_by_key(|(idx, _)| (idx.z, idx.y, idx.x));
+        cells
+    }
+
+    fn state_at<'a>(disk_ship : &DiskShip, states : &'a World, idx : IVec3) -> EntityRef<'a> {
+        let DiskShipVoxel::Instance(id) = disk_ship.map.get_by_idx(&idx) else {
+            panic!("no instance at {:?}", idx);
+        };
+        states.entity(Entity::from_raw(disk_ship.states[&id.state_id].index()))
+    }
+
+    #[test]
+    fn disk_ship_round_trip() {
+        // the registry of the blueprint tool
+        let type_registry = AppTypeRegistry::default();
+        register_save_types(&type_registry);
+        let registry = type_registry.read();
+
+        let mut door = Door::default();
+        door.locked = true;
+        door.auto_open = true;
+        door.opened_pos = Vec3::Y;
+        let mut states = World::new();
+        let door_state = states.spawn((InstanceRotate { rot_steps : IVec3::new(1, 0, 2) }, door)).id();
+        let seat_state = states.spawn(InstanceRotate::default()).id();
+        let tank_state = states.spawn((InstanceRotate::default(), FuelTank { capacity : 500.0, fuel : 123.5 })).id();
+
+        let mut map = Ship::empty_map::<DiskShipVoxel>();
+        map.first_voxel_pos = DVec3::new(1.5, -3.0, 0.25);
+        for x in 0..2 {
+            for y in 0..3 {
+                map.set_voxel_by_idx(&IVec3::new(x, y, -20), DiskShipVoxel::Instance(InstanceId { template_id : 7, state_id : 0 }));
+            }
+        }
+        map.set_voxel_by_idx(&IVec3::new(30, 0, 0), DiskShipVoxel::Instance(InstanceId { template_id : 3, state_id : 1 }));
+        map.set_voxel_by_idx(&IVec3::new(0, -8, 4), DiskShipVoxel::Instance(InstanceId { template_id : 5, state_id : 2 }));
+        map.set_voxel_by_idx(&IVec3::new(-5, 2, 1), DiskShipVoxel::Voxel(ShipBlock::Glass));
+        map.set_voxel_by_idx(&IVec3::new(-5, 3, 1), DiskShipVoxel::Voxel(ShipBlock::Armor));
+        let disk_ship = DiskShip {
+            map,
+            template_names : HashMap::from_iter([(7, "Door".to_string()), (3, "Pilot seat".to_string()), (5, "Fuel tank".to_string())]),
+            states : HashMap::from_iter([(0, door_state), (1, seat_state), (2, tank_state)])
+        };
+
+        let blueprint = Blueprint::from_disk_ship(&disk_ship, &states, &registry).unwrap();
+        let blueprint = Blueprint::from_json(&blueprint.to_json().unwrap()).unwrap();
+        let mut loaded_states = World::new();
+        let loaded = blueprint.to_disk_ship(&mut loaded_states, &registry).unwrap();
+
+        assert_eq!(named_cells(&loaded), named_cells(&disk_ship));
+        assert_eq!(loaded.map.voxel_size, disk_ship.map.voxel_size);
+        assert_eq!(loaded.map.first_voxel_pos, disk_ship.map.first_voxel_pos);
+        assert_eq!(loaded.block_count(), disk_ship.block_count());
+
+        let door = state_at(&loaded, &loaded_states, IVec3::new(1, 2, -20));
+        assert_eq!(door.get::<InstanceRotate>().unwrap().rot_steps, IVec3::new(1, 0, 2));
+        let door = door.get::<Door>().unwrap();
+        assert!(door.locked && door.auto_open && !door.is_open);
+        assert_eq!(door.opened_pos, Vec3::Y);
+        let seat = state_at(&loaded, &loaded_states, IVec3::new(30, 0, 0));
+        assert!(seat.get::<Door>().is_none());
+        let tank = state_at(&loaded, &loaded_states, IVec3::new(0, -8, 4));
+        let tank = tank.get::<FuelTank>().unwrap();
+        assert_eq!((tank.capacity, tank.fuel), (500.0, 123.5));
+    }
+
+    #[test]
+    fn newer_blueprints_are_rejected() {
+        let mut json : serde_json::Value = serde_json::from_str(r#"{"version": 2, "voxel_size": 0.25, "instances": [], "voxels": []}"#).unwrap();
+        let old = Blueprint::from_json(&json.to_string()).unwrap();
+        assert_eq!(old.first_voxel_pos, SHIP_FIRST_VOXEL_POS);
+
+        json["version"] = (BLUEPRINT_VERSION + 1).into();
+        assert!(matches!(Blueprint::from_json(&json.to_string()), Err(BlueprintError::Version(_))));
+    }
+}
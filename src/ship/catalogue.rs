@@ -0,0 +1,141 @@
+use std::fs;
+
+use bevy::ecs::reflect::ReflectComponent;
+use bevy::reflect::{FromReflect, ReflectRef};
+use bevy::prelude::*;
+use bevy_proto::prelude::{Prototype, Prototypical, PrototypesMut};
+
+/// Asset folder with the prototypes of voxel instances
+pub const PROTO_DIR : &str = "ship/proto";
+const PROTO_EXT : &str = ".prototype.ron";
+const SCENE_BUNDLE : &str = "bevy_proto::custom::SceneBundle";
+
+/// Prototype reduced to what is needed to spawn a voxel instance
+pub struct ProtoNode {
+    pub name : String,
+    pub scene : Option<String>,
+    /// Schematic inputs, the ones which are components are inserted on spawn
+    pub schematics : Vec<Box<dyn Reflect>>,
+    pub children : Vec<ProtoNode>
+}
+
+impl ProtoNode {
+    pub fn schematic<T : FromReflect>(&self) -> Option<T> {
+        self.schematics.iter()
+            .find(|schematic| schematic.type_name() == std::any::type_name::<T>())
+            .and_then(|schematic| T::from_reflect(schematic.as_ref()))
+    }
+
+    /// Node of a loaded prototype. Children are loaded by bevy_proto as separate assets,
+    /// `None` is returned while any of them is not ready yet
+    pub fn from_proto(proto : &Prototype, assets : &Assets<Prototype>) -> Option<ProtoNode> {
+        let mut node = ProtoNode {
+            name : proto.id().to_string(),
+            scene : None,
+            schematics : vec![],
+            children : vec![]
+        };
+        for (type_name, schematic) in proto.schematics().iter() {
+            let type_name : &str = type_name;
+            if type_name == SCENE_BUNDLE {
+                node.scene = scene_path(schematic.input());
+            } else {
+                node.schematics.push(schematic.input().clone_value());
+            }
+        }
+        if let Some(children) = proto.children() {
+            for child in children.iter() {
+                node.children.push(ProtoNode::from_proto(assets.get(child.handle())?, assets)?);
+            }
+        }
+        Some(node)
+    }
+}
+
+/// Asset path of a `SceneBundle` schematic input, `scene : AssetPath("..")`
+fn scene_path(input : &dyn Reflect) -> Option<String> {
+    let ReflectRef::Struct(bundle) = input.reflect_ref() else {
+        return None;
+    };
+    let ReflectRef::Enum(scene) = bundle.field("scene")?.reflect_ref() else {
+        return None;
+    };
+    if scene.variant_name() != "AssetPath" {
+        return None;
+    }
+    scene.field_at(0)?.downcast_ref::<String>().cloned()
+}
+
+/// Handles of the prototypes in [`PROTO_DIR`], kept so bevy_proto does not unload them
+#[derive(Resource, Default)]
+pub struct ShipProtos {
+    pub handles : Vec<Handle<Prototype>>
+}
+
+fn proto_paths() -> Vec<String> {
+    let dir = format!("assets/{}", PROTO_DIR);
+    let Ok(entries) = fs::read_dir(&dir) else {
+        warn!("Prototype dir {} not found", dir);
+        return vec![];
+    };
+    let mut paths : Vec<String> = entries.flatten()
+        .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_string()))
+        .filter(|name| name.ends_with(PROTO_EXT))
+        .map(|name| format!("{}/{}", PROTO_DIR, name))
+        .collect();
+    paths.sort();
+    paths
+}
+
+pub fn load_ship_protos(
+    mut prototypes : PrototypesMut,
+    mut protos : ResMut<ShipProtos>
+) {
+    protos.handles = proto_paths().iter()
+        .map(|path| prototypes.load(path.as_str()))
+        .collect();
+}
+
+/// Loaded prototypes, the ones still loading or broken are left out
+pub fn loaded_protos(protos : &ShipProtos, assets : &Assets<Prototype>) -> Vec<ProtoNode> {
+    protos.handles.iter()
+        .filter_map(|handle| assets.get(handle))
+        .filter_map(|proto| ProtoNode::from_proto(proto, assets))
+        .collect()
+}
+
+/// Inserts the schematics which are components into the spawned entity
+pub fn insert_schematics(world : &mut World, entity : Entity, schematics : Vec<Box<dyn Reflect>>) {
+    let registry = world.resource::<AppTypeRegistry>().clone();
+    let registry = registry.read();
+    let Some(mut entity) = world.get_entity_mut(entity) else {
+        return;
+    };
+    for schematic in schematics {
+        let reflect_component = registry.get_with_name(schematic.type_name())
+            .and_then(|registration| registration.data::<ReflectComponent>());
+        if let Some(reflect_component) = reflect_component {
+            reflect_component.insert(&mut entity, schematic.as_ref());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::reflect::{DynamicEnum, DynamicStruct, DynamicTuple};
+
+    use super::*;
+
+    #[test]
+    fn scene_path_from_schematic_input() {
+        let mut path = DynamicTuple::default();
+        path.insert("ship/tiles/engine.glb#Scene0".to_string());
+        let mut bundle = DynamicStruct::default();
+        bundle.insert("scene", DynamicEnum::new("AssetPath", path));
+        assert_eq!(scene_path(&bundle).as_deref(), Some("ship/tiles/engine.glb#Scene0"));
+
+        let mut bundle = DynamicStruct::default();
+        bundle.insert("scene", DynamicEnum::new("HandleId", DynamicTuple::default()));
+        assert_eq!(scene_path(&bundle), None);
+    }
+}
@@ -1,9 +1,10 @@
-use bevy::{prelude::*, math::DVec3};
+use bevy::{prelude::*, math::{DVec3, DQuat}, utils::HashMap};
+use bevy_proto::prelude::{Prototype, ProtoAssetEvent};
 use space_bundle::DSceneBundle;
 use bevy_xpbd_3d::prelude::*;
-use crate::{objects::{prelude::PilotSeat, radar::Radar, ship_camera::ShipCamera}, *};
+use crate::{scenes::asset_editor::{BlockConfig, ron_collider::*}, *};
 
-use super::{VOXEL_SIZE, instance_rotate::InstanceRotate};
+use super::{VOXEL_SIZE, instance_rotate::InstanceRotate, catalogue::*, mass::*, atmos::{Airtight, Heater}};
 
 pub const TELEPORN_NAME : &str = "Teleport spot";
 
@@ -11,34 +12,20 @@ pub trait BuildInstance {
     fn build(&self, cmds : &mut Commands, asset_server : &AssetServer) -> Entity;
 }
 
-pub struct ClosureInstance<F> {
-    pub f : F
-}
-
-impl<F> ClosureInstance<F> {
-    fn new(f : F) -> ClosureInstance<F> {
-        ClosureInstance {
-            f
-        }
-    }
-
-    fn to_box(self) -> Box<ClosureInstance<F>> {
-        Box::new(self)
-    }
-}
-
-impl<F> BuildInstance for ClosureInstance<F>
-        where F : Fn(&mut Commands, &AssetServer) -> Entity {
-    fn build(&self, cmds : &mut Commands, asset_server : &AssetServer) -> Entity {
-        (self.f)(cmds, asset_server)
-    }
-}
-
 #[derive(Component, Clone)]
 pub struct VoxelInstance {
     pub bbox : IVec3,
     pub common_id : u32,
     pub origin : DVec3,
+    /// Mass in kg, see [`BlockMass`]
+    pub mass : f64
+}
+
+impl VoxelInstance {
+    /// Offset from the center of the occupied cells to the instance transform
+    pub fn origin_offset(&self, rot : &InstanceRotate) -> DVec3 {
+        rot.quat() * (self.bbox.as_dvec3() / 2.0 * VOXEL_SIZE * self.origin)
+    }
 }
 
 pub struct VoxelInstanceConfig
@@ -53,213 +40,122 @@ pub struct AllVoxelInstances {
     pub configs : Vec<VoxelInstanceConfig>
 }
 
-fn spawn_static_instance<F>(
-        indexer : &mut u32,
-        bbox : IVec3,
-        origin : DVec3,
-        name : &str,
-        path : &str,
-        after_build : F) -> VoxelInstanceConfig
-    where F : Fn(&mut Commands, Entity) + Send  + Sync + 'static {
-    
-    let owned_path = path.to_string();
-
-    let instance = VoxelInstance {
-        bbox,
-        common_id : *indexer,
-        origin
-    };
-    let cfg = VoxelInstanceConfig {
-        name : name.to_string(),
-        instance : instance.clone(),
-        create : ClosureInstance::new(move |cmds : &mut Commands, asset_server : &AssetServer| {
-            let id = cmds.spawn(DSceneBundle {
-                scene: asset_server.load(&owned_path),
-                
-                ..default()
-            })
-            .insert(instance.clone())
-            .insert(InstanceRotate::default()).id();
-
-            let collider_pos = -instance.origin * bbox.as_dvec3() / 2.0 * VOXEL_SIZE;
-
-            let collider = Collider::cuboid(
-                bbox.x as f64 * VOXEL_SIZE, 
-                bbox.y as f64 * VOXEL_SIZE, 
-                 bbox.z as f64 * VOXEL_SIZE);
-
-            let collider = Collider::compound(
-                vec![
-                    (DVec3::new(collider_pos.x, collider_pos.y, collider_pos.y), 
-                    bevy::math::DQuat::default(), 
-                    collider)]);
-
-            cmds.entity(id)
-                .insert(collider);
-
-            after_build(cmds, id);
-
-            id
-        }).to_box()
-    };
-    *indexer += 1;
-
-    cfg
+/// Voxel instance described by a prototype file from [`PROTO_DIR`]
+pub struct ProtoInstance {
+    pub node : ProtoNode,
+    pub instance : VoxelInstance
 }
 
-pub fn init_all_voxel_instances(
-    mut cmds : Commands
-) {
-    let mut configs = vec![];
-
-    let mut indexer = 0;
-
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer, 
-            [8, 1, 8].into(), 
-            [0.0, 0.0, 0.0].into(), 
-            "Metal grids", 
-            "ss13/wall_models/metal_grid/metal_grid.gltf#Scene0",
-            |_,_|{});
-        configs.push(cfg);
-    }
-
-    {   
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [2, 1, 2].into(),
-            [0.0, 0.0, 0.0].into(),
-            TELEPORN_NAME,
-            "furniture/teleport_spot.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
-    }
-
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 1, 8].into(),
-            [0.0, 0.0, 0.0].into(),
-            "White plate",
-            "ship/tiles/base_plate.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
-    }
+impl ProtoInstance {
+    /// Collider from `RonColliderCompound` or a box over the whole bbox
+    fn collider(&self) -> Collider {
+        if let Some(collider) = self.node.schematic::<RonColliderCompound>().and_then(|compound| compound.into_collider()) {
+            return collider;
+        }
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 1, 8].into(),
-            [0.0, 0.0, 0.0].into(),
-            "White triangle plate",
-            "ship/tiles/white_triangle_plate.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
+        let bbox = self.instance.bbox;
+        let collider_pos = -self.instance.origin * bbox.as_dvec3() / 2.0 * VOXEL_SIZE;
+        let collider = Collider::cuboid(
+            bbox.x as f64 * VOXEL_SIZE,
+            bbox.y as f64 * VOXEL_SIZE,
+            bbox.z as f64 * VOXEL_SIZE);
+        Collider::compound(vec![(collider_pos, DQuat::default(), collider)])
     }
+}
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 8, 1].into(),
-            [0.0, 0.0, 0.0].into(),
-            "White door",
-            "ship/tiles/door.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
-    }
+fn spawn_proto_node(cmds : &mut Commands, asset_server : &AssetServer, node : &ProtoNode) -> Entity {
+    let id = if let Some(scene) = &node.scene {
+        cmds.spawn(DSceneBundle {
+            scene : asset_server.load(scene),
+            ..default()
+        }).id()
+    } else {
+        cmds.spawn(DSpatialBundle::default()).id()
+    };
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 8, 1].into(),
-            [0.0, 0.0, 0.0].into(),
-            "Window",
-            "ship/tiles/window.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
+    let schematics : Vec<_> = node.schematics.iter().map(|schematic| schematic.clone_value()).collect();
+    if !schematics.is_empty() {
+        cmds.add(move |world : &mut World| insert_schematics(world, id, schematics));
     }
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 1, 8].into(),
-            [0.0, 0.0, 0.0].into(),
-            "Corner window",
-            "ship/tiles/corner_window.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
+    for child in &node.children {
+        let child = spawn_proto_node(cmds, asset_server, child);
+        cmds.entity(id).add_child(child);
     }
+    id
+}
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 8, 26].into(),
-            [0.0, 0.0, 0.0].into(),
-            "Engine",
-            "ship/tiles/engine.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
+impl BuildInstance for ProtoInstance {
+    fn build(&self, cmds : &mut Commands, asset_server : &AssetServer) -> Entity {
+        let id = spawn_proto_node(cmds, asset_server, &self.node);
+        cmds.entity(id)
+            .insert(self.instance.clone())
+            .insert(InstanceRotate::default())
+            .insert(self.collider());
+        id
     }
+}
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8, 4, 8].into(),
-            [0.0, -1.0, 0.0].into(),
-            "Pilot seat",
-            "ship/tiles/pilot_seat.glb#Scene0",
-            |cmds, id|{
-                cmds.entity(id).insert(PilotSeat::default());
-
-                let radar = 
-                    cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(0.0, 1.0, -0.75)))
-                    .insert(Radar::default()).id();
-
-                cmds.entity(id).add_child(radar);
+impl AllVoxelInstances {
+    /// Builds configs from prototypes. Ids of already known names are kept, so placed blocks stay valid after reload
+    pub fn from_protos(nodes : Vec<ProtoNode>, prev : Option<&AllVoxelInstances>) -> AllVoxelInstances {
+        let mut ids : HashMap<String, u32> = prev
+            .map(|prev| prev.configs.iter().map(|cfg| (cfg.name.clone(), cfg.instance.common_id)).collect())
+            .unwrap_or_default();
+        let mut next_id = ids.values().map(|id| id + 1).max().unwrap_or(0);
+
+        let mut configs = vec![];
+        for node in nodes {
+            let Some(block_cfg) = node.schematic::<BlockConfig>() else {
+                warn!("Prototype {} has no BlockConfig and is not a voxel instance", node.name);
+                continue;
+            };
+            if configs.iter().any(|cfg : &VoxelInstanceConfig| cfg.name == node.name) {
+                warn!("Duplicate voxel instance {}", node.name);
+                continue;
             }
-        );
-        configs.push(cfg);
-    }
+            let common_id = *ids.entry(node.name.clone()).or_insert_with(|| {
+                next_id += 1;
+                next_id - 1
+            });
+
+            let mass = node.schematic::<BlockMass>().unwrap_or_default();
+            let instance = VoxelInstance {
+                bbox : block_cfg.bbox,
+                common_id,
+                origin : block_cfg.origin,
+                mass : mass.resolve(block_cfg.bbox, VOXEL_SIZE)
+            };
+            configs.push(VoxelInstanceConfig {
+                name : node.name.clone(),
+                instance : instance.clone(),
+                create : Box::new(ProtoInstance {
+                    node,
+                    instance
+                })
+            });
+        }
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [8 * 3 + 2, 8, 8].into(),
-            [0.0, -1.0, 1.0].into(),
-            "Pilot top window",
-            "ship/tiles/pilot_top_window.glb#Scene0",
-            |_,_|{}
-        );
-        configs.push(cfg);
+        AllVoxelInstances {
+            configs
+        }
     }
+}
 
-    {
-        let cfg = spawn_static_instance(
-            &mut indexer,
-            [1, 1, 1].into(),
-            [0.0, -1.0, 0.0].into(),
-            "Camera",
-            "ship/tiles/camera.glb#Scene0",
-            |cmds,id|{
-                cmds.entity(id).insert(ShipCamera);
-            }
-        );
-        configs.push(cfg);
+/// Rebuilds the catalogue when bevy_proto loads, reloads or drops a prototype
+pub fn reload_voxel_instances(
+    mut events : EventReader<ProtoAssetEvent>,
+    protos : Res<ShipProtos>,
+    assets : Res<Assets<Prototype>>,
+    mut all_instances : ResMut<AllVoxelInstances>
+) {
+    if events.iter().count() == 0 {
+        return;
     }
 
-    let all_instances = AllVoxelInstances {
-        configs
-    };
-
-    cmds.insert_resource(all_instances);
+    let reloaded = AllVoxelInstances::from_protos(loaded_protos(&protos, &assets), Some(&all_instances));
+    info!("Loaded {} voxel instances", reloaded.configs.len());
+    *all_instances = reloaded;
 }
 
 
@@ -267,8 +163,22 @@ pub struct VoxelInstancePlugin;
 
 impl Plugin for VoxelInstancePlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, init_all_voxel_instances);
+        app.register_type::<BlockConfig>();
+        app.register_type::<RonColliderCompound>();
+        app.register_type::<RonCollider>();
+        app.register_type::<RonBoxCollider>();
+        app.register_type::<RonSphereCollider>();
+        app.register_type::<Vec<RonCollider>>();
+        app.register_type::<BlockMass>();
+        app.register_type::<BlockMaterial>();
+        app.register_type::<Airtight>();
+        app.register_type::<Heater>();
+
+        app.insert_resource(AllVoxelInstances {
+            configs : vec![]
+        });
+        app.insert_resource(ShipProtos::default());
+        app.add_systems(Startup, load_ship_protos);
+        app.add_systems(Update, reload_voxel_instances);
     }
 }
-
-
@@ -0,0 +1,191 @@
+use bevy::{prelude::*, math::DVec3, utils::HashMap};
+use bevy_transform64::prelude::*;
+use bevy_xpbd_3d::prelude::*;
+
+use crate::DSpatialBundle;
+use crate::space_voxel::lod::VoxelLod;
+use crate::space_voxel::{VoxelMap, objected_voxel_map::{ObjectedVoxelMap, VoxelVal}};
+
+use super::{Ship, InstanceGridPos};
+
+const NEIGHBOURS : [IVec3; 6] = [
+    IVec3::X, IVec3::NEG_X,
+    IVec3::Y, IVec3::NEG_Y,
+    IVec3::Z, IVec3::NEG_Z
+];
+
+/// Forces a connectivity check of the ship, static ships included
+#[derive(Event)]
+pub struct CmdShipSplit(pub Entity);
+
+/// Sent for every part cut off from `from`
+#[derive(Event)]
+pub struct ShipSplitOff {
+    pub from : Entity,
+    pub part : Entity
+}
+
+/// Groups of face connected occupied cells inside `size` cells from `from`, the largest first
+pub fn voxel_islands<T, M>(map : &M, from : IVec3, size : IVec3) -> Vec<Vec<IVec3>>
+        where T : Clone, M : VoxelMap<VoxelVal<T>> {
+    let linear = |idx : IVec3| {
+        let idx = idx - from;
+        ((idx.z * size.y + idx.y) * size.x + idx.x) as usize
+    };
+    let occupied = |idx : IVec3| {
+        (idx - from).cmpge(IVec3::ZERO).all()
+            && (idx - from).cmplt(size).all()
+            && !matches!(map.get_by_idx(&idx), VoxelVal::None)
+    };
+
+    let mut visited = vec![false; (size.x * size.y * size.z) as usize];
+    let mut islands = vec![];
+    let mut stack = vec![];
+    for z in from.z..(from.z + size.z) {
+        for y in from.y..(from.y + size.y) {
+            for x in from.x..(from.x + size.x) {
+                let start = IVec3::new(x, y, z);
+                if visited[linear(start)] || !occupied(start) {
+                    continue;
+                }
+                visited[linear(start)] = true;
+                stack.push(start);
+
+                let mut island = vec![];
+                while let Some(idx) = stack.pop() {
+                    island.push(idx);
+                    for dir in NEIGHBOURS {
+                        let next = idx + dir;
+                        if occupied(next) && !visited[linear(next)] {
+                            visited[linear(next)] = true;
+                            stack.push(next);
+                        }
+                    }
+                }
+                islands.push(island);
+            }
+        }
+    }
+
+    islands.sort_by_key(|island| std::cmp::Reverse(island.len()));
+    islands
+}
+
+/// Moves every island except the largest one into a new ship with the same transform and map layout,
+/// so cell indices and local transforms of the moved instances stay valid
+pub fn split_detached_parts(
+    mut cmds : Commands,
+    mut cmd_split : EventReader<CmdShipSplit>,
+    mut split_off : EventWriter<ShipSplitOff>,
+    mut ships : Query<(Entity, &mut Ship, &DTransform, &RigidBody, Option<&LinearVelocity>, Option<&AngularVelocity>, Option<&CenterOfMass>)>,
+    instances : Query<(Entity, &Parent, &InstanceGridPos)>
+) {
+    let mut to_check : Vec<Entity> = cmd_split.iter().map(|cmd| cmd.0).collect();
+    // built ships are static, only flying ones fall apart by themselves
+    to_check.extend(ships.iter_mut()
+        .filter(|(_, ship, _, body, ..)| ship.is_changed() && **body == RigidBody::Dynamic)
+        .map(|(e, ..)| e));
+    to_check.sort();
+    to_check.dedup();
+
+    for ship_e in to_check {
+        let Ok((_, mut ship, transform, body, lin_vel, ang_vel, com)) = ships.get_mut(ship_e) else {
+            continue;
+        };
+        let Some((min, max)) = ship.map.chunk_bounds() else {
+            continue;
+        };
+        let islands = voxel_islands(&ship.map, min, max - min);
+        if islands.len() < 2 {
+            continue;
+        }
+
+        let owned : HashMap<Entity, IVec3> = instances.iter()
+            .filter(|(_, parent, _)| parent.get() == ship_e)
+            .map(|(e, _, grid)| (e, grid.idx))
+            .collect();
+        let core_com = com.map(|com| com.0).unwrap_or(DVec3::ZERO);
+
+        for island in islands.iter().skip(1) {
+            let mut part = Ship::new();
+            part.map.set_first_voxel_pos(ship.map.first_voxel_pos);
+            let mut centroid = DVec3::ZERO;
+            let mut moved : Vec<Entity> = vec![];
+            for idx in island {
+                match ship.map.get_cloned_by_idx(idx) {
+                    VoxelVal::Object(e) => {
+                        if !moved.contains(&e) {
+                            moved.push(e);
+                        }
+                    },
+                    val => {
+                        part.map.set_voxel_by_idx(idx, val);
+                        ship.map.set_voxel_by_idx(idx, VoxelVal::None);
+                    }
+                }
+                centroid += ship.map.get_idx_pos(idx) + ship.map.voxel_size / 2.0;
+            }
+            centroid /= island.len() as f64;
+            for e in &moved {
+                if let Some(footprint) = ship.map.erase_object(*e) {
+                    part.map.place_object(*e, footprint);
+                }
+            }
+            // only instances of this ship are moved to the part
+            moved.retain(|e| owned.contains_key(e));
+
+            let mut part_cmds = cmds.spawn(part);
+            part_cmds
+                .insert(DSpatialBundle::from_transform(*transform))
+                .insert(*body)
+                .insert(GravityScale(0.0))
+                .insert(VoxelLod::default())
+                .insert(Name::new("Ship part"));
+            // the part keeps moving like the point of the old body it was attached to
+            if let Some(lin_vel) = lin_vel {
+                let ang = ang_vel.map(|ang| ang.0).unwrap_or(DVec3::ZERO);
+                let arm = transform.rotation * (centroid - core_com);
+                part_cmds.insert(LinearVelocity(lin_vel.0 + ang.cross(arm)));
+            }
+            if let Some(ang_vel) = ang_vel {
+                part_cmds.insert(*ang_vel);
+            }
+            let part_e = part_cmds.id();
+
+            cmds.entity(part_e).push_children(&moved);
+
+            info!("Ship {:?} split off {:?} with {} cells", ship_e, part_e, island.len());
+            split_off.send(ShipSplitOff {
+                from : ship_e,
+                part : part_e
+            });
+        }
+        ship.map.prune_empty();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::space_voxel::objected_voxel_map::ObjectedMap;
+    use crate::space_voxel::solid_voxel_map::SolidVoxelMap;
+
+    use super::*;
+
+    #[test]
+    fn islands_split_by_gap() {
+        let mut map = ObjectedMap::new(SolidVoxelMap::<VoxelVal<i32>>::new(DVec3::ZERO, IVec3::new(10, 10, 10), 0.25));
+        let a = Entity::from_raw(1);
+        let b = Entity::from_raw(2);
+        map.set_object_by_idx(a, &IVec3::new(0, 0, 0), &IVec3::new(4, 1, 4), &IVec3::ZERO);
+        map.set_object_by_idx(b, &IVec3::new(4, 0, 0), &IVec3::new(1, 1, 1), &IVec3::ZERO);
+        map.set_voxel_by_idx(&IVec3::new(8, 8, 8), VoxelVal::Voxel(0));
+
+        let islands = voxel_islands(&map, IVec3::ZERO, map.size);
+        assert_eq!(islands.len(), 2);
+        assert_eq!(islands[0].len(), 17);
+        assert_eq!(islands[1], vec![IVec3::new(8, 8, 8)]);
+
+        map.erase_object(a);
+        assert_eq!(voxel_islands(&map, IVec3::ZERO, map.size).len(), 2);
+    }
+}
@@ -0,0 +1,152 @@
+use bevy::{prelude::*, math::{DVec3, DQuat}};
+use bevy::utils::HashMap;
+use bevy_transform64::prelude::DTransform;
+use bevy_xpbd_3d::prelude::*;
+
+use crate::DSpatialBundle;
+use crate::space_voxel::VoxelMap;
+use crate::space_voxel::chunked_voxel_map::VoxelChunk;
+use crate::space_voxel::objected_voxel_map::VoxelVal;
+
+use super::Ship;
+
+/// Hull collider entities of the ship chunks, by chunk origin
+#[derive(Component, Default)]
+pub struct ShipHullColliders {
+    pub chunks : HashMap<IVec3, Entity>
+}
+
+/// Covers the solid cells of the chunk with boxes given as (min, size) in chunk cells.
+/// Boxes grow along x, then y, then z, so a filled chunk is one box
+pub fn greedy_boxes<T>(chunk : &VoxelChunk<T>, solid : impl Fn(&T) -> bool) -> Vec<(IVec3, IVec3)>
+    where T : Default + Clone {
+    let size = chunk.size;
+    let idx = |p : IVec3| ((p.z * size.y + p.y) * size.x + p.x) as usize;
+    let free = |used : &[bool], p : IVec3| !used[idx(p)] && solid(chunk.get(p.x, p.y, p.z));
+    let mut used = vec![false; chunk.data.len()];
+
+    let mut boxes = vec![];
+    for z in 0..size.z {
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let min = IVec3::new(x, y, z);
+                if !free(&used, min) {
+                    continue;
+                }
+                let mut ext = IVec3::ONE;
+                while min.x + ext.x < size.x && free(&used, min + IVec3::new(ext.x, 0, 0)) {
+                    ext.x += 1;
+                }
+                while min.y + ext.y < size.y
+                    && (0..ext.x).all(|dx| free(&used, min + IVec3::new(dx, ext.y, 0))) {
+                    ext.y += 1;
+                }
+                while min.z + ext.z < size.z
+                    && (0..ext.y).all(|dy| (0..ext.x).all(|dx| free(&used, min + IVec3::new(dx, dy, ext.z)))) {
+                    ext.z += 1;
+                }
+
+                for dz in 0..ext.z {
+                    for dy in 0..ext.y {
+                        for dx in 0..ext.x {
+                            used[idx(min + IVec3::new(dx, dy, dz))] = true;
+                        }
+                    }
+                }
+                boxes.push((min, ext));
+            }
+        }
+    }
+    boxes
+}
+
+/// Compound of the boxes with the cell zero corner at the local origin
+pub fn boxes_collider(boxes : &[(IVec3, IVec3)], voxel_size : f64) -> Option<Collider> {
+    if boxes.is_empty() {
+        return None;
+    }
+    let shapes = boxes.iter()
+        .map(|(min, ext)| {
+            let size = ext.as_dvec3() * voxel_size;
+            let center = min.as_dvec3() * voxel_size + size / 2.0;
+            (center, DQuat::IDENTITY, Collider::cuboid(size.x, size.y, size.z))
+        })
+        .collect();
+    Some(Collider::compound(shapes))
+}
+
+/// Rebuilds the hull colliders of the chunks in the ship `dirty_set` from its raw voxel cells.
+/// Placed instances bring their own colliders
+pub fn update_ship_hull_colliders(
+    mut cmds : Commands,
+    mut ships : Query<(Entity, &Ship, Option<&mut ShipHullColliders>), Changed<Ship>>
+) {
+    for (ship_e, ship, hull) in ships.iter_mut() {
+        if ship.map.dirty_set.is_empty() {
+            continue;
+        }
+        let mut new_hull = ShipHullColliders::default();
+        let hull = match hull {
+            Some(hull) => hull.into_inner(),
+            None => &mut new_hull
+        };
+
+        for origin in ship.map.dirty_set.iter().copied() {
+            if let Some(e) = hull.chunks.remove(&origin) {
+                cmds.entity(e).despawn_recursive();
+            }
+            let Some(chunk) = ship.map.map.get(&origin) else {
+                continue;
+            };
+            let boxes = greedy_boxes(chunk, |val| matches!(val, VoxelVal::Voxel(_)));
+            let Some(collider) = boxes_collider(&boxes, ship.map.voxel_size) else {
+                continue;
+            };
+
+            let pos : DVec3 = ship.map.get_idx_pos(&origin);
+            let e = cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(pos.x, pos.y, pos.z)))
+                .insert(collider)
+                .insert(Name::new("Ship hull"))
+                .id();
+            cmds.entity(ship_e).add_child(e);
+            hull.chunks.insert(origin, e);
+        }
+
+        if !new_hull.chunks.is_empty() {
+            cmds.entity(ship_e).insert(new_hull);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boxes_cover_solid_cells() {
+        let mut chunk = VoxelChunk::<VoxelVal<i32>>::new(IVec3::ZERO, IVec3::new(8, 8, 8));
+        let solid = |val : &VoxelVal<i32>| matches!(val, VoxelVal::Voxel(_));
+
+        chunk.fill(&VoxelVal::Voxel(0));
+        assert_eq!(greedy_boxes(&chunk, solid), vec![(IVec3::ZERO, IVec3::new(8, 8, 8))]);
+
+        // floor plate with a wall along one edge
+        chunk.fill(&VoxelVal::None);
+        for z in 0..8 {
+            for x in 0..8 {
+                *chunk.get_mut(x, 0, z) = VoxelVal::Voxel(0);
+            }
+            for y in 1..4 {
+                *chunk.get_mut(0, y, z) = VoxelVal::Voxel(1);
+            }
+        }
+        *chunk.get_mut(5, 5, 5) = VoxelVal::Object(Entity::from_raw(1));
+        let boxes = greedy_boxes(&chunk, solid);
+        assert_eq!(boxes.len(), 2);
+        let volume : i32 = boxes.iter().map(|(_, ext)| ext.x * ext.y * ext.z).sum();
+        assert_eq!(volume, 8 * 8 + 3 * 8);
+
+        assert!(boxes_collider(&boxes, 0.25).is_some());
+        assert!(boxes_collider(&[], 0.25).is_none());
+    }
+}
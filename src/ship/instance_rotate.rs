@@ -1,18 +1,116 @@
+use std::f64::consts::FRAC_PI_2;
+
+use bevy::math::{DQuat, DVec3};
 use bevy::prelude::*;
 use bevy_transform64::prelude::*;
 
 
+/// Orientation of a placed instance in quarter turns.
+/// `rot_steps.x` turns around Y (older saves only have this one), `rot_steps.y` around X and `rot_steps.z` around Z.
+/// The turns are applied in the order Z, X, Y
 #[derive(Component, Clone, Reflect, Default)]
 #[reflect(Component)]
 pub struct InstanceRotate {
     pub rot_steps : IVec3
 }
 
+impl InstanceRotate {
+    pub fn quat(&self) -> DQuat {
+        let angles = self.rot_steps.as_dvec3() * FRAC_PI_2;
+        DQuat::from_rotation_y(angles.x) * DQuat::from_rotation_x(angles.y) * DQuat::from_rotation_z(angles.z)
+    }
+
+    /// Closest of the 24 grid orientations. Turns around Y only are preferred, so old saves keep their steps
+    pub fn from_quat(rotation : DQuat) -> InstanceRotate {
+        let mut best = InstanceRotate::default();
+        let mut best_dot = -1.0;
+        for y in 0..4 {
+            for z in 0..4 {
+                for x in 0..4 {
+                    let candidate = InstanceRotate {
+                        rot_steps : IVec3::new(x, y, z)
+                    };
+                    let dot = candidate.quat().dot(rotation).abs();
+                    if dot > best_dot + 1e-6 {
+                        best_dot = dot;
+                        best = candidate;
+                    }
+                }
+            }
+        }
+        best
+    }
+
+    /// Quarter turn around a world axis
+    pub fn turned(&self, axis : DVec3) -> InstanceRotate {
+        InstanceRotate::from_quat(DQuat::from_axis_angle(axis, FRAC_PI_2) * self.quat())
+    }
+
+    /// Size of the rotated instance in the voxel map
+    pub fn rotate_bbox(&self, bbox : IVec3) -> IVec3 {
+        (self.quat() * bbox.as_dvec3()).abs().round().as_ivec3()
+    }
+
+    /// Rotation of the mirror image across the plane orthogonal to X
+    pub fn mirrored_x(&self) -> InstanceRotate {
+        let q = self.quat();
+        InstanceRotate::from_quat(DQuat::from_xyzw(q.x, -q.y, -q.z, q.w))
+    }
+
+    /// Rotation of the mirror image across the plane orthogonal to Z
+    pub fn mirrored_z(&self) -> InstanceRotate {
+        let q = self.quat();
+        InstanceRotate::from_quat(DQuat::from_xyzw(-q.x, -q.y, q.z, q.w))
+    }
+}
+
 pub fn prepare_instance_rotate(
-    _query : Query<(&mut DTransform, &InstanceRotate), Added<InstanceRotate>>
+    mut query : Query<(&mut DTransform, &InstanceRotate), Changed<InstanceRotate>>
 ) {
-    // for (mut transform, rot) in query.iter_mut() {
-    //     let xyz_euler = rot.rot_steps.as_vec3() * 90.0;
-    //     transform.rotation = Quat::from_euler(EulerRot::XYZ, xyz_euler.x, xyz_euler.y, xyz_euler.z);
-    // }
-}
\ No newline at end of file
+    for (mut transform, rot) in query.iter_mut() {
+        transform.rotation = rot.quat();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn all_orientations() {
+        let mut bboxes = HashSet::new();
+        let mut steps = HashSet::new();
+        for y in 0..4 {
+            for z in 0..4 {
+                for x in 0..4 {
+                    let rot = InstanceRotate::from_quat(InstanceRotate { rot_steps : IVec3::new(x, y, z) }.quat());
+                    steps.insert(rot.rot_steps);
+                    bboxes.insert(rot.rotate_bbox(IVec3::new(1, 2, 3)));
+                }
+            }
+        }
+        assert_eq!(steps.len(), 24);
+        assert_eq!(bboxes.len(), 6);
+    }
+
+    #[test]
+    fn yaw_steps_are_kept() {
+        for x in 0..4 {
+            let rot = InstanceRotate { rot_steps : IVec3::new(x, 0, 0) };
+            assert_eq!(InstanceRotate::from_quat(rot.quat()).rot_steps, rot.rot_steps);
+        }
+    }
+
+    #[test]
+    fn turn_and_mirror() {
+        let rot = InstanceRotate::default().turned(DVec3::X);
+        assert_eq!(rot.rotate_bbox(IVec3::new(8, 8, 1)), IVec3::new(8, 1, 8));
+
+        let rot = InstanceRotate::default().turned(DVec3::Y);
+        assert_eq!(rot.rot_steps, IVec3::new(1, 0, 0));
+        assert_eq!(rot.mirrored_x().rot_steps, IVec3::new(3, 0, 0));
+        assert_eq!(rot.mirrored_z().rot_steps, IVec3::new(3, 0, 0));
+    }
+}
@@ -0,0 +1,165 @@
+use bevy::{prelude::*, math::{DVec3, DMat3}};
+use bevy_proto::prelude::{Schematic, ReflectSchematic};
+use bevy_xpbd_3d::prelude::*;
+
+use crate::space_voxel::VoxelMap;
+use crate::space_voxel::objected_voxel_map::VoxelVal;
+
+use super::{Ship, InstanceGridPos, common::VoxelInstance};
+
+#[derive(Reflect, Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockMaterial {
+    #[default]
+    Steel,
+    Aluminium,
+    Glass,
+    Composite
+}
+
+impl BlockMaterial {
+    /// Density of a whole block in kg/m³. Blocks are hollow, so this is far below the solid material
+    pub fn density(&self) -> f64 {
+        match self {
+            BlockMaterial::Steel => 800.0,
+            BlockMaterial::Aluminium => 300.0,
+            BlockMaterial::Glass => 250.0,
+            BlockMaterial::Composite => 150.0,
+        }
+    }
+
+    pub fn block_mass(&self, bbox : IVec3, voxel_size : f64) -> f64 {
+        (bbox.x * bbox.y * bbox.z) as f64 * voxel_size.powi(3) * self.density()
+    }
+}
+
+/// Mass of a voxel instance template. Zero `mass` means it is taken from the material and the bbox volume
+#[derive(Component, Reflect, Schematic, Clone, Default)]
+#[reflect(Schematic)]
+pub struct BlockMass {
+    #[reflect(default)]
+    pub material : BlockMaterial,
+    #[reflect(default)]
+    pub mass : f64
+}
+
+impl BlockMass {
+    pub fn resolve(&self, bbox : IVec3, voxel_size : f64) -> f64 {
+        if self.mass > 0.0 {
+            self.mass
+        } else {
+            self.material.block_mass(bbox, voxel_size)
+        }
+    }
+}
+
+/// Aggregated mass of a set of boxes in the ship local frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MassSummary {
+    pub mass : f64,
+    pub center : DVec3,
+    /// Inertia tensor around `center`
+    pub inertia : DMat3
+}
+
+impl MassSummary {
+    /// Sums solid boxes given as (mass, center, size)
+    pub fn from_boxes(boxes : &[(f64, DVec3, DVec3)]) -> Option<MassSummary> {
+        let mass : f64 = boxes.iter().map(|(m, _, _)| *m).sum();
+        if mass <= 0.0 {
+            return None;
+        }
+        let center = boxes.iter().map(|(m, c, _)| *c * *m).sum::<DVec3>() / mass;
+
+        let mut inertia = DMat3::ZERO;
+        for (m, c, size) in boxes {
+            let sq = *size * *size;
+            let own = DMat3::from_diagonal(DVec3::new(sq.y + sq.z, sq.x + sq.z, sq.x + sq.y) * (*m / 12.0));
+            // parallel axis theorem
+            let r = *c - center;
+            let shift = (DMat3::IDENTITY * r.length_squared() - outer(r, r)) * *m;
+            inertia += own + shift;
+        }
+
+        Some(MassSummary {
+            mass,
+            center,
+            inertia
+        })
+    }
+}
+
+fn outer(a : DVec3, b : DVec3) -> DMat3 {
+    DMat3::from_cols(a * b.x, a * b.y, a * b.z)
+}
+
+/// Recomputes `Mass`, `CenterOfMass` and `Inertia` of ships whose blocks changed, hull cells included.
+/// Placed and erased instances reach `Children` a frame after the map, so both are watched
+pub fn update_ship_mass(
+    mut cmds : Commands,
+    ships : Query<(Entity, Ref<Ship>, Option<Ref<Children>>)>,
+    instances : Query<(&VoxelInstance, &InstanceGridPos)>
+) {
+    for (ship_e, ship, children) in ships.iter() {
+        if !ship.is_changed() && !children.as_ref().is_some_and(|children| children.is_changed()) {
+            continue;
+        }
+        let voxel_size = ship.map.voxel_size;
+        let cell = DVec3::splat(voxel_size);
+        let mut boxes : Vec<_> = children.iter()
+            .flat_map(|children| children.iter())
+            .filter_map(|child| instances.get(*child).ok())
+            .map(|(instance, grid)| {
+                let size = grid.bbox.as_dvec3() * voxel_size;
+                let center = ship.map.get_idx_pos(&grid.idx) + size / 2.0;
+                (instance.mass, center, size)
+            })
+            .collect();
+        boxes.extend(ship.map.iter().filter_map(|(idx, val)| match val {
+            VoxelVal::Voxel(block) => Some((block.density() * voxel_size.powi(3), ship.map.get_idx_pos(&idx) + cell / 2.0, cell)),
+            _ => None
+        }));
+
+        let Some(summary) = MassSummary::from_boxes(&boxes) else {
+            // nothing left, the ship must not keep the mass of erased blocks
+            cmds.entity(ship_e).insert((
+                Mass(0.0),
+                InverseMass(0.0),
+                CenterOfMass(DVec3::ZERO),
+                Inertia(DMat3::ZERO),
+                InverseInertia(DMat3::ZERO)
+            ));
+            continue;
+        };
+        cmds.entity(ship_e).insert((
+            Mass(summary.mass),
+            InverseMass(1.0 / summary.mass),
+            CenterOfMass(summary.center),
+            Inertia(summary.inertia),
+            InverseInertia(summary.inertia.inverse())
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_boxes() {
+        let size = DVec3::ONE;
+        let summary = MassSummary::from_boxes(&[
+            (1.0, DVec3::new(-1.0, 0.0, 0.0), size),
+            (3.0, DVec3::new(1.0, 0.0, 0.0), size),
+        ]).unwrap();
+        assert_eq!(summary.mass, 4.0);
+        assert!((summary.center - DVec3::new(0.5, 0.0, 0.0)).length() < 1e-9);
+
+        // boxes alone give 4 * 2/12, offsets give 1 * 1.5² + 3 * 0.5²
+        let own = 4.0 * 2.0 / 12.0;
+        assert!((summary.inertia.x_axis.x - own).abs() < 1e-9);
+        assert!((summary.inertia.y_axis.y - own - 3.0).abs() < 1e-9);
+        assert!((summary.inertia.z_axis.z - own - 3.0).abs() < 1e-9);
+
+        assert!(MassSummary::from_boxes(&[]).is_none());
+    }
+}
@@ -1,7 +1,8 @@
 use bevy::{prelude::*, math::DVec3};
 use bevy_transform64::{prelude::DTransform};
 use crate::{space_voxel::objected_voxel_map::*, DSpatialBundle};
-use crate::space_voxel::solid_voxel_map::SolidVoxelMap;
+use crate::space_voxel::chunked_voxel_map::ChunkedVoxelMap;
+use crate::space_voxel::lod::VoxelLod;
 use crate::space_voxel::*;
 use serde::{Deserialize, Serialize};
 use bevy_xpbd_3d::prelude::*;
@@ -10,58 +11,148 @@ use bevy_xpbd_3d::prelude::*;
 
 pub mod common;
 pub mod save_load;
+pub mod save_slots;
+pub mod blueprint;
 pub mod instance_rotate;
+pub mod catalogue;
+pub mod connectivity;
+pub mod mass;
+pub mod atmos;
+pub mod rooms;
+pub mod voxel_mesh;
+pub mod hull_collider;
 
 pub mod prelude {
     pub use super::common::*;
     pub use super::save_load::*;
+    pub use super::save_slots::*;
+    pub use super::blueprint::*;
     pub use super::instance_rotate::*;
+    pub use super::catalogue::*;
+    pub use super::connectivity::*;
+    pub use super::mass::*;
+    pub use super::atmos::*;
+    pub use super::rooms::*;
+    pub use super::voxel_mesh::*;
+    pub use super::hull_collider::*;
     pub use super::*;
 }
 
-#[derive(Clone, Reflect, Serialize, Deserialize)]
+/// Material of a raw voxel cell, cells of one material are merged into one mesh
+#[derive(Clone, Copy, Reflect, Serialize, Deserialize, PartialEq, Eq, Hash, Debug, Default)]
 pub enum ShipBlock {
-    None
+    #[default]
+    HullSteel,
+    Glass,
+    Armor
+}
+
+impl ShipBlock {
+    pub const ALL : [ShipBlock; 3] = [ShipBlock::HullSteel, ShipBlock::Glass, ShipBlock::Armor];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShipBlock::HullSteel => "Hull steel",
+            ShipBlock::Glass => "Glass",
+            ShipBlock::Armor => "Armor",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match self {
+            ShipBlock::HullSteel => Color::rgb(0.55, 0.57, 0.6),
+            ShipBlock::Glass => Color::rgba(0.6, 0.8, 0.9, 0.3),
+            ShipBlock::Armor => Color::rgb(0.3, 0.32, 0.28),
+        }
+    }
+
+    /// Density of a filled cell in kg/m³. Hull cells are solid plates, unlike the hollow instance blocks
+    pub fn density(&self) -> f64 {
+        match self {
+            ShipBlock::HullSteel => 1600.0,
+            ShipBlock::Glass => 2500.0,
+            ShipBlock::Armor => 7800.0,
+        }
+    }
 }
 
 pub const VOXEL_SIZE : f64 = 0.25;
+pub const SHIP_CHUNK_SIZE : IVec3 = IVec3::new(16, 16, 16);
+/// Corner of cell zero. It is the corner of the old fixed 100³ ship, so old saves keep their indices
+pub const SHIP_FIRST_VOXEL_POS : DVec3 = DVec3::new(-12.5, -12.5, -12.5);
+
+pub type ShipMap = ObjectedMap<ChunkedVoxelMap<VoxelVal<ShipBlock>>>;
 
 #[derive(Component, Clone)]
 pub struct Ship {
-    pub map : SolidVoxelMap<VoxelVal<ShipBlock>>
+    pub map : ShipMap
 }
 
 impl Default for Ship {
     fn default() -> Self {
-        Ship::new_sized([100,100,100].into())
+        Ship::new()
     }
 }
 
 
 
 pub fn new_default_ship(cmds : &mut Commands) -> Entity {
-    cmds.spawn(Ship::new_sized(IVec3::new(100, 100, 100)))
+    cmds.spawn(Ship::new())
         .insert(DSpatialBundle::from_transform(DTransform::from_xyz(0.0, 0.0, 0.0)))
         .insert(RigidBody::Static)
         .insert(GravityScale(0.0))
+        .insert(VoxelLod::default())
         .insert(Name::new("Ship"))
         .id()
 }
 
 impl Ship {
-    pub fn new_sized(size : IVec3) -> Self {
-        let map = SolidVoxelMap::new(DVec3::ZERO, size, VOXEL_SIZE);
+    pub fn new() -> Self {
         Self {
-            map
+            map : ObjectedMap::new(Ship::empty_map())
         }
     }
 
+    /// Map with the ship cell layout, ship saves use it too
+    pub fn empty_map<T : Default + Clone>() -> ChunkedVoxelMap<T> {
+        let mut map = ChunkedVoxelMap::new(VOXEL_SIZE, SHIP_CHUNK_SIZE);
+        map.first_voxel_pos = SHIP_FIRST_VOXEL_POS;
+        map
+    }
+
     pub fn get_grid_idx_by_center(&self, pos : &DVec3, bbox : &IVec3) -> IVec3 {
-        let dp = bbox.as_dvec3() / 2.0 * self.map.voxel_size;
+        // snapped corners of rotated blocks may land a hair below the grid line
+        let dp = bbox.as_dvec3() / 2.0 * self.map.voxel_size - self.map.voxel_size / 2.0;
         self.map.get_grid_idx(&(*pos - dp))
     }
 }
 
+/// Frees the chunks emptied by erasing, without marking the ship changed again
+pub fn prune_ship_chunks(
+    mut ships : Query<&mut Ship, Changed<Ship>>
+) {
+    for mut ship in ships.iter_mut() {
+        ship.bypass_change_detection().map.prune_empty();
+    }
+}
+
+/// Forgets the dirty chunks once meshes and colliders were rebuilt
+pub fn clear_dirty_chunks(
+    mut ships : Query<&mut Ship, Changed<Ship>>
+) {
+    for mut ship in ships.iter_mut() {
+        ship.bypass_change_detection().map.clear_dirty();
+    }
+}
+
+/// Cells occupied by a placed instance inside its ship map
+#[derive(Component, Clone, Copy, Reflect, Default)]
+#[reflect(Component)]
+pub struct InstanceGridPos {
+    pub idx : IVec3,
+    pub bbox : IVec3
+}
+
 #[derive(Serialize, Deserialize, Clone, Hash, PartialEq, Eq, Reflect)]
 pub struct InstanceId {
     pub template_id : u32,
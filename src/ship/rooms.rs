@@ -0,0 +1,552 @@
+use bevy::{prelude::*, utils::{HashMap, HashSet}};
+
+use crate::objects::door::Door;
+use crate::space_voxel::{VoxelMap, objected_voxel_map::VoxelVal};
+
+use super::{Ship, ShipBlock, ShipMap, InstanceGridPos, atmos::Airtight};
+
+const NEIGHBOURS : [IVec3; 6] = [
+    IVec3::X, IVec3::NEG_X,
+    IVec3::Y, IVec3::NEG_Y,
+    IVec3::Z, IVec3::NEG_Z
+];
+
+/// How a voxel cell splits the ship into rooms
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoomCell {
+    Open,
+    Wall,
+    Door {
+        id : Entity,
+        open : bool
+    }
+}
+
+/// Connected open cells. `exterior` rooms touch the border of the scanned region, so they are open to space
+#[derive(Clone, Debug)]
+pub struct Room {
+    /// Survives rebuilds while the room keeps most of its cells
+    pub id : u32,
+    pub cells : Vec<IVec3>,
+    pub exterior : bool,
+    /// Indices into [`RoomGraph::doors`]
+    pub doors : Vec<usize>
+}
+
+impl Room {
+    /// m³
+    pub fn volume(&self, voxel_size : f64) -> f64 {
+        self.cells.len() as f64 * voxel_size.powi(3)
+    }
+
+    pub fn bounds(&self) -> (IVec3, IVec3) {
+        self.cells.iter().fold((IVec3::MAX, IVec3::MIN), |(min, max), idx| (min.min(*idx), max.max(*idx)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RoomDoor {
+    pub door : Entity,
+    pub open : bool,
+    pub cells : Vec<IVec3>,
+    /// Room indices touching the door, sorted
+    pub rooms : Vec<usize>
+}
+
+/// Rooms of a voxel map and the doors between them
+#[derive(Clone, Default)]
+pub struct RoomGraph {
+    pub rooms : Vec<Room>,
+    pub doors : Vec<RoomDoor>,
+    /// Scanned region of the map
+    pub from : IVec3,
+    pub size : IVec3,
+    pub voxel_size : f64,
+    /// `(old room, new room, share of the old room cells)` against the graph this one was built over
+    pub remap : Vec<(usize, usize, f64)>,
+    /// Increased by every build
+    pub generation : u32,
+    next_id : u32,
+    /// Room index of every cell, `u32::MAX` for walls and doors
+    cell_room : Vec<u32>
+}
+
+impl RoomGraph {
+    fn linear(&self, idx : IVec3) -> Option<usize> {
+        let idx = idx - self.from;
+        if idx.cmpge(IVec3::ZERO).all() && idx.cmplt(self.size).all() {
+            Some(((idx.z * self.size.y + idx.y) * self.size.x + idx.x) as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn room_at(&self, idx : IVec3) -> Option<usize> {
+        self.linear(idx)
+            .and_then(|i| self.cell_room.get(i))
+            .filter(|room| **room != u32::MAX)
+            .map(|room| *room as usize)
+    }
+
+    /// Room of the first given cell or its neighbour which is in a room, for instances standing in it
+    pub fn room_touching(&self, cells : impl Iterator<Item = IVec3>) -> Option<usize> {
+        cells.flat_map(|cell| std::iter::once(cell).chain(NEIGHBOURS.iter().map(move |dir| cell + *dir)))
+            .find_map(|cell| self.room_at(cell))
+    }
+
+    pub fn room_by_id(&self, id : u32) -> Option<usize> {
+        self.rooms.iter().position(|room| room.id == id)
+    }
+
+    /// Rooms reachable from `room` through one door, with the door index
+    pub fn neighbours(&self, room : usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.rooms[room].doors.iter()
+            .flat_map(move |door| self.doors[*door].rooms.iter()
+                .filter(move |other| **other != room)
+                .map(move |other| (*door, *other)))
+    }
+
+    pub fn set_door_open(&mut self, door : Entity, open : bool) {
+        for room_door in self.doors.iter_mut().filter(|room_door| room_door.door == door) {
+            room_door.open = open;
+        }
+    }
+
+    fn empty(voxel_size : f64, from : IVec3, size : IVec3, prev : Option<&RoomGraph>) -> RoomGraph {
+        RoomGraph {
+            from,
+            size,
+            voxel_size,
+            cell_room : vec![u32::MAX; (size.x * size.y * size.z) as usize],
+            generation : prev.map(|prev| prev.generation + 1).unwrap_or(0),
+            next_id : prev.map(|prev| prev.next_id).unwrap_or(0),
+            ..default()
+        }
+    }
+
+    /// Flood fills the open cells of `map` inside `size` cells from `from`. Ids and `remap` are taken from the overlap with `prev`
+    pub fn build<T, M, F>(map : &M, from : IVec3, size : IVec3, kind : F, prev : Option<&RoomGraph>) -> RoomGraph
+            where T : Clone, M : VoxelMap<VoxelVal<T>>, F : Fn(&VoxelVal<T>) -> RoomCell {
+        let mut graph = RoomGraph::empty(map.get_voxel_size(), from, size, prev);
+
+        let mut doors : HashMap<Entity, RoomDoor> = HashMap::new();
+        for z in from.z..(from.z + size.z) {
+            for y in from.y..(from.y + size.y) {
+                for x in from.x..(from.x + size.x) {
+                    let start = IVec3::new(x, y, z);
+                    match kind(map.get_by_idx(&start)) {
+                        RoomCell::Wall => {},
+                        RoomCell::Door { id, open } => add_door_cell(&mut doors, id, open, start),
+                        RoomCell::Open => graph.flood(map, start, &kind)
+                    }
+                }
+            }
+        }
+
+        graph.link_doors(doors);
+        graph.assign_ids(prev, &[]);
+        graph
+    }
+
+    /// Same rooms as a new [`RoomGraph::build`] over the changed `map`, when only the cells inside `dirty` boxes
+    /// (min inclusive, max exclusive) changed. Only the rooms touching the boxes are flooded again
+    pub fn update<T, M, F>(&self, map : &M, dirty : &[(IVec3, IVec3)], kind : F) -> RoomGraph
+            where T : Clone, M : VoxelMap<VoxelVal<T>>, F : Fn(&VoxelVal<T>) -> RoomCell {
+        let in_dirty = |idx : &IVec3| dirty.iter().any(|(min, max)| idx.cmpge(*min).all() && idx.cmplt(*max).all());
+        let mut graph = RoomGraph::empty(self.voxel_size, self.from, self.size, Some(self));
+
+        // rooms next to a changed cell may merge or split, the rest keep their cells
+        let mut affected = HashSet::new();
+        for (min, max) in dirty {
+            for_cells(*min - IVec3::ONE, *max + IVec3::ONE, |idx| {
+                affected.extend(self.room_at(idx));
+            });
+        }
+        let mut kept = vec![];
+        for (old_idx, room) in self.rooms.iter().enumerate().filter(|(old_idx, _)| !affected.contains(old_idx)) {
+            let new_idx = graph.rooms.len();
+            for cell in &room.cells {
+                let linear = graph.linear(*cell).unwrap();
+                graph.cell_room[linear] = new_idx as u32;
+            }
+            graph.rooms.push(Room {
+                doors : vec![],
+                ..room.clone()
+            });
+            kept.push((old_idx, new_idx));
+        }
+
+        let mut doors : HashMap<Entity, RoomDoor> = HashMap::new();
+        for door in &self.doors {
+            for cell in door.cells.iter().filter(|cell| !in_dirty(cell)) {
+                add_door_cell(&mut doors, door.door, door.open, *cell);
+            }
+        }
+        for (min, max) in dirty {
+            for_cells((*min).max(self.from), (*max).min(self.from + self.size), |idx| {
+                match kind(map.get_by_idx(&idx)) {
+                    RoomCell::Wall => {},
+                    RoomCell::Door { id, open } => add_door_cell(&mut doors, id, open, idx),
+                    RoomCell::Open => graph.flood(map, idx, &kind)
+                }
+            });
+        }
+        for room in affected {
+            for cell in &self.rooms[room].cells {
+                if kind(map.get_by_idx(cell)) == RoomCell::Open {
+                    graph.flood(map, *cell, &kind);
+                }
+            }
+        }
+
+        graph.link_doors(doors);
+        graph.assign_ids(Some(self), &kept);
+        graph
+    }
+
+    /// New room of the open cells connected to `start`, nothing is done if `start` is already in a room
+    fn flood<T, M, F>(&mut self, map : &M, start : IVec3, kind : &F)
+            where T : Clone, M : VoxelMap<VoxelVal<T>>, F : Fn(&VoxelVal<T>) -> RoomCell {
+        let Some(start_linear) = self.linear(start) else {
+            return;
+        };
+        if self.cell_room[start_linear] != u32::MAX {
+            return;
+        }
+
+        let room_idx = self.rooms.len() as u32;
+        self.cell_room[start_linear] = room_idx;
+        let mut stack = vec![start];
+        let mut room = Room {
+            id : 0,
+            cells : vec![],
+            exterior : false,
+            doors : vec![]
+        };
+        while let Some(idx) = stack.pop() {
+            room.cells.push(idx);
+            for dir in NEIGHBOURS {
+                let next = idx + dir;
+                let Some(next_linear) = self.linear(next) else {
+                    room.exterior = true;
+                    continue;
+                };
+                if self.cell_room[next_linear] == u32::MAX && kind(map.get_by_idx(&next)) == RoomCell::Open {
+                    self.cell_room[next_linear] = room_idx;
+                    stack.push(next);
+                }
+            }
+        }
+        self.rooms.push(room);
+    }
+
+    fn link_doors(&mut self, doors : HashMap<Entity, RoomDoor>) {
+        let mut doors : Vec<RoomDoor> = doors.into_values().collect();
+        doors.sort_by_key(|door| door.door);
+        for (door_idx, door) in doors.iter_mut().enumerate() {
+            let mut rooms = HashSet::new();
+            for cell in &door.cells {
+                for dir in NEIGHBOURS {
+                    if let Some(room) = self.room_at(*cell + dir) {
+                        rooms.insert(room);
+                    }
+                }
+            }
+            door.rooms = rooms.into_iter().collect();
+            door.rooms.sort();
+            for room in &door.rooms {
+                self.rooms[*room].doors.push(door_idx);
+            }
+        }
+        self.doors = doors;
+    }
+
+    /// A room keeps the id of the old room it got most cells from, the biggest claim wins.
+    /// `kept` rooms are `(old, new)` pairs with the same cells, they are not counted again
+    fn assign_ids(&mut self, prev : Option<&RoomGraph>, kept : &[(usize, usize)]) {
+        let mut overlap : HashMap<(usize, usize), usize> = HashMap::new();
+        let mut taken_old = HashSet::new();
+        let mut named = vec![false; self.rooms.len()];
+        if let Some(prev) = prev {
+            for (old_idx, new_idx) in kept {
+                self.rooms[*new_idx].id = prev.rooms[*old_idx].id;
+                named[*new_idx] = true;
+                taken_old.insert(*old_idx);
+            }
+            for (old_idx, old_room) in prev.rooms.iter().enumerate().filter(|(old_idx, _)| !taken_old.contains(old_idx)) {
+                for cell in &old_room.cells {
+                    if let Some(new_idx) = self.room_at(*cell) {
+                        *overlap.entry((old_idx, new_idx)).or_insert(0) += 1;
+                    }
+                }
+            }
+            self.remap = overlap.iter()
+                .map(|((old_idx, new_idx), count)| (*old_idx, *new_idx, *count as f64 / prev.rooms[*old_idx].cells.len() as f64))
+                .chain(kept.iter().map(|(old_idx, new_idx)| (*old_idx, *new_idx, 1.0)))
+                .collect();
+            self.remap.sort_by_key(|(old_idx, new_idx, _)| (*old_idx, *new_idx));
+        }
+
+        let mut claims : Vec<((usize, usize), usize)> = overlap.into_iter().collect();
+        claims.sort_by_key(|((old_idx, new_idx), count)| (std::cmp::Reverse(*count), *old_idx, *new_idx));
+        for ((old_idx, new_idx), _) in claims {
+            if named[new_idx] || taken_old.contains(&old_idx) {
+                continue;
+            }
+            self.rooms[new_idx].id = prev.unwrap().rooms[old_idx].id;
+            named[new_idx] = true;
+            taken_old.insert(old_idx);
+        }
+        for (room, named) in self.rooms.iter_mut().zip(named) {
+            if !named {
+                room.id = self.next_id;
+                self.next_id += 1;
+            }
+        }
+    }
+}
+
+fn add_door_cell(doors : &mut HashMap<Entity, RoomDoor>, id : Entity, open : bool, idx : IVec3) {
+    doors.entry(id)
+        .or_insert(RoomDoor { door : id, open, cells : vec![], rooms : vec![] })
+        .cells.push(idx);
+}
+
+fn for_cells(min : IVec3, max : IVec3, mut f : impl FnMut(IVec3)) {
+    for z in min.z..max.z {
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                f(IVec3::new(x, y, z));
+            }
+        }
+    }
+}
+
+/// Room graph of a ship with the player given names
+#[derive(Component, Clone, Default)]
+pub struct ShipRooms {
+    pub graph : RoomGraph,
+    pub names : HashMap<u32, String>
+}
+
+impl ShipRooms {
+    pub fn name(&self, room : usize) -> String {
+        let id = self.graph.rooms[room].id;
+        self.names.get(&id).cloned().unwrap_or_else(|| format!("Room {}", id))
+    }
+
+    /// Names bound to a cell of their room, so they can be saved with the ship
+    pub fn to_room_names(&self) -> RoomNames {
+        let names = self.graph.rooms.iter()
+            .filter_map(|room| Some(RoomName {
+                anchor : *room.cells.first()?,
+                name : self.names.get(&room.id)?.clone()
+            }))
+            .collect();
+        RoomNames {
+            names
+        }
+    }
+}
+
+/// Saved room names, applied to the rooms containing the anchors on the first build
+#[derive(Component, Reflect, Default, Clone)]
+#[reflect(Component)]
+pub struct RoomNames {
+    pub names : Vec<RoomName>
+}
+
+#[derive(Reflect, Default, Clone)]
+pub struct RoomName {
+    pub anchor : IVec3,
+    pub name : String
+}
+
+/// Allocated chunks of the ship with one cell of space around, so the outside is one exterior room
+pub fn ship_room_region(map : &ShipMap) -> (IVec3, IVec3) {
+    map.chunk_bounds()
+        .map(|(min, max)| (min - IVec3::ONE, max - min + IVec3::splat(2)))
+        .unwrap_or((IVec3::ZERO, IVec3::ZERO))
+}
+
+pub fn ship_cell_kind(val : &VoxelVal<ShipBlock>, airtight : &Query<(), With<Airtight>>, doors : &Query<&Door>) -> RoomCell {
+    match val {
+        VoxelVal::None => RoomCell::Open,
+        VoxelVal::Voxel(_) => RoomCell::Wall,
+        VoxelVal::Object(e) => {
+            if let Ok(door) = doors.get(*e) {
+                RoomCell::Door {
+                    id : *e,
+                    open : door.is_open
+                }
+            } else if airtight.contains(*e) {
+                RoomCell::Wall
+            } else {
+                RoomCell::Open
+            }
+        }
+    }
+}
+
+/// Cell boxes of the ship chunks changed since the last frame, with the footprints of instances
+/// which became walls or doors after they were placed
+fn dirty_boxes(ship : &Ship, new_blocks : &[InstanceGridPos]) -> Vec<(IVec3, IVec3)> {
+    let chunk_size = ship.map.chunk_size;
+    ship.map.dirty_set.iter()
+        .map(|origin| (*origin, *origin + chunk_size))
+        .chain(new_blocks.iter().map(|grid| (grid.idx, grid.idx + grid.bbox)))
+        .collect()
+}
+
+/// Updates the room graph of changed ships and follows the door states. Only the rooms around
+/// the dirty chunks are flooded again, a full build is done when the ship grows or shrinks
+pub fn update_ship_rooms(
+    mut cmds : Commands,
+    mut ships : Query<(Entity, Ref<Ship>, Option<&mut ShipRooms>, Option<&RoomNames>)>,
+    airtight : Query<(), With<Airtight>>,
+    doors : Query<&Door>,
+    changed_doors : Query<(Entity, &Door), Changed<Door>>,
+    new_blocks : Query<(&Parent, &InstanceGridPos), Or<(Added<Airtight>, Added<Door>)>>
+) {
+    for (ship_e, ship, rooms, saved_names) in ships.iter_mut() {
+        let Some(mut rooms) = rooms else {
+            let (from, size) = ship_room_region(&ship.map);
+            let mut rooms = ShipRooms {
+                graph : RoomGraph::build(&ship.map, from, size, |val| ship_cell_kind(val, &airtight, &doors), None),
+                names : HashMap::new()
+            };
+            for saved in saved_names.iter().flat_map(|saved| saved.names.iter()) {
+                if let Some(room) = rooms.graph.room_at(saved.anchor) {
+                    let id = rooms.graph.rooms[room].id;
+                    rooms.names.insert(id, saved.name.clone());
+                }
+            }
+            cmds.entity(ship_e).insert(rooms);
+            continue;
+        };
+        let placed : Vec<InstanceGridPos> = new_blocks.iter()
+            .filter(|(parent, _)| parent.get() == ship_e)
+            .map(|(_, grid)| *grid)
+            .collect();
+        if ship.is_changed() || !placed.is_empty() {
+            let dirty = dirty_boxes(&ship, &placed);
+            let (from, size) = ship_room_region(&ship.map);
+            let kind = |val : &VoxelVal<ShipBlock>| ship_cell_kind(val, &airtight, &doors);
+            // without dirty chunks the edit was cleared before this system saw it
+            if from != rooms.graph.from || size != rooms.graph.size || dirty.is_empty() {
+                rooms.graph = RoomGraph::build(&ship.map, from, size, kind, Some(&rooms.graph));
+            } else {
+                rooms.graph = rooms.graph.update(&ship.map, &dirty, kind);
+            }
+        }
+        for (door_e, door) in changed_doors.iter() {
+            if rooms.graph.doors.iter().any(|room_door| room_door.door == door_e && room_door.open != door.is_open) {
+                rooms.graph.set_door_open(door_e, door.is_open);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bevy::math::DVec3;
+
+    use crate::space_voxel::solid_voxel_map::SolidVoxelMap;
+
+    use super::*;
+
+    /// Closed box split by a wall at x = 5 with a door, rooms are x 1..=4 and 6..=9
+    pub fn two_rooms() -> SolidVoxelMap<VoxelVal<i32>> {
+        let mut map = SolidVoxelMap::<VoxelVal<i32>>::new(DVec3::ZERO, IVec3::new(11, 5, 5), 1.0);
+        for z in 0..5 {
+            for y in 0..5 {
+                for x in 0..11 {
+                    let border = x == 0 || x == 10 || y == 0 || y == 4 || z == 0 || z == 4 || x == 5;
+                    if border {
+                        map.set_voxel_by_idx(&IVec3::new(x, y, z), VoxelVal::Voxel(0));
+                    }
+                }
+            }
+        }
+        map.set_voxel_by_idx(&IVec3::new(5, 2, 2), VoxelVal::Object(Entity::from_raw(7)));
+        map
+    }
+
+    pub fn kind(open : bool) -> impl Fn(&VoxelVal<i32>) -> RoomCell {
+        move |val| match val {
+            VoxelVal::None => RoomCell::Open,
+            VoxelVal::Voxel(_) => RoomCell::Wall,
+            VoxelVal::Object(e) => RoomCell::Door { id : *e, open }
+        }
+    }
+
+    #[test]
+    fn rooms_doors_and_ids() {
+        let mut map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+
+        assert_eq!(graph.rooms.len(), 2);
+        assert!(graph.rooms.iter().all(|room| !room.exterior && room.cells.len() == 36));
+        assert_eq!(graph.doors.len(), 1);
+        assert_eq!(graph.doors[0].rooms, vec![0, 1]);
+        assert_eq!(graph.neighbours(0).collect::<Vec<_>>(), vec![(0, 1)]);
+        let right_id = graph.rooms[graph.room_at(IVec3::new(8, 2, 2)).unwrap()].id;
+
+        // a new wall in the left room keeps the ids of the big parts
+        for z in 1..4 {
+            for y in 1..4 {
+                map.set_voxel_by_idx(&IVec3::new(2, y, z), VoxelVal::Voxel(0));
+            }
+        }
+        let rebuilt = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), Some(&graph));
+        assert_eq!(rebuilt.rooms[rebuilt.room_at(IVec3::new(8, 2, 2)).unwrap()].id, right_id);
+        // the cut off strip is a sealed room of its own, without a door
+        let small = rebuilt.room_at(IVec3::new(1, 2, 2)).unwrap();
+        let big = rebuilt.room_at(IVec3::new(3, 2, 2)).unwrap();
+        assert_ne!(small, big);
+        assert_eq!(rebuilt.rooms[small].cells.len(), 9);
+        assert!(!rebuilt.rooms[small].exterior && rebuilt.rooms[small].doors.is_empty());
+        assert!(graph.rooms.iter().all(|room| room.id != rebuilt.rooms[small].id));
+        assert_eq!(rebuilt.generation, 1);
+    }
+
+    /// Rooms as sorted cells with the id and the exterior flag, independent of the room order
+    fn layout(graph : &RoomGraph) -> Vec<(Vec<IVec3>, u32, bool)> {
+        let mut rooms : Vec<_> = graph.rooms.iter()
+            .map(|room| {
+                let mut cells = room.cells.clone();
+                cells.sort_by_key(|idx| (idx.z, idx.y, idx.x));
+                (cells, room.id, room.exterior)
+            })
+            .collect();
+        rooms.sort_by_key(|(cells, _, _)| (cells[0].z, cells[0].y, cells[0].x));
+        rooms
+    }
+
+    #[test]
+    fn update_matches_full_build() {
+        let mut map = two_rooms();
+        let graph = RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), None);
+
+        // a hull breach unseals the left room only
+        map.set_voxel_by_idx(&IVec3::new(0, 2, 2), VoxelVal::None);
+        let dirty = [(IVec3::new(0, 2, 2), IVec3::new(1, 3, 3))];
+        let updated = graph.update(&map, &dirty, kind(false));
+        assert_eq!(layout(&updated), layout(&RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), Some(&graph))));
+        assert!(updated.rooms[updated.room_at(IVec3::new(1, 2, 2)).unwrap()].exterior);
+        assert!(!updated.rooms[updated.room_at(IVec3::new(8, 2, 2)).unwrap()].exterior);
+        assert_eq!(updated.doors[0].rooms.len(), 2);
+
+        // removing the wall with the door merges both rooms
+        for z in 1..4 {
+            for y in 1..4 {
+                map.set_voxel_by_idx(&IVec3::new(5, y, z), VoxelVal::None);
+            }
+        }
+        let dirty = [(IVec3::new(5, 1, 1), IVec3::new(6, 4, 4))];
+        let merged = updated.update(&map, &dirty, kind(false));
+        assert_eq!(layout(&merged), layout(&RoomGraph::build(&map, IVec3::ZERO, map.size, kind(false), Some(&updated))));
+        assert_eq!(merged.room_at(IVec3::new(1, 2, 2)), merged.room_at(IVec3::new(8, 2, 2)));
+        assert!(merged.doors.is_empty());
+    }
+}
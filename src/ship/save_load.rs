@@ -4,13 +4,19 @@ use std::io::{Read, Write};
 use bevy::ecs::system::EntityCommands;
 use bevy::ecs::world::{EntityRef, EntityMut};
 use bevy::math::DVec3;
+use bevy::render::view::screenshot::ScreenshotManager;
+use bevy::window::PrimaryWindow;
 use bevy::scene::serde::SceneDeserializer;
 use bevy::{prelude::*, utils::HashMap};
 use egui_notify::Toast;
 use serde::de::DeserializeSeed;
 
 use crate::network::{NetworkSplitter, MessageChannel};
+use crate::objects::door::Door;
+use crate::objects::ship_systems::FuelTank;
 use crate::scenes::ToastHolder;
+use crate::space_voxel::{solid_voxel_map::SolidVoxelMap, chunked_voxel_map::ChunkedVoxelMap};
+use crate::space_voxel::lod::{VoxelLodSettings, select_voxel_lod};
 
 use super::prelude::*;
 
@@ -27,19 +33,103 @@ pub enum DiskShipVoxel {
 
 
 
+/// Layout of [`DiskShip`] written by this version
+pub const DISK_SHIP_VERSION : u32 = 1;
+
 #[derive(Reflect, Component, Default)]
 #[reflect(Component)]
 pub struct DiskShipBase64 {
-    pub data : String
+    pub data : String,
+    /// Saves without it hold the fixed size map of version 0
+    #[reflect(default)]
+    pub version : u32
+}
+
+impl DiskShipBase64 {
+    pub fn new(disk_ship : &DiskShip) -> DiskShipBase64 {
+        DiskShipBase64 {
+            data : disk_ship.to_base64(),
+            version : DISK_SHIP_VERSION
+        }
+    }
+
+    pub fn disk_ship(&self) -> Result<DiskShip, String> {
+        DiskShip::from_base64(&self.data, self.version)
+    }
 }
 
+/// Reads a ship scene file into a world of its own
+pub fn read_ship_scene(path : &str, type_registry : &AppTypeRegistry) -> Result<World, String> {
+    let mut scene_ron = vec![];
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut scene_ron))
+        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+
+    let mut des = ron::Deserializer::from_bytes(&scene_ron)
+        .map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+    let result = SceneDeserializer {
+        type_registry : &type_registry.read()
+    }.deserialize(&mut des)
+        .map_err(|err| format!("Failed to parse {}: {}", path, err))?;
+
+    Scene::from_dynamic_scene(&result, type_registry)
+        .map(|scene| scene.world)
+        .map_err(|err| format!("Failed to build scene from {}: {}", path, err))
+}
+
+/// Types needed to read and write ship scenes outside of the game app.
+/// Every component cloned by `setup_base_save_load_cfg` has to be here
+pub fn register_save_types(registry : &AppTypeRegistry) {
+    let mut registry = registry.write();
+    registry.register::<DiskShipBase64>();
+    registry.register::<RoomNames>();
+    registry.register::<RoomName>();
+    registry.register::<InstanceRotate>();
+    registry.register::<DTransform>();
+    registry.register::<Door>();
+    registry.register::<FuelTank>();
+}
+
+/// Only the chunks holding blocks are stored
 #[derive(Serialize, Deserialize)]
 pub struct DiskShip {
-    pub map : SolidVoxelMap<DiskShipVoxel>,
+    pub map : ChunkedVoxelMap<DiskShipVoxel>,
     pub template_names : HashMap<u32, String>,
     pub states : HashMap<u32, Entity>
 }
 
+/// Version 0 saves, made when ships were a fixed 100³ map
+#[derive(Deserialize)]
+struct LegacyDiskShip {
+    map : SolidVoxelMap<DiskShipVoxel>,
+    template_names : HashMap<u32, String>,
+    states : HashMap<u32, Entity>
+}
+
+impl From<LegacyDiskShip> for DiskShip {
+    fn from(legacy : LegacyDiskShip) -> Self {
+        // the old map was centered on the ship origin just like the chunked ship layout
+        let mut map = ChunkedVoxelMap::new(legacy.map.voxel_size, SHIP_CHUNK_SIZE);
+        map.first_voxel_pos = legacy.map.first_voxel_pos;
+        for z in 0..legacy.map.size.z {
+            for y in 0..legacy.map.size.y {
+                for x in 0..legacy.map.size.x {
+                    let idx = IVec3::new(x, y, z);
+                    let v = legacy.map.get_by_idx(&idx);
+                    if !matches!(v, DiskShipVoxel::None) {
+                        map.set_voxel_by_idx(&idx, v.clone());
+                    }
+                }
+            }
+        }
+        DiskShip {
+            map,
+            template_names : legacy.template_names,
+            states : legacy.states
+        }
+    }
+}
+
 impl DiskShip {
     pub fn from_ship(ship_id : Entity, world : &World, remap : &HashMap<Entity, Entity>) -> DiskShip {
         let all_instances = world.resource::<AllVoxelInstances>();
@@ -51,43 +141,42 @@ impl DiskShip {
 
         let ship : &Ship = world.entity(ship_id).get().unwrap();
 
-        let mut map = SolidVoxelMap::<DiskShipVoxel>::new(DVec3::ZERO, ship.map.size, ship.map.voxel_size);
+        let mut map = ChunkedVoxelMap::<DiskShipVoxel>::new(ship.map.voxel_size, ship.map.chunk_size);
+        map.first_voxel_pos = ship.map.first_voxel_pos;
         
         let mut entity_id : HashMap<Entity, u32> = HashMap::new();
         let mut id_indexer = 0;
 
         let mut states : HashMap<u32, Entity> = HashMap::new();
 
-        for z in 0..map.size.z {
-            for y in 0..map.size.y {
-                for x in 0..map.size.x {
-
-                    let idx = IVec3::new(x, y, z);
-                    let v = ship.map.get_by_idx(&idx);
-
-                    let disk_v =
-                    match v {
-                        VoxelVal::None => DiskShipVoxel::None,
-                        VoxelVal::Voxel(block) => DiskShipVoxel::Voxel(block.clone()),
-                        VoxelVal::Object(e) => {
-                            let template_id = world.entity(*e)
-                                .get::<VoxelInstance>().unwrap()
-                                .common_id;
-
-                            if let Some(state_id) = entity_id.get(e) {
-                                DiskShipVoxel::Instance(InstanceId {template_id, state_id : *state_id })
-                            } else {
-                                entity_id.insert(*e, id_indexer);
-                                let val = DiskShipVoxel::Instance(InstanceId {template_id, state_id : id_indexer });
-                                states.insert(id_indexer, *remap.get(e).unwrap());
-                                id_indexer += 1;
-                                val
-                            }
-                        },
-                    };
-                    map.set_voxel_by_idx(&idx, disk_v);
-                }
-            }
+        // sorted, so state ids do not depend on the hash map order
+        let mut cells : Vec<(IVec3, &VoxelVal<ShipBlock>)> = ship.map.iter()
+            .filter(|(_, v)| !matches!(v, VoxelVal::None))
+            .collect();
+        cells.sort_by_key(|(idx, _)| (idx.z, idx.y, idx.x));
+
+        for (idx, v) in cells {
+            let disk_v =
+            match v {
+                VoxelVal::None => DiskShipVoxel::None,
+                VoxelVal::Voxel(block) => DiskShipVoxel::Voxel(*block),
+                VoxelVal::Object(e) => {
+                    let template_id = world.entity(*e)
+                        .get::<VoxelInstance>().unwrap()
+                        .common_id;
+
+                    if let Some(state_id) = entity_id.get(e) {
+                        DiskShipVoxel::Instance(InstanceId {template_id, state_id : *state_id })
+                    } else {
+                        entity_id.insert(*e, id_indexer);
+                        let val = DiskShipVoxel::Instance(InstanceId {template_id, state_id : id_indexer });
+                        states.insert(id_indexer, *remap.get(e).unwrap());
+                        id_indexer += 1;
+                        val
+                    }
+                },
+            };
+            map.set_voxel_by_idx(&idx, disk_v);
         }
 
         DiskShip {
@@ -97,6 +186,28 @@ impl DiskShip {
         }
     }
 
+    /// Occupied box of every saved instance as `(state_id, (template_id, from, to))`, sorted by state id
+    pub fn footprints(&self) -> Vec<(u32, (u32, IVec3, IVec3))> {
+        let mut footprints : HashMap<u32, (u32, IVec3, IVec3)> = HashMap::new();
+        for (idx, v) in self.map.iter() {
+            if let DiskShipVoxel::Instance(id) = v {
+                let entry = footprints.entry(id.state_id).or_insert((id.template_id, idx, idx));
+                entry.1 = entry.1.min(idx);
+                entry.2 = entry.2.max(idx);
+            }
+        }
+        let mut footprints = footprints.into_iter().collect::<Vec<_>>();
+        footprints.sort_by_key(|(state_id, _)| *state_id);
+        footprints
+    }
+
+    pub fn block_count(&self) -> usize {
+        let voxels = self.map.iter()
+            .filter(|(_, v)| matches!(v, DiskShipVoxel::Voxel(_)))
+            .count();
+        self.states.len() + voxels
+    }
+
     pub fn to_base64(&self) -> String {
         let bytes =  bincode::serialize(&self).unwrap();
         let compressed_bytes = snap::raw::Encoder::new().compress_vec(&bytes).unwrap();
@@ -106,12 +217,21 @@ impl DiskShip {
         base64::encode(compressed_bytes)
     }
 
-    pub fn from_base64(text : &String) -> DiskShip {
-        let bytes = base64::decode(text).unwrap();
-        let decompressed_bytes = snap::raw::Decoder::new().decompress_vec(&bytes).unwrap();
-        let decompressed_bytes = snap::raw::Decoder::new().decompress_vec(&decompressed_bytes).unwrap();
-        let decompressed_bytes = snap::raw::Decoder::new().decompress_vec(&decompressed_bytes).unwrap();
-        bincode::deserialize(&decompressed_bytes).unwrap()
+    /// Reads the data of a [`DiskShipBase64`] written with layout `version`
+    pub fn from_base64(text : &String, version : u32) -> Result<DiskShip, String> {
+        let bytes = base64::decode(text).map_err(|err| format!("Broken ship data: {}", err))?;
+        let mut decoder = snap::raw::Decoder::new();
+        let mut decompressed_bytes = bytes;
+        for _ in 0..3 {
+            decompressed_bytes = decoder.decompress_vec(&decompressed_bytes)
+                .map_err(|err| format!("Broken ship data: {}", err))?;
+        }
+        let res = if version == 0 {
+            bincode::deserialize::<LegacyDiskShip>(&decompressed_bytes).map(DiskShip::from)
+        } else {
+            bincode::deserialize(&decompressed_bytes)
+        };
+        res.map_err(|err| format!("Unsupported ship data of version {}: {}", version, err))
     }
 }
 
@@ -200,18 +320,35 @@ impl Plugin for ShipPlugin {
         app.add_event::<CmdShipLoad>();
         app.add_event::<ShipLoaded>();
         app.add_event::<SpawnBlockCmd>();
+        app.add_event::<CmdShipSplit>();
+        app.add_event::<ShipSplitOff>();
 
         app.register_type::<InstanceRotate>();
+        app.register_type::<RoomNames>();
+        app.register_type::<RoomName>();
 
         app.insert_resource(ShipSaveQueue::default());
         app.insert_resource(SaveLoadCfg::default());
+        app.insert_resource(SaveSlotsCfg::default());
+        app.insert_resource(ShipBlockMaterials::default());
+        app.insert_resource(VoxelLodSettings::default());
 
         app.add_system(loading_ship_system);
         app.add_system(prepare_saving_ship_system);
         app.add_system(saving_ship_system);
         app.add_system(prepare_instance_rotate);
+        app.add_system(split_detached_parts);
+        app.add_system(prune_ship_chunks.after(split_detached_parts));
+        app.add_system(select_voxel_lod);
+        app.add_system(update_ship_chunk_meshes.after(prune_ship_chunks).after(select_voxel_lod));
+        app.add_system(update_ship_hull_colliders.after(prune_ship_chunks));
+        app.add_system(clear_dirty_chunks.after(update_ship_chunk_meshes).after(update_ship_hull_colliders));
+        app.add_system(update_ship_mass);
+        app.add_system(update_ship_rooms.after(prune_ship_chunks).before(clear_dirty_chunks));
+        app.add_system(update_ship_atmos.after(update_ship_rooms));
 
         app.add_startup_system(setup_base_save_load_cfg);
+        app.add_startup_system(setup_ship_block_materials);
 
         app.add_startup_system(network_setup);
     }
@@ -241,16 +378,21 @@ fn setup_base_save_load_cfg(
 ) {
     cfg.add_simple_clone::<DTransform>();
     cfg.add_simple_clone::<InstanceRotate>();
+    cfg.add_simple_clone::<Door>();
+    cfg.add_simple_clone::<FuelTank>();
 }
 
 fn saving_ship_system(
     world : &mut World
 ) {
     let queue = world.resource::<ShipSaveQueue>().0.clone();
+    let mut saved = vec![];
+    let mut failed = vec![];
     {
         world.resource_mut::<ShipSaveQueue>().0.clear();
 
         let cfg = world.resource::<SaveLoadCfg>();
+        let slots_cfg = world.resource::<SaveSlotsCfg>();
 
         for (ship, path) in &queue {
 
@@ -267,10 +409,15 @@ fn saving_ship_system(
 
 
             let disk_ship = DiskShip::from_ship(*ship, world, &map);
+            let block_count = disk_ship.block_count();
 
-            sub_world.spawn(DiskShipBase64 {
-                data: disk_ship.to_base64(),
-            });
+            let room_names = world.get::<ShipRooms>(*ship)
+                .map(|rooms| rooms.to_room_names())
+                .unwrap_or_default();
+            sub_world.spawn((
+                DiskShipBase64::new(&disk_ship),
+                room_names
+            ));
 
             {
                 let type_registry = world.resource::<AppTypeRegistry>().clone();
@@ -278,15 +425,47 @@ fn saving_ship_system(
 
                 let ron_scene = dynamic_scene.serialize_ron(&type_registry).unwrap();
 
-                File::create(path)
-                    .and_then(|mut file| file.write_all(ron_scene.as_bytes())).unwrap();
+                prepare_slot(path, slots_cfg);
+                let res = File::create(path)
+                    .and_then(|mut file| file.write_all(ron_scene.as_bytes()));
+                if let Err(err) = res {
+                    failed.push(format!("Failed to save ship to {}: {}", path, err));
+                    continue;
+                }
+            }
+
+            let ship_name = world.get::<Name>(*ship)
+                .map(|name| name.to_string())
+                .unwrap_or_else(|| "Ship".to_string());
+            // the thumbnail is set once the screenshot is on disk, see `save_thumbnail`
+            write_meta(path, &SaveSlotMeta {
+                name : slot_stem(path),
+                ship_name,
+                created : now_unix(),
+                block_count,
+                thumbnail : None,
+                kind : slot_kind(path)
+            });
+            saved.push(path.clone());
+        }
+    }
+
+    let window = world.query_filtered::<Entity, With<PrimaryWindow>>().iter(world).next();
+    if let (Some(window), Some(path)) = (window, saved.last().cloned()) {
+        if let Some(mut screenshots) = world.get_resource_mut::<ScreenshotManager>() {
+            if let Err(err) = screenshots.take_screenshot(window, move |img| save_thumbnail(&path, img)) {
+                warn!("Failed to take ship thumbnail: {}", err);
             }
         }
     }
 
-    for (_, path) in &queue {
+    for path in &saved {
         world.resource_mut::<ToastHolder>().toast.add(Toast::info(format!("Saved ship to {}", &path)));
     }
+    for msg in failed {
+        warn!("{}", &msg);
+        world.resource_mut::<ToastHolder>().toast.add(Toast::error(msg));
+    }
 }
 
 fn prepare_saving_ship_system(
@@ -309,38 +488,39 @@ fn loading_ship_system(
     mut toast : ResMut<ToastHolder>
 ) {
     for ship_path in load_ships.iter() {
-        let mut file = File::open(&ship_path.0).unwrap();
-        let mut scene_ron = vec![];
-        file.read_to_end(&mut scene_ron).unwrap();
-        let mut des = ron::Deserializer::from_bytes(&scene_ron).unwrap();
-
-        let result = SceneDeserializer {
-            type_registry : &type_registry.read()
-        }.deserialize(&mut des).unwrap();
-
-        let mut sub_world = Scene::from_dynamic_scene(&result, &type_registry).unwrap().world;
-
-        {
-            let data = sub_world.query::<&DiskShipBase64>().iter(&sub_world).next().unwrap();
-            let disk_ship = DiskShip::from_base64(&data.data);
+        let res = read_ship_scene(&ship_path.0, &type_registry).and_then(|mut sub_world| {
+            let (data, room_names) = sub_world.query::<(&DiskShipBase64, Option<&RoomNames>)>().iter(&sub_world).next()
+                .ok_or(format!("{} has no ship data", &ship_path.0))?;
+            let disk_ship = data.disk_ship()?;
+            let room_names = room_names.cloned().unwrap_or_default();
+            Ok((disk_ship, room_names, sub_world))
+        });
+        let (disk_ship, room_names, sub_world) = match res {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                warn!("Failed to load ship from {}: {}", &ship_path.0, err);
+                toast.toast.add(Toast::error(err));
+                continue;
+            }
+        };
 
-            let mut ship = Ship::new_sized(disk_ship.map.size);
-            let mut spawned : HashMap<u32, Entity> = HashMap::new();
+        let mut ship = Ship::new();
+        ship.map.set_first_voxel_pos(disk_ship.map.first_voxel_pos);
+        let mut spawned : HashMap<u32, Entity> = HashMap::new();
 
-            let ship_id = new_default_ship(&mut cmds);
-            cmds.entity(ship_id).insert(ship.clone());
+        let ship_id = new_default_ship(&mut cmds);
+        cmds.entity(ship_id).insert((ship.clone(), room_names));
 
-            instances_from_disk(disk_ship, &mut ship, &mut spawned, &all_instances, &mut cmds, &asset_server, &mut cfg, sub_world);
+        instances_from_disk(disk_ship, &mut ship, &mut spawned, &all_instances, &mut cmds, &asset_server, &mut cfg, sub_world);
 
-            for (_, e) in &spawned {
-                cmds.entity(ship_id).add_child(*e);
-            }
+        for (_, e) in &spawned {
+            cmds.entity(ship_id).add_child(*e);
+        }
 
-            cmds.entity(ship_id).insert(ship);
+        cmds.entity(ship_id).insert(ship);
 
-            loaded_ships.send(ShipLoaded(ship_id));
-            toast.toast.add(Toast::info(format!("Loaded ship from {}", &ship_path.0)));
-        }
+        loaded_ships.send(ShipLoaded(ship_id));
+        toast.toast.add(Toast::info(format!("Loaded ship from {}", &ship_path.0)));
     }
 }
 
@@ -354,42 +534,53 @@ fn instances_from_disk(
     cfg : &mut SaveLoadCfg,
     sub_world: World) {
 
-    for z in 0..disk_ship.map.size.z {
-        for y in 0..disk_ship.map.size.y {
-            for x in 0..disk_ship.map.size.x {
-                let idx = IVec3::new(x, y, z);
-                let disk_v = disk_ship.map.get_by_idx(&idx);
-                match disk_v {
-                    DiskShipVoxel::None => {
-                        ship.map.set_voxel_by_idx(&idx, VoxelVal::None);
-                    },
-                    DiskShipVoxel::Voxel(block) => {
-                        ship.map.set_voxel_by_idx(&idx, VoxelVal::Voxel(block.clone()))
-                    },
-                    DiskShipVoxel::Instance(id) => {
-                        if spawned.contains_key(&id.state_id) {
-                            ship.map.set_voxel_by_idx(&idx, VoxelVal::Object(*spawned.get(&id.state_id).unwrap()))
-                        } else {
-                            let name = disk_ship.template_names.get(&id.template_id).unwrap().clone();
-
-                            for inst in &all_instances.configs {
-                                if inst.name == name {
-                                    let spawn_e = inst.create.build(cmds, asset_server);
-                                    spawned.insert(id.state_id, spawn_e);
-
-                                    let state_e = Entity::from_raw(
-                                        disk_ship.states.get(&id.state_id).unwrap().index()
-                                    );
-
-                                    cfg.load.build(&mut cmds.entity(spawn_e), &mut sub_world.entity(state_e));
-
-                                    ship.map.set_voxel_by_idx(&idx, VoxelVal::Object(spawn_e))
-                                }
+    let footprints : HashMap<u32, (u32, IVec3, IVec3)> = disk_ship.footprints().into_iter().collect();
+
+    for (idx, disk_v) in disk_ship.map.iter() {
+        match disk_v {
+            DiskShipVoxel::None => {},
+            DiskShipVoxel::Voxel(block) => {
+                ship.map.set_voxel_by_idx(&idx, VoxelVal::Voxel(*block))
+            },
+            DiskShipVoxel::Instance(id) => {
+                // the first cell of an instance places its whole footprint
+                if !spawned.contains_key(&id.state_id) {
+                    let Some(name) = disk_ship.template_names.get(&id.template_id) else {
+                        warn!("Saved instance {} has unknown template {}", id.state_id, id.template_id);
+                        continue;
+                    };
+
+                    for inst in &all_instances.configs {
+                        if inst.name == *name {
+                            let spawn_e = inst.create.build(cmds, asset_server);
+                            spawned.insert(id.state_id, spawn_e);
+
+                            let state_e = disk_ship.states.get(&id.state_id)
+                                .map(|e| Entity::from_raw(e.index()))
+                                .filter(|e| sub_world.get_entity(*e).is_some());
+                            match state_e {
+                                Some(state_e) => cfg.load.build(&mut cmds.entity(spawn_e), &mut sub_world.entity(state_e)),
+                                None => warn!("Saved instance {} of {} has no state", id.state_id, name)
+                            }
+
+                            if let Some((_, from, to)) = footprints.get(&id.state_id) {
+                                let rot_steps = state_e
+                                    .and_then(|state_e| sub_world.get::<InstanceRotate>(state_e))
+                                    .map_or(IVec3::ZERO, |rot| rot.rot_steps);
+                                ship.map.place_object(spawn_e, Footprint {
+                                    idx : *from,
+                                    bbox : *to - *from + IVec3::ONE,
+                                    rot_steps
+                                });
+                                cmds.entity(spawn_e).insert(InstanceGridPos {
+                                    idx : *from,
+                                    bbox : *to - *from + IVec3::ONE
+                                });
                             }
                         }
-                    },
+                    }
                 }
-            }
+            },
         }
     }
 }
\ No newline at end of file
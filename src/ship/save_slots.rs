@@ -0,0 +1,364 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub const SAVES_DIR : &str = "saves";
+pub const AUTOSAVE_DIR : &str = "saves/autosave";
+pub const BACKUP_DIR : &str = "saves/backups";
+pub const QUICK_SAVE_PATH : &str = "quick.scn.ron";
+pub const AUTOSAVE_PATH : &str = "saves/autosave/autosave.scn.ron";
+
+const SCENE_EXT : &str = ".scn.ron";
+const META_EXT : &str = ".meta.ron";
+const THUMBNAIL_EXT : &str = ".png";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SaveSlotKind {
+    #[default]
+    Named,
+    Quick,
+    Autosave,
+    Backup
+}
+
+/// Sidecar file written next to every saved ship
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SaveSlotMeta {
+    pub name : String,
+    pub ship_name : String,
+    /// Unix time in seconds
+    pub created : u64,
+    pub block_count : usize,
+    pub thumbnail : Option<String>,
+    pub kind : SaveSlotKind
+}
+
+#[derive(Clone, Debug)]
+pub struct SaveSlot {
+    pub path : String,
+    pub meta : Option<SaveSlotMeta>
+}
+
+#[derive(Resource)]
+pub struct SaveSlotsCfg {
+    /// How many previous versions of a named or quick save are kept in `saves/backups`
+    pub backups : usize,
+    pub autosave_enabled : bool,
+    pub autosave_minutes : f32,
+    /// How many autosaves are kept in `saves/autosave`
+    pub autosave_keep : usize
+}
+
+impl Default for SaveSlotsCfg {
+    fn default() -> Self {
+        Self {
+            backups : 5,
+            autosave_enabled : true,
+            autosave_minutes : 5.0,
+            autosave_keep : 5
+        }
+    }
+}
+
+pub fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub fn format_age(created : u64) -> String {
+    let age = now_unix().saturating_sub(created);
+    if age < 60 {
+        format!("{} s ago", age)
+    } else if age < 60 * 60 {
+        format!("{} min ago", age / 60)
+    } else if age < 60 * 60 * 24 {
+        format!("{} h ago", age / 60 / 60)
+    } else {
+        format!("{} days ago", age / 60 / 60 / 24)
+    }
+}
+
+/// Path of the save typed in by the player. Path separators and `..` are dropped, so it stays in `saves/`.
+/// The stems of the quick save and autosaves are reserved, they would share their backup chains
+pub fn named_save_path(name : &str) -> Result<String, String> {
+    let mut stem : String = name.trim().chars()
+        .map(|c| if c.is_alphanumeric() || " -_.".contains(c) { c } else { '_' })
+        .collect();
+    while stem.contains("..") {
+        stem = stem.replace("..", ".");
+    }
+    let stem = stem.trim_matches(|c| c == '.' || c == ' ');
+    if stem.is_empty() {
+        return Err("Save name is empty".to_string());
+    }
+    let reserved = [slot_stem(QUICK_SAVE_PATH), slot_stem(AUTOSAVE_PATH)];
+    if reserved.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        return Err(format!("Save name {} is reserved", stem));
+    }
+    Ok(format!("{}/{}{}", SAVES_DIR, stem, SCENE_EXT))
+}
+
+pub fn ensure_save_dirs() {
+    for dir in [SAVES_DIR, AUTOSAVE_DIR, BACKUP_DIR] {
+        if let Err(err) = fs::create_dir_all(dir) {
+            warn!("Failed to create save dir {}: {}", dir, err);
+        }
+    }
+}
+
+/// `saves/my_ship.scn.ron` -> `my_ship`
+pub fn slot_stem(path : &str) -> String {
+    let file_name = Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(path);
+    file_name.strip_suffix(SCENE_EXT).unwrap_or(file_name).to_string()
+}
+
+pub fn meta_path(path : &str) -> String {
+    match path.strip_suffix(SCENE_EXT) {
+        Some(base) => format!("{}{}", base, META_EXT),
+        None => format!("{}{}", path, META_EXT)
+    }
+}
+
+pub fn thumbnail_path(path : &str) -> String {
+    match path.strip_suffix(SCENE_EXT) {
+        Some(base) => format!("{}{}", base, THUMBNAIL_EXT),
+        None => format!("{}{}", path, THUMBNAIL_EXT)
+    }
+}
+
+pub fn slot_kind(path : &str) -> SaveSlotKind {
+    if path == QUICK_SAVE_PATH {
+        SaveSlotKind::Quick
+    } else if path.starts_with(AUTOSAVE_DIR) {
+        SaveSlotKind::Autosave
+    } else if path.starts_with(BACKUP_DIR) {
+        SaveSlotKind::Backup
+    } else {
+        SaveSlotKind::Named
+    }
+}
+
+pub fn read_meta(path : &str) -> Option<SaveSlotMeta> {
+    let data = fs::read_to_string(meta_path(path)).ok()?;
+    ron::from_str(&data).ok()
+}
+
+pub fn write_meta(path : &str, meta : &SaveSlotMeta) {
+    match ron::ser::to_string_pretty(meta, ron::ser::PrettyConfig::default()) {
+        Ok(data) => {
+            if let Err(err) = fs::write(meta_path(path), data) {
+                warn!("Failed to write save meta for {}: {}", path, err);
+            }
+        },
+        Err(err) => warn!("Failed to serialize save meta for {}: {}", path, err)
+    }
+}
+
+/// Writes the screenshot of a fresh save and points the slot meta at it
+pub fn save_thumbnail(path : &str, img : Image) {
+    let thumbnail = thumbnail_path(path);
+    let res = img.try_into_dynamic()
+        .map_err(|err| err.to_string())
+        .and_then(|img| img.to_rgb8().save(&thumbnail).map_err(|err| err.to_string()));
+    if let Err(err) = res {
+        warn!("Failed to write thumbnail {}: {}", thumbnail, err);
+        return;
+    }
+    if let Some(mut meta) = read_meta(path) {
+        meta.thumbnail = Some(thumbnail);
+        write_meta(path, &meta);
+    }
+}
+
+fn move_slot(from : &str, to : &str) {
+    let _ = fs::rename(from, to);
+    let _ = fs::rename(meta_path(from), meta_path(to));
+    let _ = fs::rename(thumbnail_path(from), thumbnail_path(to));
+}
+
+fn remove_slot(path : &str) {
+    let _ = fs::remove_file(path);
+    let _ = fs::remove_file(meta_path(path));
+    let _ = fs::remove_file(thumbnail_path(path));
+}
+
+fn backup_slot_path(dir : &str, stem : &str, idx : usize) -> String {
+    format!("{}/{}.{}{}", dir, stem, idx, SCENE_EXT)
+}
+
+/// Moves an existing save at `path` into the backup chain `dir/<stem>.1 .. dir/<stem>.<keep>`.
+/// The oldest backup falls out of the chain
+pub fn rotate_backups(path : &str, dir : &str, keep : usize) {
+    if !Path::new(path).exists() {
+        return;
+    }
+    if keep == 0 {
+        remove_slot(path);
+        return;
+    }
+    let stem = slot_stem(path);
+
+    remove_slot(&backup_slot_path(dir, &stem, keep));
+    for idx in (1..keep).rev() {
+        let from = backup_slot_path(dir, &stem, idx);
+        if Path::new(&from).exists() {
+            move_slot(&from, &backup_slot_path(dir, &stem, idx + 1));
+        }
+    }
+    move_slot(path, &backup_slot_path(dir, &stem, 1));
+}
+
+/// Frees `path` for a new save, keeping previous versions according to `cfg`
+pub fn prepare_slot(path : &str, cfg : &SaveSlotsCfg) {
+    ensure_save_dirs();
+    match slot_kind(path) {
+        SaveSlotKind::Autosave => rotate_backups(path, AUTOSAVE_DIR, cfg.autosave_keep),
+        SaveSlotKind::Backup => {},
+        _ => rotate_backups(path, BACKUP_DIR, cfg.backups)
+    }
+}
+
+fn collect_dir(dir : &str, slots : &mut Vec<SaveSlot>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path : PathBuf = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        let Some(path) = path.to_str() else {
+            continue;
+        };
+        if path.ends_with(SCENE_EXT) {
+            let path = path.replace('\\', "/");
+            slots.push(SaveSlot {
+                meta : read_meta(&path),
+                path
+            });
+        }
+    }
+}
+
+/// All restorable saves, newest first
+pub fn list_slots() -> Vec<SaveSlot> {
+    ensure_save_dirs();
+    list_slots_from(QUICK_SAVE_PATH, &[SAVES_DIR, AUTOSAVE_DIR, BACKUP_DIR])
+}
+
+fn list_slots_from(quick_path : &str, dirs : &[&str]) -> Vec<SaveSlot> {
+    let mut slots = vec![];
+    if Path::new(quick_path).exists() {
+        slots.push(SaveSlot {
+            path : quick_path.to_string(),
+            meta : read_meta(quick_path)
+        });
+    }
+    for dir in dirs {
+        collect_dir(dir, &mut slots);
+    }
+
+    slots.sort_by(|a, b| {
+        let a_time = a.meta.as_ref().map(|m| m.created).unwrap_or(0);
+        let b_time = b.meta.as_ref().map(|m| m.created).unwrap_or(0);
+        b_time.cmp(&a_time).then(a.path.cmp(&b.path))
+    });
+    slots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name : &str) -> String {
+        let dir = std::env::temp_dir().join(format!("space_sandbox_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_str().unwrap().replace('\\', "/")
+    }
+
+    fn write_slot(path : &str, text : &str, created : u64) {
+        fs::write(path, text).unwrap();
+        write_meta(path, &SaveSlotMeta {
+            name : slot_stem(path),
+            created,
+            ..default()
+        });
+    }
+
+    #[test]
+    fn slot_kinds() {
+        assert_eq!(slot_kind(QUICK_SAVE_PATH), SaveSlotKind::Quick);
+        assert_eq!(slot_kind(AUTOSAVE_PATH), SaveSlotKind::Autosave);
+        assert_eq!(slot_kind("saves/backups/ship.2.scn.ron"), SaveSlotKind::Backup);
+        assert_eq!(slot_kind(&named_save_path("ship").unwrap()), SaveSlotKind::Named);
+    }
+
+    #[test]
+    fn save_names_stay_in_saves() {
+        assert_eq!(named_save_path(" my ship ").unwrap(), "saves/my ship.scn.ron");
+        assert_eq!(named_save_path("../../x").unwrap(), "saves/_._x.scn.ron");
+        assert_eq!(named_save_path("a/b\\c").unwrap(), "saves/a_b_c.scn.ron");
+        assert_eq!(named_save_path("ship...v2").unwrap(), "saves/ship.v2.scn.ron");
+        assert!(named_save_path("").is_err());
+        assert!(named_save_path(" .. ").is_err());
+        assert!(named_save_path("quick").is_err());
+        assert!(named_save_path("Autosave").is_err());
+    }
+
+    #[test]
+    fn backups_rotate_and_drop_oldest() {
+        let dir = test_dir("rotate");
+        let backups = format!("{}/backups", dir);
+        fs::create_dir_all(&backups).unwrap();
+        let path = format!("{}/ship.scn.ron", dir);
+
+        for version in 1..=4 {
+            write_slot(&path, &version.to_string(), version);
+            rotate_backups(&path, &backups, 2);
+            assert!(!Path::new(&path).exists());
+        }
+
+        let read = |idx : usize| fs::read_to_string(backup_slot_path(&backups, "ship", idx)).ok();
+        assert_eq!(read(1).as_deref(), Some("4"));
+        assert_eq!(read(2).as_deref(), Some("3"));
+        assert_eq!(read(3), None);
+        // the meta moves with its save
+        assert_eq!(read_meta(&backup_slot_path(&backups, "ship", 2)).map(|meta| meta.created), Some(3));
+
+        // nothing to rotate
+        rotate_backups(&path, &backups, 2);
+        assert_eq!(read(1).as_deref(), Some("4"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn slots_listed_newest_first() {
+        let dir = test_dir("list");
+        let named = format!("{}/saves", dir);
+        let autosave = format!("{}/autosave", dir);
+        fs::create_dir_all(&named).unwrap();
+        fs::create_dir_all(&autosave).unwrap();
+        let quick = format!("{}/quick.scn.ron", dir);
+
+        write_slot(&quick, "quick", 20);
+        write_slot(&format!("{}/old.scn.ron", named), "old", 10);
+        write_slot(&format!("{}/auto.scn.ron", autosave), "auto", 30);
+        fs::write(format!("{}/no_meta.scn.ron", named), "").unwrap();
+        fs::write(format!("{}/notes.txt", named), "").unwrap();
+
+        let slots = list_slots_from(&quick, &[&named, &autosave]);
+        let names = slots.iter().map(|slot| slot_stem(&slot.path)).collect::<Vec<_>>();
+        assert_eq!(names, vec!["auto", "quick", "old", "no_meta"]);
+        assert!(slots.last().unwrap().meta.is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use bevy_transform64::prelude::DTransform;
+
+use crate::DSpatialBundle;
+use crate::space_voxel::VoxelMap;
+use crate::space_voxel::objected_mesh_generate::{generate_mesh_padded, group_quads, quads_mesh};
+use crate::space_voxel::lod::{VoxelLod, LOD_REMESH_PER_FRAME, downsample_chunk, chunks_to_remesh, coarse_cell};
+
+use super::{Ship, ShipBlock};
+
+/// Render material of every [`ShipBlock`]
+#[derive(Resource, Default)]
+pub struct ShipBlockMaterials {
+    pub materials : HashMap<ShipBlock, Handle<StandardMaterial>>
+}
+
+/// Mesh entities of the ship chunks, by chunk origin
+#[derive(Component, Default)]
+pub struct ShipChunkMeshes {
+    pub chunks : HashMap<IVec3, Vec<Entity>>,
+    /// Chunks still meshed at the previous [`VoxelLod`] level
+    pub pending : HashSet<IVec3>
+}
+
+pub fn setup_ship_block_materials(
+    mut block_materials : ResMut<ShipBlockMaterials>,
+    mut materials : ResMut<Assets<StandardMaterial>>
+) {
+    for block in ShipBlock::ALL {
+        let color = block.color();
+        let material = materials.add(StandardMaterial {
+            base_color : color,
+            perceptual_roughness : 0.6,
+            metallic : if block == ShipBlock::Glass { 0.0 } else { 0.8 },
+            alpha_mode : if color.a() < 1.0 { AlphaMode::Blend } else { AlphaMode::Opaque },
+            ..default()
+        });
+        block_materials.materials.insert(block, material);
+    }
+}
+
+/// Rebuilds the meshes of the chunks in the ship `dirty_set`, one mesh per block material.
+/// All chunks are rebuilt when the [`VoxelLod`] level changes, [`LOD_REMESH_PER_FRAME`] at a time.
+/// Object cells are left to their instances
+pub fn update_ship_chunk_meshes(
+    mut cmds : Commands,
+    mut meshes : ResMut<Assets<Mesh>>,
+    block_materials : Res<ShipBlockMaterials>,
+    mut ships : Query<(Entity, &Ship, Option<&mut ShipChunkMeshes>, Option<Ref<VoxelLod>>)>
+) {
+    let mut budget = LOD_REMESH_PER_FRAME;
+    for (ship_e, ship, chunk_meshes, lod) in ships.iter_mut() {
+        let lod_changed = lod.as_ref().is_some_and(|lod| lod.is_changed());
+        let pending = chunk_meshes.as_ref().is_some_and(|chunk_meshes| !chunk_meshes.pending.is_empty());
+        if ship.map.dirty_set.is_empty() && !lod_changed && !pending {
+            continue;
+        }
+        let factor = lod.map_or(1, |lod| lod.factor_for(ship.map.chunk_size));
+        let first_build = chunk_meshes.is_none();
+        let mut new_meshes = ShipChunkMeshes::default();
+        let chunk_meshes = match chunk_meshes {
+            Some(chunk_meshes) => chunk_meshes.into_inner(),
+            None => &mut new_meshes
+        };
+
+        let known = ship.map.map.keys().chain(chunk_meshes.chunks.keys()).copied().collect::<Vec<_>>();
+        let to_remesh = chunks_to_remesh(&ship.map.dirty_set, &mut chunk_meshes.pending, known.into_iter(), lod_changed, &mut budget);
+        for origin in to_remesh {
+            for e in chunk_meshes.chunks.remove(&origin).unwrap_or_default() {
+                cmds.entity(e).despawn_recursive();
+            }
+            let Some(chunk) = ship.map.map.get(&origin) else {
+                continue;
+            };
+            let coarse;
+            let chunk = if factor > 1 {
+                coarse = downsample_chunk(chunk, factor);
+                &coarse
+            } else {
+                chunk
+            };
+
+            // the padding comes from the neighbour chunks, so faces between chunks are culled
+            let coarse_origin = origin / factor;
+            let buffer = generate_mesh_padded(chunk, |lp| coarse_cell(&ship.map, coarse_origin + lp, factor));
+            if buffer.quads.num_quads() == 0 {
+                continue;
+            }
+            // mesh vertices start at the padding cell before the chunk origin
+            let pos = ship.map.get_idx_pos(&(origin - IVec3::splat(factor)));
+            let voxel_size = ship.map.voxel_size * factor as f64;
+            let mut spawned = vec![];
+            for (block, faces) in group_quads(chunk, &buffer) {
+                let Some(material) = block_materials.materials.get(&block) else {
+                    continue;
+                };
+                let e = cmds.spawn(DSpatialBundle::from_transform(DTransform::from_xyz(pos.x, pos.y, pos.z)))
+                    .insert(meshes.add(quads_mesh(&faces, voxel_size as f32)))
+                    .insert(material.clone())
+                    .insert(Name::new("Ship chunk"))
+                    .id();
+                cmds.entity(ship_e).add_child(e);
+                spawned.push(e);
+            }
+            chunk_meshes.chunks.insert(origin, spawned);
+        }
+
+        if first_build {
+            cmds.entity(ship_e).insert(new_meshes);
+        }
+    }
+}
@@ -1,77 +1,121 @@
 use bevy::utils::{HashMap, HashSet};
 use bevy::prelude::*;
+use serde::{Serialize, Deserialize};
 
-use super::{VoxelMap, Real};
+use super::{VoxelMap, Real, objected_voxel_map::VoxelVal};
 
 
+/// Unbounded map made of lazily allocated chunks. Cell indices follow [`super::solid_voxel_map::SolidVoxelMap`]:
+/// cell `idx` covers `first_voxel_pos + idx * voxel_size` up to the next cell, negative indices included
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ChunkedVoxelMap<T> {
     pub map: HashMap<IVec3, VoxelChunk<T>>,
     pub voxel_size: f64,
     pub chunk_size: IVec3,
+    pub first_voxel_pos: Real,
+    /// Origins of the chunks changed since the set was last cleared. A change of a boundary cell
+    /// marks the allocated chunk on the other side too, its mesh faces that cell
+    #[serde(skip)]
     pub dirty_set: HashSet<IVec3>,
+    #[serde(skip)]
+    dummy: T,
 }
 
 impl<T> VoxelMap<T> for ChunkedVoxelMap<T> 
     where T : Default + Clone {
     fn get_grid_pos(&self, pos: &Real) -> Real {
-        let vp = self.get_voxel_pos(pos);
-        Real::new(
-            vp.x as f64 * self.voxel_size,
-            vp.y as f64 * self.voxel_size,
-            vp.z as f64 * self.voxel_size,
-        )
+        self.get_idx_pos(&self.get_voxel_pos(pos))
     }
 
-    fn get_grid_idx(&self, _pos: &Real) -> IVec3 {
-        todo!()
+    fn get_grid_idx(&self, pos: &Real) -> IVec3 {
+        self.get_voxel_pos(pos)
     }
 
-    fn get_idx_pos(&self, _pos : &IVec3) -> Real {
-        todo!()
+    fn get_idx_pos(&self, pos : &IVec3) -> Real {
+        pos.as_dvec3() * self.voxel_size + self.first_voxel_pos
     }
 
-    fn get_cloned(&self, _pos: &Real) -> T {
-        todo!()
+    fn get_cloned(&self, pos: &Real) -> T {
+        self.get_cloned_by_idx(&self.get_voxel_pos(pos))
     }
 
-    fn get(&self, _pos: &Real) -> &T {
-        todo!()
+    fn get(&self, pos: &Real) -> &T {
+        self.get_by_idx(&self.get_voxel_pos(pos))
     }
 
-    fn get_mut(&mut self, _pos: &Real) -> Option<&mut T> {
-        todo!()
+    fn get_mut(&mut self, pos: &Real) -> Option<&mut T> {
+        let idx = self.get_voxel_pos(pos);
+        self.get_mut_by_idx(&idx)
     }
 
-    fn set_voxel(&mut self, _pos : &Real, _val : T) {
-        todo!()
+    fn set_voxel(&mut self, pos : &Real, val : T) {
+        let idx = self.get_voxel_pos(pos);
+        self.set_voxel_by_idx(&idx, val);
     }
 
-    fn get_cloned_by_idx(&self, _pos : &IVec3) -> T {
-        todo!()
+    fn get_cloned_by_idx(&self, pos : &IVec3) -> T {
+        self.get_by_idx(pos).clone()
     }
 
-    fn get_by_idx(&self, _pos : &IVec3) -> &T {
-        todo!()
+    fn get_by_idx(&self, pos : &IVec3) -> &T {
+        if let Some(chunk) = self.get_chunk_by_voxel(pos) {
+            let lp = *pos - chunk.origin;
+            chunk.get(lp.x, lp.y, lp.z)
+        } else {
+            &self.dummy
+        }
     }
 
-    fn get_mut_by_idx(&mut self, _pos : &IVec3) -> Option<&mut T> {
-        todo!()
+    fn get_mut_by_idx(&mut self, pos : &IVec3) -> Option<&mut T> {
+        let chunk = self.chunk_or_insert(pos);
+        let lp = *pos - chunk.origin;
+        Some(chunk.get_mut(lp.x, lp.y, lp.z))
     }
 
-    fn set_voxel_by_idx(&mut self, _pos : &IVec3, _val : T) {
-        todo!()
+    fn set_voxel_by_idx(&mut self, pos : &IVec3, val : T) {
+        let chunk = self.chunk_or_insert(pos);
+        let lp = *pos - chunk.origin;
+        *chunk.get_mut(lp.x, lp.y, lp.z) = val;
     }
 
     fn get_bounds(&self) -> super::MapBounds {
-        todo!()
+        super::MapBounds::Infinity
+    }
+
+    /// Cells of the allocated chunks, the rest of the map is empty
+    fn cell_range(&self) -> Option<(IVec3, IVec3)> {
+        Some(self.chunk_bounds().map_or((IVec3::ZERO, IVec3::NEG_ONE), |(min, max)| (min, max - IVec3::ONE)))
+    }
+
+    /// Walks only the allocated chunks which overlap the box
+    fn overlap_aabb(&self, min: &Real, max: &Real) -> Vec<IVec3>
+        where T : PartialEq {
+        let (lo, hi) = super::query::cells_in_box(self, min, max);
+        let mut res = vec![];
+        for chunk in self.map.values() {
+            let from = lo.max(chunk.origin);
+            let to = hi.min(chunk.origin + chunk.size - IVec3::ONE);
+            for z in from.z..=to.z {
+                for y in from.y..=to.y {
+                    for x in from.x..=to.x {
+                        let idx = IVec3::new(x, y, z);
+                        let lp = idx - chunk.origin;
+                        if *chunk.get(lp.x, lp.y, lp.z) != self.dummy {
+                            res.push(idx);
+                        }
+                    }
+                }
+            }
+        }
+        res
     }
 
     fn get_voxel_size(&self) -> f64 {
-        todo!()
+        self.voxel_size
     }
 
     fn test_default() -> Self {
-        todo!()
+        ChunkedVoxelMap::new(0.5, IVec3::new(16, 16, 16))
     }
 }
 
@@ -84,105 +128,96 @@ where
             map: HashMap::new(),
             voxel_size,
             chunk_size,
+            first_voxel_pos: Real::ZERO,
             dirty_set: HashSet::new(),
+            dummy: T::default(),
         }
     }
 
     pub fn get_voxel_pos(&self, pos: &Real) -> IVec3 {
-        IVec3::new(
-            (pos.x / self.voxel_size).round() as i32,
-            (pos.y / self.voxel_size).round() as i32,
-            (pos.z / self.voxel_size).round() as i32,
-        )
+        ((*pos - self.first_voxel_pos) / self.voxel_size).floor().as_ivec3()
     }
 
-    
-
+    /// Origin of the chunk holding the cell `pos`
     pub fn get_origin(&self, pos: &IVec3) -> IVec3 {
         IVec3::new(
-            (pos.x as f32 / self.chunk_size.x as f32).floor() as i32 * self.chunk_size.x,
-            (pos.y as f32 / self.chunk_size.y as f32).floor() as i32 * self.chunk_size.y,
-            (pos.z as f32 / self.chunk_size.z as f32).floor() as i32 * self.chunk_size.z,
+            pos.x.div_euclid(self.chunk_size.x) * self.chunk_size.x,
+            pos.y.div_euclid(self.chunk_size.y) * self.chunk_size.y,
+            pos.z.div_euclid(self.chunk_size.z) * self.chunk_size.z,
         )
     }
 
     pub fn get_chunk_by_voxel(&self, pos: &IVec3) -> Option<&VoxelChunk<T>> {
-        let origin = self.get_origin(pos);
-
-        if let Some(chunk) = self.map.get(&origin) {
-            Some(chunk)
-        } else {
-            None
-        }
+        self.map.get(&self.get_origin(pos))
     }
 
     pub fn get_chunk(&self, pos: &Real) -> Option<&VoxelChunk<T>> {
-        let vp = self.get_voxel_pos(pos);
-        let origin = self.get_origin(&vp);
-
-        if let Some(chunk) = self.map.get(&origin) {
-            Some(chunk)
-        } else {
-            None
-        }
+        self.get_chunk_by_voxel(&self.get_voxel_pos(pos))
     }
 
     pub fn get_chunk_mut(&mut self, pos: &Real) -> Option<&mut VoxelChunk<T>> {
-        let vp = self.get_voxel_pos(pos);
-        let origin = self.get_origin(&vp);
-
-        if let Some(chunk) = self.map.get_mut(&origin) {
-            Some(chunk)
-        } else {
-            None
-        }
+        let origin = self.get_origin(&self.get_voxel_pos(pos));
+        self.map.get_mut(&origin)
     }
 
-    pub fn get_mut(&mut self, pos: &Real) -> &mut T {
-        let vp = self.get_voxel_pos(pos);
-        let origin = self.get_origin(&vp);
-        let chunk_size = self.chunk_size;
-        self.dirty_set.insert(origin);
-
-        if !self.map.contains_key(&origin) {
-            let chunk = VoxelChunk::<T>::new(origin, chunk_size);
-
-            let _lp = vp - origin;
-            self.map.insert(origin, chunk);
-        }
+    /// Cells covered by the allocated chunks as `(min, max)`, `max` excluded
+    pub fn chunk_bounds(&self) -> Option<(IVec3, IVec3)> {
+        let min = self.map.keys().copied().reduce(|a, b| a.min(b))?;
+        let max = self.map.keys().copied().reduce(|a, b| a.max(b))?;
+        Some((min, max + self.chunk_size))
+    }
 
-        let chunk = self.map.get_mut(&origin).unwrap();
-        let lp = vp - chunk.origin;
-        chunk.get_mut(lp.x, lp.y, lp.z)
+    /// Every cell of the allocated chunks
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &T)> {
+        self.map.values().flat_map(|chunk| chunk.iter().map(|(lp, val)| (chunk.origin + lp, val)))
     }
 
-    pub fn get_cloned(&self, pos: &Real) -> T {
-        if let Some(chunk) = self.get_chunk(pos) {
-            let vp = self.get_voxel_pos(pos) - chunk.origin;
-            chunk.get(vp.x, vp.y, vp.z).clone()
-        } else {
-            T::default()
+    fn mark_boundary_neighbours(&mut self, origin: IVec3, pos: &IVec3) {
+        let lp = *pos - origin;
+        for axis in 0..3 {
+            let mut step = IVec3::ZERO;
+            if lp[axis] == 0 {
+                step[axis] = -1;
+            } else if lp[axis] == self.chunk_size[axis] - 1 {
+                step[axis] = 1;
+            } else {
+                continue;
+            }
+            let neighbour = origin + step * self.chunk_size;
+            if self.map.contains_key(&neighbour) {
+                self.dirty_set.insert(neighbour);
+            }
         }
     }
 
-    pub fn set(&mut self, pos: &Real, val: T) {
-        let vp = self.get_voxel_pos(pos);
-        let origin = self.get_origin(&vp);
-        if let Some(chunk) = self.get_chunk_mut(pos) {
-            let lp = vp - chunk.origin;
-            *chunk.get_mut(lp.x, lp.y, lp.z) = val;
-        } else {
-            let origin = self.get_origin(&vp);
-            let mut chunk = VoxelChunk::<T>::new(origin, self.chunk_size);
+    /// Chunk holding the cell `pos`, allocated if missing and marked dirty
+    fn chunk_or_insert(&mut self, pos: &IVec3) -> &mut VoxelChunk<T> {
+        let origin = self.get_origin(pos);
+        let chunk_size = self.chunk_size;
+        self.dirty_set.insert(origin);
+        self.mark_boundary_neighbours(origin, pos);
+        self.map.entry(origin).or_insert_with(|| VoxelChunk::new(origin, chunk_size))
+    }
+}
 
-            let lp = vp - origin;
-            *chunk.get_mut(lp.x, lp.y, lp.z) = val;
-            self.map.insert(origin, chunk);
+impl<T> ChunkedVoxelMap<VoxelVal<T>>
+where
+    T: Clone,
+{
+    /// Frees the chunks left without voxels and objects
+    pub fn prune_empty(&mut self) {
+        let empty : Vec<IVec3> = self.map.values()
+            .filter(|chunk| chunk.data.iter().all(|val| matches!(val, VoxelVal::None)))
+            .map(|chunk| chunk.origin)
+            .collect();
+        for origin in empty {
+            self.map.remove(&origin);
+            self.dirty_set.insert(origin);
         }
-        self.dirty_set.insert(origin);
     }
 }
 
+#[derive(Serialize, Deserialize, Clone)]
 pub struct VoxelChunk<T> {
     pub origin: IVec3,
     pub size: IVec3,
@@ -206,6 +241,15 @@ where
         &mut self.data[((z * self.size.y + y) * self.size.x + x) as usize]
     }
 
+    /// Cells with their position inside the chunk
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &T)> {
+        let size = self.size;
+        self.data.iter().enumerate().map(move |(i, val)| {
+            let i = i as i32;
+            (IVec3::new(i % size.x, (i / size.x) % size.y, i / (size.x * size.y)), val)
+        })
+    }
+
     pub fn fill(&mut self, val: &T) {
         for i in 0..self.data.len() {
             self.data[i] = val.clone();
@@ -0,0 +1,286 @@
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_transform64::SimpleWorldOrigin;
+use bevy_transform64::prelude::DGlobalTransform;
+
+use super::VoxelMap;
+use super::chunked_voxel_map::{ChunkedVoxelMap, VoxelChunk};
+use super::objected_voxel_map::VoxelVal;
+
+/// Distance is scaled by this before switching, so a body on a boundary does not flicker between levels
+const LOD_HYSTERESIS : f64 = 1.1;
+/// Chunks remeshed per frame for level changes, over all bodies of one system. Edited chunks do not count
+pub const LOD_REMESH_PER_FRAME : usize = 16;
+
+/// Camera distances where voxel meshes switch to the next coarser level
+#[derive(Resource, Clone, Debug)]
+pub struct VoxelLodSettings {
+    pub distances : Vec<f64>
+}
+
+impl Default for VoxelLodSettings {
+    fn default() -> Self {
+        Self {
+            distances : vec![250.0, 1000.0, 4000.0]
+        }
+    }
+}
+
+impl VoxelLodSettings {
+    pub fn level(&self, distance : f64) -> usize {
+        self.distances.iter().take_while(|d| distance >= **d).count()
+    }
+
+    /// Level for a body at `distance` which is shown at `current` now
+    pub fn next_level(&self, current : usize, distance : f64) -> usize {
+        let coarser = self.level(distance / LOD_HYSTERESIS);
+        let finer = self.level(distance * LOD_HYSTERESIS);
+        if coarser > current {
+            coarser
+        } else if finer < current {
+            finer
+        } else {
+            current
+        }
+    }
+}
+
+/// Detail of the voxel meshes of a body. Level `n` merges `2^n` cells along every axis
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct VoxelLod {
+    pub level : usize
+}
+
+impl VoxelLod {
+    pub fn factor(&self) -> i32 {
+        1 << self.level
+    }
+
+    /// [`Self::factor`] for chunks of `chunk_size`, see [`fitting_factor`]
+    pub fn factor_for(&self, chunk_size : IVec3) -> i32 {
+        fitting_factor(chunk_size, self.factor())
+    }
+}
+
+/// The largest power of two up to `factor` that divides every axis of `size`. A chunk size which is
+/// not a power of two gets a finer level than asked for instead of coarse cells cut by the chunk border
+pub fn fitting_factor(size : IVec3, factor : i32) -> i32 {
+    let mut factor = factor.max(1);
+    while size % factor != IVec3::ZERO {
+        factor /= 2;
+    }
+    factor
+}
+
+/// Voxel kept by a coarse cell: the most common one, a tie goes to the one met first
+fn majority<T : Clone + Eq>(counts : &[(T, u32)]) -> Option<T> {
+    let mut best : Option<&(T, u32)> = None;
+    for count in counts {
+        match best {
+            Some((_, n)) if count.1 <= *n => {},
+            _ => best = Some(count)
+        }
+    }
+    best.map(|(block, _)| block.clone())
+}
+
+fn count_block<T : Eq + Clone>(counts : &mut Vec<(T, u32)>, block : &T) {
+    match counts.iter_mut().find(|(b, _)| b == block) {
+        Some((_, n)) => *n += 1,
+        None => counts.push((block.clone(), 1))
+    }
+}
+
+/// Chunk with `factor` times fewer cells along every axis. A coarse cell is solid if any of its cells
+/// holds a voxel, so thin walls stay visible. It takes the most common voxel of its cells, a tie goes to
+/// the voxel met first with x running fastest, then y, then z. Object cells are left out, their instances draw them.
+/// `factor` is lowered with [`fitting_factor`] when it does not divide the chunk size
+pub fn downsample_chunk<T>(chunk : &VoxelChunk<VoxelVal<T>>, factor : i32) -> VoxelChunk<VoxelVal<T>>
+    where T : Clone + Eq {
+    let factor = fitting_factor(chunk.size, factor);
+    let size = chunk.size / factor;
+    let mut res = VoxelChunk::new(chunk.origin / factor, size);
+    let mut counts : Vec<Vec<(T, u32)>> = vec![vec![]; res.data.len()];
+    for (idx, val) in chunk.iter() {
+        if let VoxelVal::Voxel(block) = val {
+            let coarse = idx / factor;
+            let i = ((coarse.z * size.y + coarse.y) * size.x + coarse.x) as usize;
+            count_block(&mut counts[i], block);
+        }
+    }
+    for (val, count) in res.data.iter_mut().zip(counts) {
+        if let Some(block) = majority(&count) {
+            *val = VoxelVal::Voxel(block);
+        }
+    }
+    res
+}
+
+/// Cell `idx` of the map at `factor` times coarser level, the same cell [`downsample_chunk`] makes.
+/// Used for the padding of coarse chunk meshes, which reaches into the neighbour chunks
+pub fn coarse_cell<T>(map : &ChunkedVoxelMap<VoxelVal<T>>, idx : IVec3, factor : i32) -> VoxelVal<T>
+    where T : Clone + Eq {
+    if factor == 1 {
+        return map.get_cloned_by_idx(&idx);
+    }
+    let mut counts : Vec<(T, u32)> = vec![];
+    // the same order as the cells of a chunk
+    for z in 0..factor {
+        for y in 0..factor {
+            for x in 0..factor {
+                if let VoxelVal::Voxel(block) = map.get_by_idx(&(idx * factor + IVec3::new(x, y, z))) {
+                    count_block(&mut counts, block);
+                }
+            }
+        }
+    }
+    majority(&counts).map_or(VoxelVal::None, VoxelVal::Voxel)
+}
+
+/// Chunks whose meshes have to be rebuilt this frame: the dirty ones and up to `budget` chunks from `pending`.
+/// A level change queues every known chunk in `pending`, so a body switches its level over several frames
+pub fn chunks_to_remesh(
+    dirty : &HashSet<IVec3>,
+    pending : &mut HashSet<IVec3>,
+    known : impl Iterator<Item = IVec3>,
+    lod_changed : bool,
+    budget : &mut usize
+) -> HashSet<IVec3> {
+    if lod_changed {
+        pending.extend(known);
+    }
+    let mut res = dirty.clone();
+    pending.retain(|origin| !dirty.contains(origin));
+
+    let mut queued = pending.iter().copied().collect::<Vec<_>>();
+    queued.sort_by_key(|origin| (origin.z, origin.y, origin.x));
+    for origin in queued.into_iter().take(*budget) {
+        pending.remove(&origin);
+        res.insert(origin);
+        *budget -= 1;
+    }
+    res
+}
+
+pub fn select_voxel_lod(
+    origin : Res<SimpleWorldOrigin>,
+    settings : Res<VoxelLodSettings>,
+    mut bodies : Query<(&DGlobalTransform, &mut VoxelLod)>
+) {
+    for (transform, mut lod) in bodies.iter_mut() {
+        let distance = transform.translation().distance(origin.origin);
+        let level = settings.next_level(lod.level, distance);
+        if level != lod.level {
+            lod.level = level;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_switch_with_margin() {
+        let settings = VoxelLodSettings::default();
+        assert_eq!(settings.level(10.0), 0);
+        assert_eq!(settings.level(300.0), 1);
+        assert_eq!(settings.level(10000.0), 3);
+
+        // just past a boundary keeps the current level until the margin is crossed
+        assert_eq!(settings.next_level(0, 260.0), 0);
+        assert_eq!(settings.next_level(0, 300.0), 1);
+        assert_eq!(settings.next_level(1, 240.0), 1);
+        assert_eq!(settings.next_level(1, 200.0), 0);
+        assert_eq!(settings.next_level(0, 10000.0), 3);
+    }
+
+    #[test]
+    fn downsample_keeps_thin_walls() {
+        let mut chunk = VoxelChunk::<VoxelVal<i32>>::new(IVec3::new(16, 0, 0), IVec3::new(16, 16, 16));
+        for z in 0..16 {
+            for y in 0..16 {
+                *chunk.get_mut(3, y, z) = VoxelVal::Voxel(1);
+            }
+        }
+        *chunk.get_mut(2, 0, 0) = VoxelVal::Voxel(2);
+        *chunk.get_mut(9, 9, 9) = VoxelVal::Object(Entity::from_raw(1));
+
+        let coarse = downsample_chunk(&chunk, 4);
+        assert_eq!(coarse.size, IVec3::new(4, 4, 4));
+        assert_eq!(coarse.origin, IVec3::new(4, 0, 0));
+        assert_eq!(coarse.iter().filter(|(_, val)| **val != VoxelVal::None).count(), 16);
+        assert_eq!(coarse.get(0, 0, 0), &VoxelVal::Voxel(1));
+        assert_eq!(coarse.get(2, 2, 2), &VoxelVal::None);
+    }
+
+    #[test]
+    fn downsample_ties_are_stable() {
+        let mut chunk = VoxelChunk::<VoxelVal<i32>>::new(IVec3::ZERO, IVec3::new(4, 4, 4));
+        *chunk.get_mut(1, 0, 0) = VoxelVal::Voxel(7);
+        *chunk.get_mut(0, 1, 0) = VoxelVal::Voxel(5);
+        *chunk.get_mut(1, 1, 1) = VoxelVal::Voxel(5);
+        *chunk.get_mut(0, 0, 1) = VoxelVal::Voxel(7);
+        // two of each, the first cell in x, y, z order wins
+        assert_eq!(downsample_chunk(&chunk, 2).get(0, 0, 0), &VoxelVal::Voxel(7));
+        *chunk.get_mut(0, 0, 0) = VoxelVal::Voxel(5);
+        assert_eq!(downsample_chunk(&chunk, 2).get(0, 0, 0), &VoxelVal::Voxel(5));
+    }
+
+    #[test]
+    fn odd_chunk_sizes_get_a_fitting_factor() {
+        assert_eq!(fitting_factor(IVec3::splat(16), 4), 4);
+        assert_eq!(fitting_factor(IVec3::splat(6), 4), 2);
+        assert_eq!(fitting_factor(IVec3::new(8, 8, 7), 8), 1);
+        assert_eq!(VoxelLod { level : 3 }.factor_for(IVec3::splat(12)), 4);
+
+        let mut chunk = VoxelChunk::<VoxelVal<i32>>::new(IVec3::new(6, 0, 0), IVec3::new(6, 6, 6));
+        *chunk.get_mut(5, 5, 5) = VoxelVal::Voxel(1);
+        let coarse = downsample_chunk(&chunk, 4);
+        assert_eq!(coarse.size, IVec3::new(3, 3, 3));
+        assert_eq!(coarse.origin, IVec3::new(3, 0, 0));
+        assert_eq!(coarse.get(2, 2, 2), &VoxelVal::Voxel(1));
+
+        let odd = VoxelChunk::<VoxelVal<i32>>::new(IVec3::ZERO, IVec3::new(5, 5, 5));
+        assert_eq!(downsample_chunk(&odd, 2).size, IVec3::new(5, 5, 5));
+    }
+
+    #[test]
+    fn level_change_is_spread_over_frames() {
+        let dirty : HashSet<IVec3> = [IVec3::ZERO].into_iter().collect();
+        let known = (0..5).map(|x| IVec3::new(x * 16, 0, 0));
+        let mut pending = HashSet::new();
+
+        let mut budget = 2;
+        let first = chunks_to_remesh(&dirty, &mut pending, known, true, &mut budget);
+        assert_eq!(first.len(), 3);
+        assert!(first.contains(&IVec3::ZERO));
+        assert_eq!(budget, 0);
+        assert_eq!(pending.len(), 2);
+
+        // the budget is spent, only edits go through
+        let none = chunks_to_remesh(&HashSet::new(), &mut pending, std::iter::empty(), false, &mut budget);
+        assert!(none.is_empty());
+
+        let mut budget = 2;
+        let rest = chunks_to_remesh(&HashSet::new(), &mut pending, std::iter::empty(), false, &mut budget);
+        assert_eq!(rest.len() + first.len(), 5);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn coarse_cell_matches_downsample() {
+        let mut map = ChunkedVoxelMap::<VoxelVal<i32>>::new(0.5, IVec3::new(8, 8, 8));
+        for (idx, block) in [(IVec3::new(-1, 0, 0), 1), (IVec3::new(-2, 1, 0), 1), (IVec3::new(-3, 3, 3), 2), (IVec3::new(5, 6, 7), 3)] {
+            map.set_voxel_by_idx(&idx, VoxelVal::Voxel(block));
+        }
+        for chunk in map.map.values() {
+            let coarse = downsample_chunk(chunk, 4);
+            for (lp, val) in coarse.iter() {
+                assert_eq!(&coarse_cell(&map, coarse.origin + lp, 4), val);
+            }
+        }
+        // cells of unallocated chunks are empty
+        assert_eq!(coarse_cell(&map, IVec3::new(-1, -1, 0), 4), VoxelVal::None);
+    }
+}
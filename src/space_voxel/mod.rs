@@ -3,9 +3,14 @@ pub mod chunked_voxel_map;
 pub mod objected_mesh_generate;
 pub mod solid_voxel_map;
 pub mod voxel_test;
+pub mod query;
+pub mod region;
+pub mod lod;
 
 use bevy::{prelude::*, math::DVec3};
 
+use query::VoxelHit;
+
 pub enum MapBounds {
     Infinity,
     Limited {from : Real, to : Real}
@@ -32,5 +37,32 @@ pub trait VoxelMap<T>
     fn get_bounds(&self) -> MapBounds;
     fn get_voxel_size(&self) -> f64;
 
+    /// Cells outside of `(min, max)`, `max` included, are all `T::default()`. `None` if there is no such limit,
+    /// `min > max` if every cell is empty
+    fn cell_range(&self) -> Option<(IVec3, IVec3)> {
+        query::bounds_cell_range(self)
+    }
+
     fn test_default() -> Self;
+
+    /// First cell along the ray which is not `T::default()`, see [`query::raycast`]
+    fn raycast(&self, origin : &Real, dir : &Real, max_dist : f64) -> Option<VoxelHit<T>>
+        where T : Clone + Default + PartialEq {
+        query::raycast(self, origin, dir, max_dist)
+    }
+
+    fn overlap_aabb(&self, min : &Real, max : &Real) -> Vec<IVec3>
+        where T : Default + PartialEq {
+        query::overlap_aabb(self, min, max)
+    }
+
+    fn overlap_sphere(&self, center : &Real, radius : f64) -> Vec<IVec3>
+        where T : Default + PartialEq {
+        query::overlap_sphere(self, center, radius)
+    }
+
+    /// Face neighbours in [`query::FACE_DIRS`] order
+    fn neighbours(&self, idx : &IVec3) -> [(IVec3, &T); 6] {
+        query::FACE_DIRS.map(|dir| (*idx + dir, self.get_by_idx(&(*idx + dir))))
+    }
 }
\ No newline at end of file
@@ -1,7 +1,12 @@
+use std::hash::Hash;
+
+use bevy::math::IVec3;
 use bevy::reflect::FromReflect;
 use bevy::reflect::Typed;
+use bevy::render::mesh::{Indices, Mesh, PrimitiveTopology};
+use bevy::utils::HashMap;
 use block_mesh::{
-    greedy_quads, GreedyQuadsBuffer, MergeVoxel, Voxel, VoxelVisibility, RIGHT_HANDED_Y_UP_CONFIG,
+    greedy_quads, GreedyQuadsBuffer, MergeVoxel, UnorientedQuad, Voxel, VoxelVisibility, RIGHT_HANDED_Y_UP_CONFIG,
 };
 use super::objected_voxel_map::*;
 use super::chunked_voxel_map::*;
@@ -9,6 +14,15 @@ use super::chunked_voxel_map::*;
 
 pub fn generate_mesh<T: PartialEq + Eq + Clone + Typed + FromReflect>(
     chunk: &VoxelChunk<VoxelVal<T>>,
+) -> GreedyQuadsBuffer {
+    generate_mesh_padded(chunk, |_| VoxelVal::None)
+}
+
+/// Like [`generate_mesh`], the padding cells around the chunk take `border(lp)`, `lp` is relative
+/// to the chunk origin. Faces against voxels of the neighbour chunks are culled then
+pub fn generate_mesh_padded<T: PartialEq + Eq + Clone + Typed + FromReflect>(
+    chunk: &VoxelChunk<VoxelVal<T>>,
+    border: impl Fn(IVec3) -> VoxelVal<T>,
 ) -> GreedyQuadsBuffer {
     let mut buffer = GreedyQuadsBuffer::new(chunk.data.len());
 
@@ -19,15 +33,20 @@ pub fn generate_mesh<T: PartialEq + Eq + Clone + Typed + FromReflect>(
     ];
 
     let mut padded_data = vec![VoxelVal::None; (size[0] * size[1] * size[2]) as usize];
-    for z in 0..chunk.size.z {
-        for y in 0..chunk.size.y {
-            for x in 0..chunk.size.x {
+    for z in -1..=chunk.size.z {
+        for y in -1..=chunk.size.y {
+            for x in -1..=chunk.size.x {
+                let lp = IVec3::new(x, y, z);
+                let inside = lp.cmpge(IVec3::ZERO).all() && lp.cmplt(chunk.size).all();
                 let dz = z + 1;
                 let dy = y + 1;
                 let dx = x + 1;
 
-                padded_data[((dz as u32 * size[1] + dy as u32) * size[0] + dx as u32) as usize] =
-                    chunk.get(x, y, z).clone();
+                padded_data[((dz as u32 * size[1] + dy as u32) * size[0] + dx as u32) as usize] = if inside {
+                    chunk.get(x, y, z).clone()
+                } else {
+                    border(lp)
+                };
             }
         }
     }
@@ -47,6 +66,44 @@ pub fn generate_mesh<T: PartialEq + Eq + Clone + Typed + FromReflect>(
     buffer
 }
 
+/// Quads of [`generate_mesh`] split by the voxel they were made of, one list per face of `RIGHT_HANDED_Y_UP_CONFIG`
+pub fn group_quads<T: Hash + PartialEq + Eq + Clone + Typed + FromReflect>(
+    chunk: &VoxelChunk<VoxelVal<T>>,
+    buffer: &GreedyQuadsBuffer,
+) -> HashMap<T, [Vec<UnorientedQuad>; 6]> {
+    let mut groups: HashMap<T, [Vec<UnorientedQuad>; 6]> = HashMap::new();
+    for (face, quads) in buffer.quads.groups.iter().enumerate() {
+        for quad in quads {
+            // quads are in the padded chunk coordinates
+            let [x, y, z] = quad.minimum;
+            if let VoxelVal::Voxel(id) = chunk.get(x as i32 - 1, y as i32 - 1, z as i32 - 1) {
+                groups.entry(id.clone()).or_default()[face].push(*quad);
+            }
+        }
+    }
+    groups
+}
+
+/// Triangle mesh of grouped quads. Vertex (0, 0, 0) is the corner of the padding cell before the chunk origin
+pub fn quads_mesh(faces: &[Vec<UnorientedQuad>; 6], voxel_size: f32) -> Mesh {
+    let num_quads: usize = faces.iter().map(|quads| quads.len()).sum();
+    let mut positions = Vec::with_capacity(num_quads * 4);
+    let mut normals = Vec::with_capacity(num_quads * 4);
+    let mut indices = Vec::with_capacity(num_quads * 6);
+    for (quads, face) in faces.iter().zip(RIGHT_HANDED_Y_UP_CONFIG.faces.iter()) {
+        for quad in quads {
+            indices.extend_from_slice(&face.quad_mesh_indices(positions.len() as u32));
+            positions.extend_from_slice(&face.quad_mesh_positions(quad, voxel_size));
+            normals.extend_from_slice(&face.quad_mesh_normals());
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
 
 impl<VoxelID : Typed + FromReflect> Voxel for VoxelVal<VoxelID> {
     fn get_visibility(&self) -> VoxelVisibility {
@@ -69,7 +126,9 @@ impl<VoxelID: PartialEq + Eq + Clone + Typed + FromReflect> MergeVoxel for Voxel
 
 #[cfg(test)]
 mod tests {
-    use super::{generate_mesh, VoxelVal};
+    use bevy::math::IVec3;
+
+    use super::{generate_mesh, generate_mesh_padded, group_quads, VoxelVal};
     use super::super::chunked_voxel_map::VoxelChunk;
 
     #[test]
@@ -81,4 +140,36 @@ mod tests {
             println!("{:?}", g);
         }
     }
+
+    #[test]
+    fn quads_grouped_by_voxel() {
+        let mut chunk = VoxelChunk::<VoxelVal<usize>>::new([0, 0, 0].into(), [4, 4, 4].into());
+        *chunk.get_mut(0, 0, 0) = VoxelVal::Voxel(1);
+        *chunk.get_mut(1, 0, 0) = VoxelVal::Voxel(2);
+        *chunk.get_mut(3, 0, 0) = VoxelVal::Voxel(2);
+        let buffer = generate_mesh(&chunk);
+        let groups = group_quads(&chunk, &buffer);
+
+        assert_eq!(groups.len(), 2);
+        // the shared face of touching voxels is hidden
+        assert_eq!(groups[&1].iter().map(|quads| quads.len()).sum::<usize>(), 5);
+        assert_eq!(groups[&2].iter().map(|quads| quads.len()).sum::<usize>(), 11);
+    }
+
+    #[test]
+    fn border_voxels_hide_faces() {
+        let mut chunk = VoxelChunk::<VoxelVal<usize>>::new([0, 0, 0].into(), [4, 4, 4].into());
+        *chunk.get_mut(3, 0, 0) = VoxelVal::Voxel(1);
+        *chunk.get_mut(1, 1, 1) = VoxelVal::Voxel(1);
+        let alone = generate_mesh(&chunk);
+        assert_eq!(alone.quads.num_quads(), 12);
+
+        // the neighbour chunk has a voxel right after (3, 0, 0)
+        let padded = generate_mesh_padded(&chunk, |lp| {
+            if lp == IVec3::new(4, 0, 0) { VoxelVal::Voxel(1) } else { VoxelVal::None }
+        });
+        assert_eq!(padded.quads.num_quads(), 11);
+        // padding voxels get no faces of their own
+        assert_eq!(group_quads(&chunk, &padded)[&1].iter().map(|quads| quads.len()).sum::<usize>(), 11);
+    }
 }
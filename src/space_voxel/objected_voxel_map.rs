@@ -1,10 +1,12 @@
 
 // extern crate test;
 
-use bevy::{prelude::*};
-use super::{VoxelMap, solid_voxel_map::SolidVoxelMap};
+use std::ops::Deref;
 
-#[derive(PartialEq, Eq, Clone)]
+use bevy::{prelude::*, utils::HashMap};
+use super::{VoxelMap, MapBounds, Real, chunked_voxel_map::ChunkedVoxelMap};
+
+#[derive(PartialEq, Eq, Clone, Debug)]
 #[derive(Default)]
 pub enum VoxelVal<VoxelID> {
     #[default]
@@ -16,56 +18,214 @@ pub enum VoxelVal<VoxelID> {
 
 
 
+/// Cells taken by an object: the lowest cell, the size and the orientation in quarter turns
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Footprint {
+    pub idx : IVec3,
+    pub bbox : IVec3,
+    pub rot_steps : IVec3
+}
+
+impl Footprint {
+    pub fn new(idx : IVec3, bbox : IVec3) -> Footprint {
+        Footprint {
+            idx,
+            bbox,
+            rot_steps : IVec3::ZERO
+        }
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = IVec3> {
+        let (idx, bbox) = (self.idx, self.bbox);
+        (0..bbox.z).flat_map(move |z| (0..bbox.y).flat_map(move |y| (0..bbox.x).map(move |x| idx + IVec3::new(x, y, z))))
+    }
+}
+
+/// Map of voxels and multi-cell objects. Object cells are written through [`ObjectedVoxelMap::place_object`]
+/// and [`ObjectedVoxelMap::erase_object`], which keep the footprint index in sync
 pub trait ObjectedVoxelMap<T> : VoxelMap<VoxelVal<T>>
     where T : Clone
 {
-    fn set_object_by_idx(&mut self, e : Entity, pos : &IVec3, bbox : &IVec3) {
-        for z in 0..bbox.z {
-            for y in 0..bbox.y {
-                for x in 0..bbox.x {
-                    self.set_voxel_by_idx(&(*pos + IVec3 {x, y, z}), VoxelVal::Object(e));
-                }
-            }
+    fn footprints(&self) -> &HashMap<Entity, Footprint>;
+
+    /// Writes the object cells and indexes them. An object placed before is moved, its old cells are cleared.
+    /// Cells of other objects are kept, check [`ObjectedVoxelMap::can_place_object`] first
+    fn place_object(&mut self, e : Entity, footprint : Footprint);
+
+    /// Clears the cells of the object and returns where it was
+    fn erase_object(&mut self, e : Entity) -> Option<Footprint>;
+
+    fn footprint(&self, e : Entity) -> Option<&Footprint> {
+        self.footprints().get(&e)
+    }
+
+    fn object_at(&self, pos : &IVec3) -> Option<Entity> {
+        match self.get_by_idx(pos) {
+            VoxelVal::Object(e) => Some(*e),
+            _ => None
         }
     }
 
+    fn set_object_by_idx(&mut self, e : Entity, pos : &IVec3, bbox : &IVec3, rot_steps : &IVec3) {
+        self.place_object(e, Footprint {
+            idx : *pos,
+            bbox : *bbox,
+            rot_steps : *rot_steps
+        });
+    }
+
     fn can_place_object(&self, pos : &IVec3, bbox : &IVec3) -> bool {
-        for z in 0..bbox.z {
-            for y in 0..bbox.y {
-                for x in 0..bbox.x {
-                    if let VoxelVal::None = self.get_by_idx(&(*pos + IVec3 {x, y, z})) {
-                        
-                    } else {
-                        return false;
-                    }
-                }
-            }
-        }
-        true
-    }
-
-    fn erase_object(&mut self, pos : &IVec3, search_area : &IVec3) {
-        if let VoxelVal::Object(id) = self.get_by_idx(pos).clone() {
-            for z in (pos.z - search_area.z)..=(pos.z + search_area.z) {
-                for y in (pos.y - search_area.y)..=(pos.y + search_area.y) {
-                    for x in (pos.x - search_area.x)..=(pos.x + search_area.x) {
-                        if let VoxelVal::Object(vox_id) = self.get_by_idx(&IVec3{x, y, z}).clone() {
-                            if vox_id == id {
-                                self.set_voxel_by_idx(&IVec3{x, y, z}, VoxelVal::None);
-                            }
-                        }
-                    }
-                }
-            }
+        Footprint::new(*pos, *bbox).cells()
+            .all(|idx| matches!(self.get_by_idx(&idx), VoxelVal::None))
+    }
+}
+
+/// Voxel map `M` with the index of the objects placed in it. It owns every write: voxel writes leave
+/// object cells alone and object values are never written as voxels, so the index can not go stale.
+/// Reading goes to `M` through `Deref`
+#[derive(Clone)]
+pub struct ObjectedMap<M> {
+    map : M,
+    footprints : HashMap<Entity, Footprint>
+}
+
+impl<M> ObjectedMap<M> {
+    pub fn new(map : M) -> ObjectedMap<M> {
+        ObjectedMap {
+            map,
+            footprints : HashMap::new()
         }
     }
 }
 
+impl<M> Deref for ObjectedMap<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.map
+    }
+}
 
-impl<T> ObjectedVoxelMap<T> for SolidVoxelMap<VoxelVal<T>> 
+impl<T> ObjectedMap<ChunkedVoxelMap<VoxelVal<T>>>
     where T : Clone
 {
+    /// Moves the whole map, cell indices stay the same
+    pub fn set_first_voxel_pos(&mut self, pos : Real) {
+        self.map.first_voxel_pos = pos;
+    }
+
+    /// See [`ChunkedVoxelMap::prune_empty`]
+    pub fn prune_empty(&mut self) {
+        self.map.prune_empty();
+    }
 
+    pub fn clear_dirty(&mut self) {
+        self.map.dirty_set.clear();
+    }
+}
+
+impl<T, M> VoxelMap<VoxelVal<T>> for ObjectedMap<M>
+    where T : Clone, M : VoxelMap<VoxelVal<T>>
+{
+    fn get_grid_pos(&self, pos: &Real) -> Real {
+        self.map.get_grid_pos(pos)
+    }
+
+    fn get_grid_idx(&self, pos: &Real) -> IVec3 {
+        self.map.get_grid_idx(pos)
+    }
+
+    fn get_idx_pos(&self, pos : &IVec3) -> Real {
+        self.map.get_idx_pos(pos)
+    }
+
+    fn get_cloned(&self, pos: &Real) -> VoxelVal<T> {
+        self.map.get_cloned(pos)
+    }
+
+    fn get(&self, pos: &Real) -> &VoxelVal<T> {
+        self.map.get(pos)
+    }
+
+    /// Always `None`, a cell written through a reference could break the object index
+    fn get_mut(&mut self, _pos: &Real) -> Option<&mut VoxelVal<T>> {
+        None
+    }
+
+    fn set_voxel(&mut self, pos : &Real, val : VoxelVal<T>) {
+        let idx = self.get_grid_idx(pos);
+        self.set_voxel_by_idx(&idx, val);
+    }
+
+    fn get_cloned_by_idx(&self, pos : &IVec3) -> VoxelVal<T> {
+        self.map.get_cloned_by_idx(pos)
+    }
+
+    fn get_by_idx(&self, pos : &IVec3) -> &VoxelVal<T> {
+        self.map.get_by_idx(pos)
+    }
+
+    /// Always `None`, see [`ObjectedMap::get_mut`]
+    fn get_mut_by_idx(&mut self, _pos : &IVec3) -> Option<&mut VoxelVal<T>> {
+        None
+    }
+
+    /// Object values and writes over object cells are dropped, objects change through [`ObjectedVoxelMap`]
+    fn set_voxel_by_idx(&mut self, pos : &IVec3, val : VoxelVal<T>) {
+        if matches!(val, VoxelVal::Object(_)) || self.object_at(pos).is_some() {
+            return;
+        }
+        self.map.set_voxel_by_idx(pos, val);
+    }
+
+    fn get_bounds(&self) -> MapBounds {
+        self.map.get_bounds()
+    }
+
+    fn get_voxel_size(&self) -> f64 {
+        self.map.get_voxel_size()
+    }
+
+    fn cell_range(&self) -> Option<(IVec3, IVec3)> {
+        self.map.cell_range()
+    }
+
+    fn overlap_aabb(&self, min : &Real, max : &Real) -> Vec<IVec3>
+        where VoxelVal<T> : Default + PartialEq {
+        self.map.overlap_aabb(min, max)
+    }
+
+    fn test_default() -> Self {
+        ObjectedMap::new(M::test_default())
+    }
+}
+
+impl<T, M> ObjectedVoxelMap<T> for ObjectedMap<M>
+    where T : Clone, M : VoxelMap<VoxelVal<T>>
+{
+    fn footprints(&self) -> &HashMap<Entity, Footprint> {
+        &self.footprints
+    }
+
+    fn place_object(&mut self, e : Entity, footprint : Footprint) {
+        self.erase_object(e);
+        for idx in footprint.cells() {
+            if self.object_at(&idx).is_none() {
+                self.map.set_voxel_by_idx(&idx, VoxelVal::Object(e));
+            }
+        }
+        self.footprints.insert(e, footprint);
+    }
+
+    fn erase_object(&mut self, e : Entity) -> Option<Footprint> {
+        let footprint = self.footprints.remove(&e)?;
+        for idx in footprint.cells() {
+            if self.object_at(&idx) == Some(e) {
+                self.map.set_voxel_by_idx(&idx, VoxelVal::None);
+            }
+        }
+        Some(footprint)
+    }
 }
 
 #[cfg(test)]
@@ -79,7 +239,7 @@ mod tests {
 
     #[test]
     fn solid_objected_map() {
-        let mut map = SolidVoxelMap::<VoxelVal<i32>>::test_default();
+        let mut map = ObjectedMap::<SolidVoxelMap<VoxelVal<i32>>>::test_default();
 
         let e = Entity::from_raw(12356);
 
@@ -90,7 +250,7 @@ mod tests {
         assert!(res);
 
         //place object
-        map.set_object_by_idx(e, &pos, &bbox);
+        map.set_object_by_idx(e, &pos, &bbox, &IVec3::ZERO);
 
         //test can place again
         let res = map.can_place_object(&pos, &bbox);
@@ -101,8 +261,13 @@ mod tests {
         let res = map.can_place_object(&pos, &(bbox / 2));
         assert!(!res);
 
+        assert_eq!(map.footprint(e), Some(&Footprint::new(pos, bbox)));
+        assert_eq!(map.object_at(&(pos + bbox - IVec3::ONE)), Some(e));
+
         //remove object
-        map.erase_object(&pos, &bbox);
+        assert_eq!(map.erase_object(e), Some(Footprint::new(pos, bbox)));
+        assert!(map.footprints().is_empty());
+        assert_eq!(map.erase_object(e), None);
 
         //test can place again
         let res = map.can_place_object(&pos, &bbox);
@@ -110,6 +275,28 @@ mod tests {
         
     }
 
+    #[test]
+    fn map_owns_object_cells() {
+        let mut map = ObjectedMap::new(SolidVoxelMap::<VoxelVal<i32>>::new(Real::ZERO, IVec3::splat(8), 0.5));
+        let e = Entity::from_raw(1);
+        map.set_object_by_idx(e, &IVec3::ZERO, &IVec3::new(2, 1, 3), &IVec3::new(1, 0, 0));
+        assert_eq!(map.footprint(e).unwrap().rot_steps, IVec3::new(1, 0, 0));
+
+        // plain writes can not touch object cells or forge them
+        map.set_voxel_by_idx(&IVec3::ZERO, VoxelVal::None);
+        map.set_voxel_by_idx(&IVec3::new(5, 5, 5), VoxelVal::Object(e));
+        assert_eq!(map.object_at(&IVec3::ZERO), Some(e));
+        assert_eq!(map.object_at(&IVec3::new(5, 5, 5)), None);
+        assert!(map.get_mut_by_idx(&IVec3::new(5, 5, 5)).is_none());
+
+        // placing again moves the object
+        map.place_object(e, Footprint::new(IVec3::new(4, 0, 0), IVec3::ONE));
+        assert_eq!(map.object_at(&IVec3::ZERO), None);
+        assert_eq!(map.iter_occupied().count(), 1);
+        map.set_voxel_by_idx(&IVec3::ZERO, VoxelVal::Voxel(3));
+        assert_eq!(map.get_cloned_by_idx(&IVec3::ZERO), VoxelVal::Voxel(3));
+    }
+
     // #[bench]
     // fn add_bench_solid(b : &mut Bencher) {
     //     let mut map = SolidVoxelMap::<VoxelVal<i32>>::test_default();
@@ -119,7 +306,7 @@ mod tests {
 
     //     b.iter( black_box(|| {
     //         map.can_place_object(&pos, &bbox);
-    //         map.set_object_by_idx(e, &pos, &bbox);
+    //         map.set_object_by_idx(e, &pos, &bbox, &IVec3::ZERO);
     //     }));
     // }
 
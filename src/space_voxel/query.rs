@@ -0,0 +1,248 @@
+use bevy::prelude::*;
+
+use super::{VoxelMap, MapBounds, Real};
+
+/// Face neighbour offsets: -x, +x, -y, +y, -z, +z
+pub const FACE_DIRS : [IVec3; 6] = [
+    IVec3::NEG_X, IVec3::X,
+    IVec3::NEG_Y, IVec3::Y,
+    IVec3::NEG_Z, IVec3::Z
+];
+
+pub struct VoxelHit<T> {
+    pub idx : IVec3,
+    /// Normal of the hit cell face, zero if the ray starts inside an occupied cell
+    pub normal : IVec3,
+    pub distance : f64,
+    pub value : T
+}
+
+/// Cells of a limited map as `(min, max)`, `max` included
+pub fn bounds_cell_range<T, M : VoxelMap<T> + ?Sized>(map : &M) -> Option<(IVec3, IVec3)> {
+    match map.get_bounds() {
+        MapBounds::Infinity => None,
+        MapBounds::Limited { from, to } => {
+            let cell_zero = map.get_idx_pos(&IVec3::ZERO);
+            let min = ((from - cell_zero) / map.get_voxel_size()).round().as_ivec3();
+            let max = ((to - cell_zero) / map.get_voxel_size()).round().as_ivec3() - IVec3::ONE;
+            Some((min, max))
+        }
+    }
+}
+
+/// Walks the cells along the ray with DDA and returns the first one which is not `T::default()`.
+/// Positions are in the map frame, `max_dist` is in the same units
+pub fn raycast<T, M>(map : &M, origin : &Real, dir : &Real, max_dist : f64) -> Option<VoxelHit<T>>
+    where T : Clone + Default + PartialEq, M : VoxelMap<T> + ?Sized {
+    let dir = dir.try_normalize()?;
+    let voxel_size = map.get_voxel_size();
+    // ray in cell units, cell `idx` spans idx..idx + 1
+    let origin = (*origin - map.get_idx_pos(&IVec3::ZERO)) / voxel_size;
+    // the walk is clipped to the cells which can be occupied
+    let range = map.cell_range();
+    if range.is_some_and(|(min, max)| min.cmpgt(max).any()) {
+        return None;
+    }
+
+    let mut t_enter = 0.0;
+    let mut t_exit = max_dist / voxel_size;
+    let mut normal = IVec3::ZERO;
+    if let Some((min, max)) = range {
+        let (lo, hi) = (min.as_dvec3(), (max + IVec3::ONE).as_dvec3());
+        for axis in 0..3 {
+            if dir[axis].abs() < f64::EPSILON {
+                if origin[axis] < lo[axis] || origin[axis] > hi[axis] {
+                    return None;
+                }
+                continue;
+            }
+            let t1 = (lo[axis] - origin[axis]) / dir[axis];
+            let t2 = (hi[axis] - origin[axis]) / dir[axis];
+            if t1.min(t2) > t_enter {
+                t_enter = t1.min(t2);
+                normal = IVec3::ZERO;
+                normal[axis] = -dir[axis].signum() as i32;
+            }
+            t_exit = t_exit.min(t1.max(t2));
+        }
+        if t_enter > t_exit {
+            return None;
+        }
+    }
+
+    let start = origin + dir * t_enter;
+    let mut idx = start.floor().as_ivec3();
+    if let Some((min, max)) = range {
+        idx = idx.clamp(min, max);
+    }
+    let mut step = IVec3::ZERO;
+    let mut t_max = Real::splat(f64::INFINITY);
+    let mut t_delta = Real::splat(f64::INFINITY);
+    for axis in 0..3 {
+        if dir[axis] > 0.0 {
+            step[axis] = 1;
+            t_max[axis] = t_enter + (idx[axis] as f64 + 1.0 - start[axis]) / dir[axis];
+            t_delta[axis] = 1.0 / dir[axis];
+        } else if dir[axis] < 0.0 {
+            step[axis] = -1;
+            t_max[axis] = t_enter + (idx[axis] as f64 - start[axis]) / dir[axis];
+            t_delta[axis] = -1.0 / dir[axis];
+        }
+    }
+
+    let empty = T::default();
+    let mut t = t_enter;
+    loop {
+        let value = map.get_by_idx(&idx);
+        if *value != empty {
+            return Some(VoxelHit {
+                idx,
+                normal,
+                distance : t * voxel_size,
+                value : value.clone()
+            });
+        }
+
+        let axis = if t_max.x < t_max.y && t_max.x < t_max.z {
+            0
+        } else if t_max.y < t_max.z {
+            1
+        } else {
+            2
+        };
+        t = t_max[axis];
+        if t > t_exit {
+            return None;
+        }
+        idx[axis] += step[axis];
+        t_max[axis] += t_delta[axis];
+        normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+
+        if let Some((min, max)) = range {
+            if idx[axis] < min[axis] || idx[axis] > max[axis] {
+                return None;
+            }
+        }
+    }
+}
+
+/// Cells touched by the box from `min` to `max` as `(lo, hi)`, `hi` included, clipped by [`VoxelMap::cell_range`].
+/// `lo > hi` if the box misses the cells
+pub fn cells_in_box<T, M : VoxelMap<T> + ?Sized>(map : &M, min : &Real, max : &Real) -> (IVec3, IVec3) {
+    let mut lo = map.get_grid_idx(&min.min(*max));
+    let mut hi = map.get_grid_idx(&min.max(*max));
+    if let Some((range_min, range_max)) = map.cell_range() {
+        lo = lo.max(range_min);
+        hi = hi.min(range_max);
+    }
+    (lo, hi)
+}
+
+/// Occupied cells overlapping the box from `min` to `max`
+pub fn overlap_aabb<T, M>(map : &M, min : &Real, max : &Real) -> Vec<IVec3>
+    where T : Default + PartialEq, M : VoxelMap<T> + ?Sized {
+    let (lo, hi) = cells_in_box(map, min, max);
+    let empty = T::default();
+    let mut res = vec![];
+    for z in lo.z..=hi.z {
+        for y in lo.y..=hi.y {
+            for x in lo.x..=hi.x {
+                let idx = IVec3::new(x, y, z);
+                if *map.get_by_idx(&idx) != empty {
+                    res.push(idx);
+                }
+            }
+        }
+    }
+    res
+}
+
+/// Occupied cells overlapping the sphere
+pub fn overlap_sphere<T, M>(map : &M, center : &Real, radius : f64) -> Vec<IVec3>
+    where T : Default + PartialEq, M : VoxelMap<T> + ?Sized {
+    let voxel_size = map.get_voxel_size();
+    map.overlap_aabb(&(*center - radius), &(*center + radius))
+        .into_iter()
+        .filter(|idx| {
+            let cell_min = map.get_idx_pos(idx);
+            let closest = center.clamp(cell_min, cell_min + voxel_size);
+            closest.distance_squared(*center) <= radius * radius
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::solid_voxel_map::SolidVoxelMap;
+    use super::super::chunked_voxel_map::ChunkedVoxelMap;
+    use test_case::test_case;
+
+    fn with_plate<M : VoxelMap<i32>>(mut map : M) -> M {
+        // 2 x 0.5 x 2 plate, its top at y = 0
+        for z in -2..2 {
+            for x in -2..2 {
+                map.set_voxel(&Real::new(x as f64 * 0.5 + 0.25, -0.25, z as f64 * 0.5 + 0.25), 3);
+            }
+        }
+        map
+    }
+
+    #[test_case(SolidVoxelMap::<i32>::test_default())]
+    #[test_case(ChunkedVoxelMap::<i32>::test_default())]
+    fn raycast_hits_faces(map : impl VoxelMap<i32>) {
+        let map = with_plate(map);
+
+        let hit = map.raycast(&Real::new(0.1, 10.0, 0.1), &Real::NEG_Y, 100.0).unwrap();
+        assert_eq!(hit.idx, map.get_grid_idx(&Real::new(0.1, -0.1, 0.1)));
+        assert_eq!(hit.normal, IVec3::Y);
+        assert_eq!(hit.value, 3);
+        assert!((hit.distance - 10.0).abs() < 1e-9);
+
+        let hit = map.raycast(&Real::new(-20.0, -0.1, 0.6), &Real::X, 100.0).unwrap();
+        assert_eq!(hit.normal, IVec3::NEG_X);
+        assert!((hit.distance - 19.0).abs() < 1e-9);
+
+        assert!(map.raycast(&Real::new(-20.0, 0.1, 0.6), &Real::X, 100.0).is_none());
+        assert!(map.raycast(&Real::new(0.1, 10.0, 0.1), &Real::NEG_Y, 5.0).is_none());
+
+        let inside = map.raycast(&Real::new(0.1, -0.1, 0.1), &Real::X, 100.0).unwrap();
+        assert_eq!(inside.normal, IVec3::ZERO);
+    }
+
+    #[test_case(SolidVoxelMap::<i32>::test_default())]
+    #[test_case(ChunkedVoxelMap::<i32>::test_default())]
+    fn overlaps_and_neighbours(map : impl VoxelMap<i32>) {
+        let map = with_plate(map);
+
+        assert_eq!(map.overlap_aabb(&Real::new(-5.0, -5.0, -5.0), &Real::new(5.0, 5.0, 5.0)).len(), 16);
+        assert_eq!(map.overlap_aabb(&Real::new(0.1, -0.1, 0.1), &Real::new(0.6, 0.0, 0.2)).len(), 2);
+        assert!(map.overlap_aabb(&Real::new(0.1, 0.1, 0.1), &Real::new(0.6, 1.0, 0.2)).is_empty());
+
+        // touches the top of the four middle cells only
+        assert_eq!(map.overlap_sphere(&Real::new(0.0, 0.2, 0.0), 0.25).len(), 4);
+        assert!(map.overlap_sphere(&Real::new(0.0, 0.2, 0.0), 0.15).is_empty());
+
+        let idx = map.get_grid_idx(&Real::new(0.1, -0.1, 0.1));
+        let occupied = map.neighbours(&idx).iter()
+            .filter(|(_, val)| **val == 3)
+            .count();
+        assert_eq!(occupied, 4);
+    }
+
+    #[test]
+    fn chunked_queries_stay_in_allocated_chunks() {
+        let empty = ChunkedVoxelMap::<i32>::test_default();
+        assert!(empty.raycast(&Real::ZERO, &Real::X, 1e9).is_none());
+        assert!(empty.overlap_aabb(&Real::splat(-1e6), &Real::splat(1e6)).is_empty());
+
+        // far rays and huge boxes only walk the chunks of the plate
+        let map = with_plate(ChunkedVoxelMap::<i32>::test_default());
+        let hit = map.raycast(&Real::new(0.1, 1e6, 0.1), &Real::NEG_Y, 1e9).unwrap();
+        assert_eq!(hit.normal, IVec3::Y);
+        assert!((hit.distance - 1e6).abs() < 1e-6);
+        assert!(map.raycast(&Real::new(0.1, 1e6, 0.1), &Real::Y, 1e9).is_none());
+        assert_eq!(map.overlap_aabb(&Real::splat(-1e6), &Real::splat(1e6)).len(), 16);
+    }
+}
@@ -0,0 +1,355 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use super::{VoxelMap, Real};
+use super::solid_voxel_map::SolidVoxelMap;
+use super::objected_voxel_map::{ObjectedVoxelMap, ObjectedMap, VoxelVal, Footprint};
+
+/// Quarter turns and mirroring of a pasted region. Columns are the images of the X, Y and Z axes
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RegionTransform {
+    pub cols : [IVec3; 3]
+}
+
+impl Default for RegionTransform {
+    fn default() -> Self {
+        RegionTransform::IDENTITY
+    }
+}
+
+impl RegionTransform {
+    pub const IDENTITY : RegionTransform = RegionTransform {
+        cols : [IVec3::X, IVec3::Y, IVec3::Z]
+    };
+
+    /// Counterclockwise quarter turns around X, like `DQuat::from_rotation_x`
+    pub fn turn_x(turns : i32) -> RegionTransform {
+        RegionTransform { cols : [IVec3::X, IVec3::Z, IVec3::NEG_Y] }.pow(turns)
+    }
+
+    /// Counterclockwise quarter turns around Y, like `DQuat::from_rotation_y`
+    pub fn turn_y(turns : i32) -> RegionTransform {
+        RegionTransform { cols : [IVec3::NEG_Z, IVec3::Y, IVec3::X] }.pow(turns)
+    }
+
+    /// Counterclockwise quarter turns around Z, like `DQuat::from_rotation_z`
+    pub fn turn_z(turns : i32) -> RegionTransform {
+        RegionTransform { cols : [IVec3::Y, IVec3::NEG_X, IVec3::Z] }.pow(turns)
+    }
+
+    /// Mirror across the plane orthogonal to `axis`, 0 is X
+    pub fn mirror(axis : usize) -> RegionTransform {
+        let mut res = RegionTransform::IDENTITY;
+        res.cols[axis] = -res.cols[axis];
+        res
+    }
+
+    fn pow(&self, turns : i32) -> RegionTransform {
+        (0..turns.rem_euclid(4)).fold(RegionTransform::IDENTITY, |acc, _| acc.then(self))
+    }
+
+    /// This transform followed by `next`
+    pub fn then(&self, next : &RegionTransform) -> RegionTransform {
+        RegionTransform {
+            cols : self.cols.map(|col| next.apply(col))
+        }
+    }
+
+    pub fn apply(&self, v : IVec3) -> IVec3 {
+        self.cols[0] * v.x + self.cols[1] * v.y + self.cols[2] * v.z
+    }
+
+    /// Size of the transformed `size` region
+    pub fn size(&self, size : IVec3) -> IVec3 {
+        self.apply(size).abs()
+    }
+
+    /// Cell of the transformed region which `idx` of the `size` region moves to. Both regions start at zero
+    pub fn cell(&self, idx : IVec3, size : IVec3) -> IVec3 {
+        // doubled cell centers relative to the region center stay integer
+        let centered = self.apply(idx * 2 + IVec3::ONE - size);
+        (centered + self.size(size) - IVec3::ONE) / 2
+    }
+
+    pub fn is_mirror(&self) -> bool {
+        self.cols[0].dot(self.cols[1].cross(self.cols[2])) < 0
+    }
+
+    /// Orientation of [`Footprint::rot_steps`], turns around Z, X and Y in this order
+    pub fn from_steps(rot_steps : IVec3) -> RegionTransform {
+        RegionTransform::turn_z(rot_steps.z)
+            .then(&RegionTransform::turn_x(rot_steps.y))
+            .then(&RegionTransform::turn_y(rot_steps.x))
+    }
+
+    /// Steps of a rotation, searched in the order of `InstanceRotate::from_quat` so both pick the same steps
+    pub fn steps(&self) -> Option<IVec3> {
+        for y in 0..4 {
+            for z in 0..4 {
+                for x in 0..4 {
+                    let steps = IVec3::new(x, y, z);
+                    if RegionTransform::from_steps(steps) == *self {
+                        return Some(steps);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Orientation of an object turned by this transform. Objects can not be mirrored,
+    /// a mirroring transform leaves them mirrored across their own X
+    pub fn turn_steps(&self, rot_steps : IVec3) -> IVec3 {
+        let mut res = RegionTransform::from_steps(rot_steps).then(self);
+        if res.is_mirror() {
+            res = RegionTransform::mirror(0).then(&res);
+        }
+        res.steps().unwrap_or(rot_steps)
+    }
+}
+
+/// Box from the corner `from` spanning `size` cells, negative sizes go back from `from`.
+/// Returns the lowest cell and the positive size
+pub fn normalize_box(from : IVec3, size : IVec3) -> (IVec3, IVec3) {
+    (from.min(from + size), size.abs())
+}
+
+/// Fill, copy and paste of box regions. Regions are given as a corner cell and the size in cells, see [`normalize_box`].
+/// Object cells belong to their instances, these writes leave them alone
+pub trait RegionVoxelMap<T> : VoxelMap<VoxelVal<T>>
+    where T : Clone
+{
+    /// Object values are not written, objects are placed with [`ObjectedVoxelMap::place_object`]
+    fn fill_box(&mut self, from : &IVec3, size : &IVec3, val : VoxelVal<T>) {
+        if matches!(val, VoxelVal::Object(_)) {
+            return;
+        }
+        let (from, size) = normalize_box(*from, *size);
+        for z in from.z..(from.z + size.z) {
+            for y in from.y..(from.y + size.y) {
+                for x in from.x..(from.x + size.x) {
+                    let idx = IVec3::new(x, y, z);
+                    if !matches!(self.get_by_idx(&idx), VoxelVal::Object(_)) {
+                        self.set_voxel_by_idx(&idx, val.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// Standalone copy of the region, its cell zero lies at the lowest cell of the box
+    fn copy_region(&self, from : &IVec3, size : &IVec3) -> SolidVoxelMap<VoxelVal<T>> {
+        let (from, size) = normalize_box(*from, *size);
+        let mut region = SolidVoxelMap::new(Real::ZERO, size, self.get_voxel_size());
+        region.first_voxel_pos = self.get_idx_pos(&from);
+        for (idx, val) in region.data.iter_mut().enumerate() {
+            let i = idx as i32;
+            let local = IVec3::new(i % size.x, (i / size.x) % size.y, i / (size.x * size.y));
+            *val = self.get_cloned_by_idx(&(from + local));
+        }
+        region
+    }
+
+    /// Writes the voxels of the transformed region with its lowest cell at `at`.
+    /// Empty and object cells of the region are skipped, see [`RegionObjectedVoxelMap::paste_objects`] for objects
+    fn paste_region(&mut self, region : &SolidVoxelMap<VoxelVal<T>>, at : &IVec3, transform : &RegionTransform) {
+        for (idx, val) in region.iter() {
+            if !matches!(val, VoxelVal::Voxel(_)) {
+                continue;
+            }
+            let to = *at + transform.cell(idx, region.size);
+            if !matches!(self.get_by_idx(&to), VoxelVal::Object(_)) {
+                self.set_voxel_by_idx(&to, val.clone());
+            }
+        }
+    }
+}
+
+impl<T, M> RegionVoxelMap<T> for M
+    where T : Clone, M : VoxelMap<VoxelVal<T>> + ?Sized {}
+
+/// Region copy and paste which keeps object cells pointing to live entities
+pub trait RegionObjectedVoxelMap<T> : ObjectedVoxelMap<T>
+    where T : Clone
+{
+    /// Like [`RegionVoxelMap::copy_region`], the footprints of the objects in the region come along
+    fn copy_objects(&self, from : &IVec3, size : &IVec3) -> ObjectedMap<SolidVoxelMap<VoxelVal<T>>> {
+        let (from, size) = normalize_box(*from, *size);
+        let mut region = ObjectedMap::new(self.copy_region(&from, &size));
+        let objects : Vec<Entity> = region.iter()
+            .filter_map(|(_, val)| match val {
+                VoxelVal::Object(e) => Some(*e),
+                _ => None
+            })
+            .collect();
+        for e in objects {
+            if let Some(footprint) = self.footprint(e) {
+                region.place_object(e, Footprint {
+                    idx : footprint.idx - from,
+                    ..*footprint
+                });
+            }
+        }
+        region
+    }
+
+    /// Like [`RegionVoxelMap::paste_region`], objects from [`RegionObjectedVoxelMap::copy_objects`] are placed
+    /// as the entities from `remap`, turned with the region. Objects missing from `remap`, cut by the region
+    /// or landing on other objects are left out
+    fn paste_objects(
+        &mut self,
+        region : &ObjectedMap<SolidVoxelMap<VoxelVal<T>>>,
+        at : &IVec3,
+        transform : &RegionTransform,
+        remap : &HashMap<Entity, Entity>
+    ) {
+        let mut placed = vec![];
+        for (e, new_e) in remap {
+            let Some(footprint) = region.footprint(*e) else {
+                continue;
+            };
+            let last = footprint.idx + footprint.bbox - IVec3::ONE;
+            if !region.contains(&footprint.idx) || !region.contains(&last) {
+                continue;
+            }
+            let (a, b) = (transform.cell(footprint.idx, region.size), transform.cell(last, region.size));
+            let footprint = Footprint {
+                idx : *at + a.min(b),
+                bbox : transform.size(footprint.bbox),
+                rot_steps : transform.turn_steps(footprint.rot_steps)
+            };
+            if footprint.cells().all(|idx| self.object_at(&idx).is_none()) {
+                placed.push((*new_e, footprint));
+            }
+        }
+
+        self.paste_region(region, at, transform);
+        for (e, footprint) in placed {
+            self.place_object(e, footprint);
+        }
+    }
+}
+
+impl<T, M> RegionObjectedVoxelMap<T> for M
+    where T : Clone, M : ObjectedVoxelMap<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::chunked_voxel_map::ChunkedVoxelMap;
+
+    #[test]
+    fn transforms_match_quat_turns() {
+        use bevy::math::DQuat;
+        use std::f64::consts::FRAC_PI_2;
+
+        let v = IVec3::new(1, 2, 3);
+        let cases = [
+            (RegionTransform::turn_x(1), DQuat::from_rotation_x(FRAC_PI_2)),
+            (RegionTransform::turn_y(1), DQuat::from_rotation_y(FRAC_PI_2)),
+            (RegionTransform::turn_z(3), DQuat::from_rotation_z(3.0 * FRAC_PI_2)),
+        ];
+        for (transform, quat) in cases {
+            assert_eq!(transform.apply(v), (quat * v.as_dvec3()).round().as_ivec3());
+        }
+        assert_eq!(RegionTransform::turn_y(4), RegionTransform::IDENTITY);
+        assert_eq!(RegionTransform::mirror(0).then(&RegionTransform::mirror(0)), RegionTransform::IDENTITY);
+    }
+
+    #[test]
+    fn copy_and_paste_turned() {
+        let v = |block| VoxelVal::Voxel(block);
+        let mut map = ChunkedVoxelMap::<VoxelVal<i32>>::new(0.5, IVec3::new(4, 4, 4));
+        // L shape: a 3 cell bar along x and one cell above its first cell
+        map.fill_box(&IVec3::new(-2, 0, 5), &IVec3::new(3, 1, 1), v(1));
+        map.set_voxel_by_idx(&IVec3::new(-2, 1, 5), v(2));
+
+        let region = map.copy_region(&IVec3::new(-2, 0, 5), &IVec3::new(3, 2, 1));
+        assert_eq!(region.iter_occupied().count(), 4);
+        assert_eq!(region.get_cloned_by_idx(&IVec3::new(0, 1, 0)), v(2));
+        assert_eq!(region.first_voxel_pos, map.get_idx_pos(&IVec3::new(-2, 0, 5)));
+        // the same box given from the opposite corner
+        let back = map.copy_region(&IVec3::new(1, 2, 6), &IVec3::new(-3, -2, -1));
+        assert_eq!(back.size, region.size);
+        assert!(back.data == region.data);
+
+        let mut target = ChunkedVoxelMap::<VoxelVal<i32>>::new(0.5, IVec3::new(4, 4, 4));
+        // the bar along x turns to run along -z, so it ends up at z 0..3 with the tall cell at z = 2
+        let turn = RegionTransform::turn_y(1);
+        assert_eq!(turn.size(region.size), IVec3::new(1, 2, 3));
+        target.paste_region(&region, &IVec3::ZERO, &turn);
+        for z in 0..3 {
+            assert_eq!(target.get_cloned_by_idx(&IVec3::new(0, 0, z)), v(1));
+        }
+        assert_eq!(target.get_cloned_by_idx(&IVec3::new(0, 1, 2)), v(2));
+        assert_eq!(target.get_cloned_by_idx(&IVec3::new(0, 1, 0)), VoxelVal::None);
+
+        let mut mirrored = ChunkedVoxelMap::<VoxelVal<i32>>::new(0.5, IVec3::new(4, 4, 4));
+        mirrored.paste_region(&region, &IVec3::ZERO, &RegionTransform::mirror(0));
+        assert_eq!(mirrored.get_cloned_by_idx(&IVec3::new(2, 1, 0)), v(2));
+    }
+
+    #[test]
+    fn writes_keep_object_cells() {
+        let e = Entity::from_raw(1);
+        let mut map = ObjectedMap::new(SolidVoxelMap::<VoxelVal<i32>>::new(Real::ZERO, IVec3::new(8, 8, 8), 0.5));
+        map.set_object_by_idx(e, &IVec3::new(2, 2, 2), &IVec3::new(2, 1, 1), &IVec3::ZERO);
+
+        map.fill_box(&IVec3::ONE, &IVec3::splat(3), VoxelVal::Voxel(4));
+        map.fill_box(&IVec3::ZERO, &IVec3::splat(8), VoxelVal::Object(Entity::from_raw(9)));
+        assert_eq!(map.object_at(&IVec3::new(3, 2, 2)), Some(e));
+        assert_eq!(map.get_cloned_by_idx(&IVec3::new(1, 2, 2)), VoxelVal::Voxel(4));
+
+        let mut region = SolidVoxelMap::<VoxelVal<i32>>::new(Real::ZERO, IVec3::new(3, 1, 1), 0.5);
+        region.fill_box(&IVec3::ZERO, &IVec3::new(3, 1, 1), VoxelVal::Voxel(5));
+        map.paste_region(&region, &IVec3::new(1, 2, 2), &RegionTransform::IDENTITY);
+        assert_eq!(map.object_at(&IVec3::new(2, 2, 2)), Some(e));
+        assert_eq!(map.get_cloned_by_idx(&IVec3::new(1, 2, 2)), VoxelVal::Voxel(5));
+        assert_eq!(map.footprint(e), Some(&Footprint::new(IVec3::new(2, 2, 2), IVec3::new(2, 1, 1))));
+    }
+
+    #[test]
+    fn steps_follow_instance_order() {
+        // steps of a rotation come back unchanged when they are the first match
+        for steps in [IVec3::ZERO, IVec3::new(1, 0, 0), IVec3::new(3, 1, 0), IVec3::new(2, 0, 1)] {
+            assert_eq!(RegionTransform::from_steps(steps).steps(), Some(steps));
+        }
+        assert_eq!(RegionTransform::mirror(1).steps(), None);
+        assert_eq!(RegionTransform::turn_y(1).turn_steps(IVec3::new(3, 0, 0)), IVec3::ZERO);
+        // mirrors keep a proper rotation
+        assert!(RegionTransform::from_steps(RegionTransform::mirror(2).turn_steps(IVec3::new(1, 1, 0))).steps().is_some());
+    }
+
+    #[test]
+    fn paste_remaps_objects() {
+        let old = Entity::from_raw(1);
+        let new = Entity::from_raw(2);
+        let cut = Entity::from_raw(3);
+        let mut map = ObjectedMap::new(SolidVoxelMap::<VoxelVal<i32>>::new(Real::ZERO, IVec3::new(8, 8, 8), 0.5));
+        map.set_object_by_idx(old, &IVec3::new(1, 1, 1), &IVec3::new(2, 1, 1), &IVec3::ZERO);
+        map.set_object_by_idx(Entity::from_raw(4), &IVec3::new(1, 2, 1), &IVec3::ONE, &IVec3::ZERO);
+        map.set_object_by_idx(cut, &IVec3::new(2, 2, 2), &IVec3::new(1, 1, 2), &IVec3::ZERO);
+        map.set_voxel_by_idx(&IVec3::new(1, 1, 2), VoxelVal::Voxel(7));
+
+        let region = map.copy_objects(&IVec3::ONE, &IVec3::new(2, 2, 2));
+        let remap = HashMap::from_iter([(old, new), (cut, Entity::from_raw(5))]);
+        let mut target = ObjectedMap::new(SolidVoxelMap::<VoxelVal<i32>>::new(Real::ZERO, IVec3::new(8, 8, 8), 0.5));
+        target.paste_objects(&region, &IVec3::new(4, 4, 4), &RegionTransform::turn_y(1), &remap);
+
+        // the 2 x 1 x 1 object turns to 1 x 1 x 2 and takes the turn
+        let footprint = *target.footprint(new).unwrap();
+        assert_eq!((footprint.idx, footprint.bbox), (IVec3::new(4, 4, 4), IVec3::new(1, 1, 2)));
+        assert_eq!(footprint.rot_steps, IVec3::new(1, 0, 0));
+        assert!(footprint.cells().all(|idx| target.object_at(&idx) == Some(new)));
+        assert!(target.get_by_idx(&IVec3::new(4, 5, 5)) == &VoxelVal::None);
+        assert!(target.get_by_idx(&IVec3::new(5, 4, 5)) == &VoxelVal::Voxel(7));
+        // objects sticking out of the region stay behind
+        assert!(target.footprint(Entity::from_raw(5)).is_none());
+        assert!(target.iter().all(|(_, val)| val != &VoxelVal::Object(Entity::from_raw(5))));
+
+        // pasting over a placed object keeps it
+        target.paste_objects(&region, &IVec3::new(4, 4, 3), &RegionTransform::turn_y(1), &HashMap::from_iter([(old, Entity::from_raw(6))]));
+        assert!(target.footprint(Entity::from_raw(6)).is_none());
+        assert_eq!(target.footprint(new), Some(&footprint));
+    }
+}
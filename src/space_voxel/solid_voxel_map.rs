@@ -29,31 +29,93 @@ impl<T> SolidVoxelMap<T>
     }
 }
 
+/// Cell index outside of a [`SolidVoxelMap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds {
+    pub idx : IVec3,
+    pub size : IVec3
+}
+
+impl std::fmt::Display for OutOfBounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cell {} is outside of the {} map", self.idx, self.size)
+    }
+}
+
+impl std::error::Error for OutOfBounds {}
+
 impl<T> SolidVoxelMap<T>
     where T : Default + Clone
 {
+    pub fn contains(&self, pos : &IVec3) -> bool {
+        pos.cmpge(IVec3::ZERO).all() && pos.cmplt(self.size).all()
+    }
+
+    /// Linear index of the cell, every axis is checked so the last column does not wrap into the next row
     #[inline]
-    fn get_idx(&self, pos : &IVec3) -> usize {
-        let idx = (pos.z * self.size.y + pos.y) * self.size.x + pos.x;
-        if idx >= 0 {
-            idx as usize
-        } else {
-            self.data.len()
+    fn get_idx(&self, pos : &IVec3) -> Option<usize> {
+        self.contains(pos).then(|| ((pos.z * self.size.y + pos.y) * self.size.x + pos.x) as usize)
+    }
+
+    fn out_of_bounds(&self, pos : &IVec3) -> OutOfBounds {
+        OutOfBounds {
+            idx : *pos,
+            size : self.size
         }
     }
 
-    #[inline]
-    fn get_line(&self, z : i32, y : i32) -> &[T] {
-        let start_idx = (z * self.size.y + y * self.size.x) as usize;
+    pub fn try_get(&self, pos : &IVec3) -> Result<&T, OutOfBounds> {
+        self.get_idx(pos)
+            .map(|idx| &self.data[idx])
+            .ok_or_else(|| self.out_of_bounds(pos))
+    }
 
-        &self.data[start_idx..(start_idx + self.size.x as usize)]
+    pub fn try_get_mut(&mut self, pos : &IVec3) -> Result<&mut T, OutOfBounds> {
+        match self.get_idx(pos) {
+            Some(idx) => Ok(&mut self.data[idx]),
+            None => Err(self.out_of_bounds(pos))
+        }
     }
 
-    #[inline]
-    fn get_line_mut(&mut self, z : i32, y : i32) -> &mut [T] {
-        let start_idx = (z * self.size.y + y * self.size.x) as usize;
+    pub fn try_set(&mut self, pos : &IVec3, val : T) -> Result<(), OutOfBounds> {
+        *self.try_get_mut(pos)? = val;
+        Ok(())
+    }
 
-        &mut self.data[start_idx..(start_idx + self.size.x as usize)]
+    /// Row of cells along x
+    pub fn get_line(&self, z : i32, y : i32) -> Option<&[T]> {
+        let start_idx = self.get_idx(&IVec3::new(0, y, z))?;
+        Some(&self.data[start_idx..(start_idx + self.size.x as usize)])
+    }
+
+    pub fn get_line_mut(&mut self, z : i32, y : i32) -> Option<&mut [T]> {
+        let start_idx = self.get_idx(&IVec3::new(0, y, z))?;
+        Some(&mut self.data[start_idx..(start_idx + self.size.x as usize)])
+    }
+
+    fn idx_of(&self, i : usize) -> IVec3 {
+        let i = i as i32;
+        IVec3::new(i % self.size.x, (i / self.size.x) % self.size.y, i / (self.size.x * self.size.y))
+    }
+
+    /// Every cell with its index
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, &T)> {
+        self.data.iter().enumerate().map(move |(i, val)| (self.idx_of(i), val))
+    }
+
+    /// Cells which are not `T::default()`
+    pub fn iter_occupied(&self) -> impl Iterator<Item = (IVec3, &T)>
+        where T : PartialEq {
+        let empty = T::default();
+        self.iter().filter(move |(_, val)| **val != empty)
+    }
+
+    /// Cells from `from` to `from + size`, the part outside of the map is skipped
+    pub fn iter_region(&self, from : IVec3, size : IVec3) -> impl Iterator<Item = (IVec3, &T)> {
+        let min = from.max(IVec3::ZERO);
+        let max = (from + size).min(self.size);
+        (min.z..max.z).flat_map(move |z| (min.y..max.y).flat_map(move |y| (min.x..max.x).map(move |x| IVec3::new(x, y, z))))
+            .map(move |idx| (idx, &self.data[self.get_idx(&idx).unwrap()]))
     }
 }
 
@@ -77,59 +139,39 @@ impl<T> VoxelMap<T> for SolidVoxelMap<T>
     }
 
     fn get_cloned(&self, pos: &Real) -> T {
-        let vec_idx = self.get_grid_idx(pos);
-        let idx = self.get_idx(&vec_idx);
-        if idx < self.data.len() {
-            self.data[idx].clone()
-        } else {
-            T::default()
-        }
+        self.get_cloned_by_idx(&self.get_grid_idx(pos))
     }
 
     fn get(&self, pos: &Real) -> &T {
-        let vec_idx = self.get_grid_idx(pos);
-        let idx = self.get_idx(&vec_idx);
-        self.data.get(idx).unwrap_or(&self.dummpy)
+        self.get_by_idx(&self.get_grid_idx(pos))
     }
 
     fn get_mut(&mut self, pos: &Real) -> Option<&mut T> {
         let vec_idx = self.get_grid_idx(pos);
-        let idx = self.get_idx(&vec_idx);
-        self.data.get_mut(idx)
+        self.get_mut_by_idx(&vec_idx)
     }
 
     fn set_voxel(&mut self, pos : &Real, val : T) {
         let vec_idx = self.get_grid_idx(pos);
-        let idx = self.get_idx(&vec_idx);
-        if idx < self.data.len() {
-            self.data[idx] = val;
-        }
+        self.set_voxel_by_idx(&vec_idx, val);
     }
 
     fn get_cloned_by_idx(&self, pos : &IVec3) -> T {
-        let idx = self.get_idx(pos);
-        if idx < self.data.len() {
-            self.data[idx].clone()
-        } else {
-            T::default()
-        }
+        self.get_by_idx(pos).clone()
     }
 
     fn get_by_idx(&self, pos : &IVec3) -> &T {
-        let idx = self.get_idx(pos);
-        self.data.get(idx).unwrap_or(&self.dummpy)
+        self.try_get(pos).unwrap_or(&self.dummpy)
     }
 
     fn get_mut_by_idx(&mut self, pos : &IVec3) -> Option<&mut T> {
-        let idx = self.get_idx(pos);
-        self.data.get_mut(idx)
+        self.try_get_mut(pos).ok()
     }
 
+    /// Writes outside of the map are dropped silently, tools paint past the edges on purpose.
+    /// [`SolidVoxelMap::try_set`] reports them
     fn set_voxel_by_idx(&mut self, pos : &IVec3, val : T) {
-        let idx = self.get_idx(pos);
-        if idx < self.data.len() {
-            self.data[idx] = val;
-        }
+        let _ = self.try_set(pos, val);
     }
 
     fn get_bounds(&self) -> MapBounds {
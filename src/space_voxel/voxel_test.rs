@@ -5,11 +5,13 @@
 mod chunk_map_tests {
     use super::super::*;
     use super::super::solid_voxel_map::*;
+    use super::super::chunked_voxel_map::*;
     use bevy::{scene::serde::SceneDeserializer};
     use test_case::test_case;
     use serde::{Deserialize, de::DeserializeSeed};
 
     #[test_case(SolidVoxelMap::<i32>::test_default())]
+    #[test_case(ChunkedVoxelMap::<i32>::test_default())]
     fn get_voxel_pos(map : impl VoxelMap<i32>) {
 
         let pos = Real::new(0.0, 0.0, 0.0);
@@ -20,6 +22,7 @@ mod chunk_map_tests {
     }
 
     #[test_case(SolidVoxelMap::<i32>::test_default())]
+    #[test_case(ChunkedVoxelMap::<i32>::test_default())]
     fn get_set(mut map : impl VoxelMap<i32>) {
         let pos = Real::new(11.0, 10.0, -9.0);
         assert_eq!(map.get_cloned(&pos), 0);
@@ -30,6 +33,7 @@ mod chunk_map_tests {
 
 
     #[test_case(SolidVoxelMap::<i32>::test_default())]
+    #[test_case(ChunkedVoxelMap::<i32>::test_default())]
     fn fill(mut map : impl VoxelMap<i32>) {
         let start_pos = Real::new(0.0, 0.0, 0.0);
         let end_pos = Real::new(10.0, 10.0, 10.0);
@@ -45,6 +49,35 @@ mod chunk_map_tests {
         }
     }
 
+    #[test]
+    fn chunked_indexing_matches_solid() {
+        let solid = SolidVoxelMap::<i32>::new(Real::ZERO, IVec3::new(10, 10, 10), 0.5);
+        let mut chunked = ChunkedVoxelMap::<i32>::new(0.5, IVec3::new(4, 4, 4));
+        chunked.first_voxel_pos = solid.first_voxel_pos;
+
+        for pos in [Real::new(-2.5, 0.0, 2.49), Real::new(-0.01, 0.26, -1.74), Real::new(1.0, -2.4, 0.74)] {
+            let idx = solid.get_grid_idx(&pos);
+            assert_eq!(chunked.get_grid_idx(&pos), idx);
+            assert_eq!(chunked.get_grid_pos(&pos), solid.get_grid_pos(&pos));
+            assert_eq!(chunked.get_idx_pos(&idx), solid.get_idx_pos(&idx));
+        }
+
+        // negative cells live in their own chunks
+        chunked.set_voxel_by_idx(&IVec3::new(-1, -5, 3), 7);
+        assert_eq!(chunked.get_cloned_by_idx(&IVec3::new(-1, -5, 3)), 7);
+        assert_eq!(chunked.get_cloned_by_idx(&IVec3::new(3, -5, 3)), 0);
+        assert!(chunked.dirty_set.contains(&IVec3::new(-4, -8, 0)));
+        assert!(matches!(chunked.get_bounds(), MapBounds::Infinity));
+
+        // a boundary cell marks the allocated chunk on the other side, an inner cell does not
+        chunked.dirty_set.clear();
+        chunked.set_voxel_by_idx(&IVec3::new(0, -5, 3), 7);
+        assert!(chunked.dirty_set.contains(&IVec3::new(-4, -8, 0)));
+        chunked.dirty_set.clear();
+        chunked.set_voxel_by_idx(&IVec3::new(1, -6, 2), 7);
+        assert_eq!(chunked.dirty_set.len(), 1);
+    }
+
     #[test]
     fn ron_save_load_solid_map() {
         let mut map = SolidVoxelMap::<i32>::test_default();
@@ -67,6 +100,22 @@ mod chunk_map_tests {
         }
     }
 
+    #[test]
+    fn ron_save_load_chunked_map() {
+        let mut map = ChunkedVoxelMap::<i32>::test_default();
+        map.set_voxel_by_idx(&IVec3::new(-20, 3, 40), 10);
+        map.set_voxel_by_idx(&IVec3::new(1, 2, 3), 11);
+
+        let disk = ron::to_string(&map).unwrap();
+        let map_2 : ChunkedVoxelMap::<i32> = ron::from_str(&disk).unwrap();
+
+        assert_eq!(map_2.map.len(), 2);
+        assert_eq!(map_2.get_cloned_by_idx(&IVec3::new(-20, 3, 40)), 10);
+        assert_eq!(map_2.get_cloned_by_idx(&IVec3::new(1, 2, 3)), 11);
+        assert_eq!(map_2.chunk_bounds(), Some((IVec3::new(-32, 0, 0), IVec3::new(16, 16, 48))));
+        assert_eq!(map_2.iter().filter(|(_, val)| **val != 0).count(), 2);
+    }
+
     #[test]
     fn world_save_load_solid_map() {
         let mut map = SolidVoxelMap::<i32>::test_default();
@@ -116,3 +165,73 @@ mod chunk_map_tests {
     }
 
 }
+
+#[cfg(test)]
+mod solid_map_props {
+    use super::super::*;
+    use super::super::solid_voxel_map::*;
+
+    const SIZE : IVec3 = IVec3::new(7, 5, 3);
+
+    fn inside(idx : IVec3) -> bool {
+        (0..SIZE.x).contains(&idx.x) && (0..SIZE.y).contains(&idx.y) && (0..SIZE.z).contains(&idx.z)
+    }
+
+    /// Every index from -`r` to `r` - 1 with the given step
+    fn indices(r : i32, step : usize) -> impl Iterator<Item = IVec3> {
+        (-r..r).step_by(step).flat_map(move |z| (-r..r).step_by(step).flat_map(move |y| (-r..r).step_by(step).map(move |x| IVec3::new(x, y, z))))
+    }
+
+    #[test]
+    fn writes_touch_only_their_cell() {
+        for idx in indices(10, 1) {
+            let mut map = SolidVoxelMap::<i32>::new(Real::ZERO, SIZE, 0.5);
+            assert_eq!(map.try_set(&idx, 5).is_ok(), inside(idx));
+            map.set_voxel_by_idx(&idx, 5);
+
+            let occupied : Vec<IVec3> = map.iter_occupied().map(|(idx, _)| idx).collect();
+            if inside(idx) {
+                assert_eq!(occupied, vec![idx]);
+                assert_eq!(map.get_cloned_by_idx(&idx), 5);
+            } else {
+                assert!(occupied.is_empty());
+                assert_eq!(map.get_cloned_by_idx(&idx), 0);
+                assert_eq!(map.try_get(&idx), Err(OutOfBounds { idx, size : SIZE }));
+            }
+        }
+    }
+
+    // offsets stay off the cell faces, where rounding may pick either cell
+    #[test]
+    fn positions_round_trip() {
+        let map = SolidVoxelMap::<i32>::new(Real::new(0.3, -1.0, 2.0), SIZE, 0.5);
+        for idx in indices(10, 1) {
+            for offset in [Real::splat(0.01), Real::new(0.5, 0.99, 0.25), Real::splat(0.99)] {
+                let pos = map.get_idx_pos(&idx) + offset * 0.5;
+                assert_eq!(map.get_grid_idx(&pos), idx);
+                assert_eq!(map.get_grid_pos(&pos), map.get_idx_pos(&idx));
+            }
+        }
+    }
+
+    #[test]
+    fn region_is_clipped() {
+        let mut map = SolidVoxelMap::<i32>::new(Real::ZERO, SIZE, 0.5);
+        for (i, val) in map.data.iter_mut().enumerate() {
+            *val = i as i32;
+        }
+        for from in indices(10, 3) {
+            for size in indices(6, 5).map(|size| size + 6) {
+                let clipped = ((from + size).min(SIZE) - from.max(IVec3::ZERO)).max(IVec3::ZERO);
+
+                let cells : Vec<(IVec3, i32)> = map.iter_region(from, size).map(|(idx, val)| (idx, *val)).collect();
+                assert_eq!(cells.len() as i32, clipped.x * clipped.y * clipped.z);
+                for (idx, val) in cells {
+                    assert!(inside(idx));
+                    assert_eq!(map.try_get(&idx), Ok(&val));
+                    assert_eq!(map.get_line(idx.z, idx.y).map(|line| line[idx.x as usize]), Some(val));
+                }
+            }
+        }
+    }
+}